@@ -0,0 +1,243 @@
+use crate::object::{read_object_from, write_object_to, ObjectFile};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Archive file layout (all words big endian)
+///   - magic "LC3A" (4 bytes)
+///   - format version (u16)
+///   - member count (u16), then per member:
+///     name (u16 byte length, UTF-8 bytes), a relocatable object (see object.rs)
+pub const ARCHIVE_MAGIC: &[u8; 4] = b"LC3A";
+pub const ARCHIVE_VERSION: u16 = 1;
+
+/// Bundle of relocatable objects, the linker only takes the members it needs
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Archive {
+    pub members: Vec<(String, ObjectFile)>,
+}
+
+impl Archive {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a member, replacing one with the same name
+    pub fn insert(&mut self, name: &str, object: ObjectFile) {
+        match self.members.iter_mut().find(|(member, _)| member == name) {
+            Some(member) => member.1 = object,
+            None => self.members.push((name.to_string(), object)),
+        }
+    }
+}
+
+pub fn read_archive(path: &str) -> io::Result<Archive> {
+    let mut f = BufReader::new(File::open(path)?);
+    read_archive_from(&mut f)
+}
+
+pub fn write_archive(path: &str, archive: &Archive) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    write_archive_to(archive, &mut f)?;
+    f.flush()
+}
+
+/// True if the bytes start like an archive
+pub fn is_archive(bytes: &[u8]) -> bool {
+    bytes.starts_with(ARCHIVE_MAGIC)
+}
+
+pub fn write_archive_to<W: Write>(archive: &Archive, w: &mut W) -> io::Result<()> {
+    w.write_all(ARCHIVE_MAGIC)?;
+    w.write_all(&ARCHIVE_VERSION.to_be_bytes())?;
+    w.write_all(&(archive.members.len() as u16).to_be_bytes())?;
+    for (name, object) in &archive.members {
+        w.write_all(&(name.len() as u16).to_be_bytes())?;
+        w.write_all(name.as_bytes())?;
+        write_object_to(object, w)?;
+    }
+    Ok(())
+}
+
+pub fn read_archive_from<R: Read>(r: &mut R) -> io::Result<Archive> {
+    let mut magic = [0_u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != ARCHIVE_MAGIC {
+        return Err(invalid_data("not an lc3 archive"));
+    }
+    if read_u16(r)? != ARCHIVE_VERSION {
+        return Err(invalid_data("unsupported archive version"));
+    }
+
+    let mut archive = Archive::new();
+    for _ in 0..read_u16(r)? {
+        let mut name = vec![0_u8; read_u16(r)? as usize];
+        r.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| invalid_data("name is not UTF-8"))?;
+        if !is_plain_name(&name) {
+            return Err(invalid_data(&format!("bad member name {:?}", name)));
+        }
+        archive.members.push((name, read_object_from(r)?));
+    }
+    Ok(archive)
+}
+
+/// Archive members needed to define the imports of `objects`, in archive order
+///
+/// Members pulled in can import symbols of their own, so the archives are
+/// searched again until nothing new is needed. Names are `archive(member)`.
+pub fn select_members(
+    objects: &[(String, ObjectFile)],
+    archives: &[(String, Archive)],
+) -> Vec<(String, ObjectFile)> {
+    let mut defined = HashSet::new();
+    let mut undefined = HashSet::new();
+    for (_, object) in objects {
+        add_symbols(object, &mut defined, &mut undefined);
+    }
+
+    let mut selected: Vec<(usize, usize)> = vec![];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (a, (_, archive)) in archives.iter().enumerate() {
+            for (m, (_, member)) in archive.members.iter().enumerate() {
+                let needed = member
+                    .exports()
+                    .any(|symbol| undefined.contains(&symbol.name));
+                if needed && !selected.contains(&(a, m)) {
+                    add_symbols(member, &mut defined, &mut undefined);
+                    selected.push((a, m));
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    selected
+        .into_iter()
+        .map(|(a, m)| {
+            let (archive_name, archive) = &archives[a];
+            let (member_name, member) = &archive.members[m];
+            (format!("{}({})", archive_name, member_name), member.clone())
+        })
+        .collect()
+}
+
+fn add_symbols(
+    object: &ObjectFile,
+    defined: &mut HashSet<String>,
+    undefined: &mut HashSet<String>,
+) {
+    for symbol in object.exports() {
+        defined.insert(symbol.name.clone());
+        undefined.remove(&symbol.name);
+    }
+    for symbol in object.imports() {
+        if !defined.contains(&symbol.name) {
+            undefined.insert(symbol.name.clone());
+        }
+    }
+}
+
+/// A file name without directories, so extracting stays in the current directory
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && !name.contains(['/', '\\'])
+        && !name.contains("..")
+        && !Path::new(name).is_absolute()
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut buffer = [0_u8; 2];
+    r.read_exact(&mut buffer)?;
+    Ok(u16::from_be_bytes(buffer))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::{read_archive_from, select_members, write_archive_to, Archive};
+    use crate::object::{Binding, ObjectFile, Section, Symbol};
+
+    /// Object exporting and importing the given names
+    fn object(exports: &[&str], imports: &[&str]) -> ObjectFile {
+        let symbol = |name: &&str, binding| Symbol {
+            name: name.to_string(),
+            binding,
+            section: 0,
+            offset: 0,
+        };
+        ObjectFile {
+            sections: vec![Section {
+                name: "text".to_string(),
+                origin: None,
+                words: vec![0xc1c0],
+            }],
+            symbols: exports
+                .iter()
+                .map(|name| symbol(name, Binding::Export))
+                .chain(imports.iter().map(|name| symbol(name, Binding::Import)))
+                .collect(),
+            relocations: vec![],
+        }
+    }
+
+    fn stdlib() -> Archive {
+        let mut archive = Archive::new();
+        archive.insert("mul.lobj", object(&["MUL"], &[]));
+        archive.insert("div.lobj", object(&["DIV"], &[]));
+        archive.insert("print.lobj", object(&["PRINT_NUM"], &["DIV"]));
+        archive
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let mut archive = stdlib();
+        archive.insert("mul.lobj", object(&["MUL", "SQUARE"], &[]));
+        assert_eq!(archive.members.len(), 3);
+
+        let mut buffer = vec![];
+        write_archive_to(&archive, &mut buffer).unwrap();
+        assert_eq!(read_archive_from(&mut buffer.as_slice()).unwrap(), archive);
+        assert!(read_archive_from(&mut &buffer[..20]).is_err());
+    }
+
+    #[test]
+    fn test_reject_member_names_with_paths() {
+        for name in [
+            "../evil.lobj",
+            "/tmp/evil.lobj",
+            "dir/a.lobj",
+            "dir\\a.lobj",
+            "..",
+            "",
+        ] {
+            let mut archive = Archive::new();
+            archive.insert(name, object(&["EVIL"], &[]));
+            let mut buffer = vec![];
+            write_archive_to(&archive, &mut buffer).unwrap();
+            assert!(
+                read_archive_from(&mut buffer.as_slice()).is_err(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_select_only_needed_members() {
+        let main = object(&["MAIN"], &["PRINT_NUM"]);
+        let archives = vec![("std.lar".to_string(), stdlib())];
+        let selected = select_members(&[("main.lobj".to_string(), main)], &archives);
+        let names: Vec<&str> = selected.iter().map(|(name, _)| name.as_str()).collect();
+        // PRINT_NUM pulls in DIV on the second pass, MUL is never needed
+        assert_eq!(names, vec!["std.lar(print.lobj)", "std.lar(div.lobj)"]);
+    }
+}
@@ -0,0 +1,1643 @@
+use crate::decode_instruction::{IMM5, OFFSET11, OFFSET6, OFFSET9, TRAP_VECTOR};
+use crate::expression::{Base, Expr, Value};
+use crate::lexer::{Token, TokenKind};
+use crate::loader::Program;
+use crate::object::{Binding, ObjectFile, Relocation, RelocationKind, Section, Symbol};
+use crate::opcodes::mask;
+use crate::preprocessor::{preprocess, Line};
+use crate::source::{Diagnostic, Severity, Sources, Span};
+use crate::symbols::SymbolTable;
+use crate::vm::Opcode;
+use std::collections::{HashMap, HashSet};
+
+// name of the section in relocatable output, and of the local symbol at its start
+const SECTION_NAME: &str = "text";
+const SECTION_SYMBOL: &str = ".text";
+
+#[derive(Default)]
+pub struct Options {
+    // emit a relocatable object instead of an absolute program
+    pub relocatable: bool,
+}
+
+/// Output of a successful assembly
+pub struct Assembly {
+    pub program: Program,
+    // labels, for the .sym file
+    pub symbols: SymbolTable,
+    // only filled for relocatable output
+    pub object: ObjectFile,
+    // every statement with its words, in address order
+    pub listing: Vec<Listed>,
+    // labels and constants by name, for the cross reference
+    pub definitions: Vec<Definition>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Words emitted for one statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listed {
+    pub address: u16,
+    pub words: Vec<u16>,
+    // the whole source line
+    pub line: Span,
+    // column the statement starts at, after its label
+    pub column: usize,
+    // the outermost macro call if the line came from a macro
+    pub call: Option<Span>,
+    pub provenance: Provenance,
+    // upper cased mnemonic or directive, the first instruction of an expansion
+    pub op: String,
+    // .FILL, .BLKW, .STRINGZ and literals
+    pub data: bool,
+}
+
+/// A label or constant with where it was defined and used
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    pub value: Value,
+    pub span: Span,
+    pub uses: Vec<Span>,
+}
+
+/// Where the words of a statement came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Provenance {
+    // written in the source
+    Source,
+    // a pseudo-instruction expanded into real instructions
+    Expansion,
+    // a literal pool entry for LDIMM or a far CALL
+    Literal,
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Register(u16, Span),
+    Expr(Expr),
+    Str(String, Span),
+}
+
+impl Operand {
+    fn span(&self) -> Span {
+        match self {
+            Operand::Register(_, span) | Operand::Str(_, span) => *span,
+            Operand::Expr(expr) => expr.span(),
+        }
+    }
+}
+
+/// One parsed source line
+#[derive(Clone)]
+struct Statement {
+    // index into the preprocessed lines
+    line: usize,
+    label: Option<(String, Span)>,
+    // upper cased mnemonic, directives keep their dot
+    op: Option<(String, Span)>,
+    operands: Vec<Operand>,
+    address: u16,
+    provenance: Provenance,
+}
+
+/// Assemble a file and everything it includes
+///
+/// Fails with every error and warning found, in source order. Statements
+/// with errors are skipped so the rest of the file is still checked.
+pub fn assemble(
+    sources: &mut Sources,
+    file: usize,
+    options: &Options,
+) -> Result<Assembly, Vec<Diagnostic>> {
+    match run(sources, file, options) {
+        (analysis, Some(mut assembly)) => {
+            assembly.definitions = analysis.definitions;
+            assembly.warnings = analysis.diagnostics;
+            Ok(assembly)
+        }
+        (analysis, None) => Err(analysis.diagnostics),
+    }
+}
+
+/// What is known about a source file, also when it has errors
+pub struct Analysis {
+    pub diagnostics: Vec<Diagnostic>,
+    pub definitions: Vec<Definition>,
+    // words of the statements that assembled
+    pub listing: Vec<Listed>,
+}
+
+/// Check a file without producing output, for editors
+pub fn analyze(sources: &mut Sources, file: usize, options: &Options) -> Analysis {
+    run(sources, file, options).0
+}
+
+/// Both passes, the assembly is only finished when there are no errors
+fn run(sources: &mut Sources, file: usize, options: &Options) -> (Analysis, Option<Assembly>) {
+    let lines = match preprocess(sources, file) {
+        Ok(lines) => lines,
+        Err(e) => {
+            let analysis = Analysis {
+                diagnostics: vec![e],
+                definitions: vec![],
+                listing: vec![],
+            };
+            return (analysis, None);
+        }
+    };
+    let mut statements = vec![];
+    let mut parse_errors = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        match parse_statement(i, line) {
+            Ok(Some(statement)) => statements.push(statement),
+            Ok(None) => {}
+            Err(e) => parse_errors.push(e),
+        }
+    }
+
+    // CALLs that start out as JSR and turn into LD + JSRR when the target is
+    // too far, which moves everything after them, so lay out until none changes
+    let mut far_calls = HashSet::new();
+    let mut assembler = loop {
+        let mut assembler = Assembler::new(&lines, options);
+        let laid_out = assembler.first_pass(&statements, &far_calls);
+        let far = assembler.far_calls();
+        if far.is_empty() {
+            assembler.check_fallthrough(&laid_out);
+            assembler.second_pass(&laid_out);
+            break assembler;
+        }
+        far_calls.extend(far);
+    };
+
+    if !assembler.ended {
+        let text = &sources.file(file).text;
+        let span = Span {
+            file,
+            line: text.lines().count().max(1),
+            column: 1,
+            len: text.lines().last().unwrap_or_default().chars().count(),
+        };
+        assembler
+            .diagnostics
+            .push(Diagnostic::warning(span, "missing .END"));
+    }
+    let mut diagnostics = parse_errors;
+    diagnostics.append(&mut assembler.diagnostics);
+    // the same mistake can be found by both passes
+    diagnostics.sort_by_key(|d| (d.span.file, d.span.line, d.span.column));
+    diagnostics.dedup();
+
+    let analysis = Analysis {
+        definitions: assembler.definitions(&statements),
+        listing: assembler.listing.clone(),
+        diagnostics,
+    };
+    let failed = analysis
+        .diagnostics
+        .iter()
+        .any(|d| d.severity == Severity::Error);
+    let assembly = (!failed).then(|| assembler.finish());
+    (analysis, assembly)
+}
+
+struct Assembler<'a> {
+    lines: &'a [Line],
+    options: &'a Options,
+    // labels and constants -> (value, where they were defined)
+    symbols: HashMap<String, (Value, Span)>,
+    // labels in definition order
+    labels: Vec<String>,
+    globals: HashSet<String>,
+    origin: Option<u16>,
+    words: Vec<u16>,
+    object: ObjectFile,
+    // object symbol name -> index
+    symbol_indices: HashMap<String, u16>,
+    // (line, address, target) of every CALL assembled as JSR
+    calls: Vec<(usize, u16, Expr)>,
+    literal_count: usize,
+    listing: Vec<Listed>,
+    // errors and warnings so far
+    diagnostics: Vec<Diagnostic>,
+    // seen .END
+    ended: bool,
+}
+
+impl<'a> Assembler<'a> {
+    fn new(lines: &'a [Line], options: &'a Options) -> Self {
+        Self {
+            lines,
+            options,
+            symbols: HashMap::new(),
+            labels: vec![],
+            globals: HashSet::new(),
+            origin: None,
+            words: vec![],
+            object: ObjectFile::new(),
+            symbol_indices: HashMap::new(),
+            calls: vec![],
+            literal_count: 0,
+            listing: vec![],
+            diagnostics: vec![],
+            ended: false,
+        }
+    }
+
+    /// Keep an error of one statement and go on with the next
+    fn report<T>(&mut self, result: Result<T, Diagnostic>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(diagnostic) => {
+                self.diagnostics.push(diagnostic);
+                None
+            }
+        }
+    }
+
+    fn error(&self, statement: &Statement, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.lines[statement.line].error(span, message)
+    }
+
+    /// Expand pseudo-instructions, assign addresses, define labels and constants
+    ///
+    /// `far_calls` holds the lines of CALLs that need the long form.
+    fn first_pass(
+        &mut self,
+        statements: &[Statement],
+        far_calls: &HashSet<usize>,
+    ) -> Vec<Statement> {
+        let mut laid_out = vec![];
+        let mut address: u32 = 0;
+        let mut pool = vec![];
+        for statement in statements {
+            let op = statement.op.as_ref().map(|(op, _)| op.as_str());
+            if op == Some(".END") {
+                self.ended = true;
+                break;
+            }
+            if op == Some(".POOL") {
+                let count = self.expect_count(statement, 0);
+                self.report(count);
+                let mut statement = statement.clone();
+                statement.op = None;
+                self.place(statement, &mut address, &mut laid_out);
+                for literal in pool.drain(..).map(|(_, literal)| literal) {
+                    self.place(literal, &mut address, &mut laid_out);
+                }
+                continue;
+            }
+            let expanded = self.expand(statement, far_calls, &mut pool);
+            for statement in self.report(expanded).unwrap_or_default() {
+                self.place(statement, &mut address, &mut laid_out);
+            }
+        }
+        for (_, literal) in pool {
+            self.place(literal, &mut address, &mut laid_out);
+        }
+
+        if self.origin.is_none() && !self.options.relocatable {
+            let span = self.lines.first().map(|line| line.span).unwrap_or_default();
+            self.diagnostics
+                .push(Diagnostic::new(span, "missing .ORIG"));
+        }
+        laid_out
+    }
+
+    /// Give the statement its address and define its label
+    fn place(
+        &mut self,
+        mut statement: Statement,
+        address: &mut u32,
+        laid_out: &mut Vec<Statement>,
+    ) {
+        let op = statement.op.as_ref().map(|(op, _)| op.as_str());
+        let op_span = statement
+            .op
+            .as_ref()
+            .map(|(_, span)| *span)
+            .unwrap_or_default();
+        if op == Some(".ORIG") {
+            if self.origin.is_some() {
+                let message = "only one .ORIG per file, link separate files instead";
+                self.diagnostics
+                    .push(self.error(&statement, op_span, message));
+            } else if let Some(origin) = self.report(self.constant(&statement, 0, 0xffff)) {
+                self.origin = Some(origin as u16);
+                *address = if self.options.relocatable {
+                    0
+                } else {
+                    origin as u32
+                };
+            }
+        }
+        statement.address = *address as u16;
+
+        if op == Some(".EQU") {
+            let defined = self.define_constant(&statement);
+            self.report(defined);
+            return;
+        }
+        if let Some((label, span)) = &statement.label {
+            let value = if self.options.relocatable {
+                Value {
+                    value: *address as i32,
+                    base: Base::Section,
+                }
+            } else {
+                Value::absolute(*address as i32)
+            };
+            let defined = self.define(&statement, label, *span, value);
+            // literal pool labels stay out of the symbol table
+            if self.report(defined).is_some() && statement.provenance != Provenance::Literal {
+                self.labels.push(label.clone());
+            }
+        }
+        if let Some(".GLOBAL" | ".EXTERN") = op {
+            let declared = self.declare(&statement);
+            self.report(declared);
+        }
+
+        let size = self.report(self.size(&statement)).unwrap_or(0);
+        if size > 0 && self.origin.is_none() && !self.options.relocatable {
+            self.diagnostics
+                .push(self.error(&statement, op_span, "code before .ORIG"));
+        }
+        *address += size as u32;
+        if *address > 0x10000 {
+            let message = "program runs past the end of memory";
+            self.diagnostics
+                .push(self.error(&statement, op_span, message));
+            *address = 0x10000;
+        }
+        if let Some(("CALL", Some(Operand::Expr(target)))) = statement
+            .op
+            .as_ref()
+            .map(|(op, _)| (op.as_str(), statement.operands.first()))
+        {
+            self.calls
+                .push((statement.line, statement.address, target.clone()));
+        }
+        laid_out.push(statement);
+    }
+
+    /// Labels and constants with every operand using them, sorted by name
+    fn definitions(&self, statements: &[Statement]) -> Vec<Definition> {
+        let mut uses: HashMap<&str, Vec<Span>> = HashMap::new();
+        for statement in statements {
+            let op = statement.op.as_ref().map(|(op, _)| op.as_str());
+            // names being declared are not uses
+            let skip = match op {
+                Some(".GLOBAL" | ".EXTERN") => statement.operands.len(),
+                Some(".EQU") if statement.label.is_none() => 1,
+                _ => 0,
+            };
+            for operand in &statement.operands[skip..] {
+                if let Operand::Expr(expr) = operand {
+                    for (name, span) in expr.symbols() {
+                        uses.entry(name).or_default().push(span);
+                    }
+                }
+            }
+        }
+
+        let mut definitions: Vec<Definition> = self
+            .symbols
+            .iter()
+            .filter(|(name, _)| !name.starts_with('='))
+            .map(|(name, (value, span))| Definition {
+                name: name.clone(),
+                value: value.clone(),
+                span: *span,
+                uses: uses.remove(name.as_str()).unwrap_or_default(),
+            })
+            .collect();
+        definitions.sort_by(|a, b| a.name.cmp(&b.name));
+        definitions
+    }
+
+    /// Warn about instructions that run on into data instead of jumping away
+    fn check_fallthrough(&mut self, statements: &[Statement]) {
+        let mut previous: Option<&Statement> = None;
+        for statement in statements {
+            let Some((op, op_span)) = &statement.op else {
+                continue;
+            };
+            let data = matches!(op.as_str(), ".FILL" | ".BLKW" | ".STRINGZ")
+                || statement.provenance == Provenance::Literal;
+            if data {
+                if let Some(instruction) = previous.filter(|p| p.address + 1 == statement.address) {
+                    let span = instruction.op.as_ref().unwrap().1;
+                    let warning = self.lines[statement.line]
+                        .warning(*op_span, "execution falls through into data")
+                        .with_note(span, "this instruction does not branch, return or halt");
+                    self.diagnostics.push(warning);
+                }
+                previous = None;
+            } else if op.starts_with('.') {
+                continue;
+            } else {
+                previous = (!ends_flow(statement)).then_some(statement);
+            }
+        }
+    }
+
+    /// Real instructions for a pseudo-instruction, literals go to the pool
+    ///
+    ///     PUSH R1          ADD R6, R6, #-1 / STR R1, R6, #0
+    ///     POP R1           LDR R1, R6, #0 / ADD R6, R6, #1
+    ///     MOV R1, R2       ADD R1, R2, #0
+    ///     CLR R1           AND R1, R1, #0
+    ///     SUB R1, R2, R3   NOT, ADD #1 and ADD, R3 is restored when it is negated in place
+    ///                      and ADD R1, R1, #0 sets COND from the result again
+    ///     SUB R1, R2, #5   ADD R1, R2, #-5
+    ///     LDIMM R1, #500   AND + ADD if it fits in imm5, else LD from the pool
+    ///     CALL LABEL       JSR, or LD R7 from the pool + JSRR R7 when out of range
+    fn expand(
+        &mut self,
+        statement: &Statement,
+        far_calls: &HashSet<usize>,
+        pool: &mut Vec<(Option<String>, Statement)>,
+    ) -> Result<Vec<Statement>, Diagnostic> {
+        let Some((op, op_span)) = statement.op.clone() else {
+            return Ok(vec![statement.clone()]);
+        };
+        let register = |r: u16| Operand::Register(r, op_span);
+        let number = |value: i32| Operand::Expr(Expr::Number(value, op_span));
+        const SP: u16 = 6;
+        let instructions = match op.as_str() {
+            "PUSH" => {
+                self.expect_count(statement, 1)?;
+                let r = self.register(statement, 0)?;
+                vec![
+                    ("ADD", vec![register(SP), register(SP), number(-1)]),
+                    ("STR", vec![register(r), register(SP), number(0)]),
+                ]
+            }
+            "POP" => {
+                self.expect_count(statement, 1)?;
+                let r = self.register(statement, 0)?;
+                vec![
+                    ("LDR", vec![register(r), register(SP), number(0)]),
+                    ("ADD", vec![register(SP), register(SP), number(1)]),
+                ]
+            }
+            "MOV" => {
+                self.expect_count(statement, 2)?;
+                let (dr, sr) = (self.register(statement, 0)?, self.register(statement, 1)?);
+                vec![("ADD", vec![register(dr), register(sr), number(0)])]
+            }
+            "CLR" => {
+                self.expect_count(statement, 1)?;
+                let r = self.register(statement, 0)?;
+                vec![("AND", vec![register(r), register(r), number(0)])]
+            }
+            "SUB" => {
+                self.expect_count(statement, 3)?;
+                let (dr, sr1) = (self.register(statement, 0)?, self.register(statement, 1)?);
+                match &statement.operands[2] {
+                    Operand::Expr(expr) => {
+                        let negated = Expr::Negate(Box::new(expr.clone()), expr.span());
+                        vec![(
+                            "ADD",
+                            vec![register(dr), register(sr1), Operand::Expr(negated)],
+                        )]
+                    }
+                    Operand::Register(sr2, _) if *sr2 == sr1 => {
+                        vec![("AND", vec![register(dr), register(dr), number(0)])]
+                    }
+                    Operand::Register(sr2, _) if dr != sr1 => vec![
+                        ("NOT", vec![register(dr), register(*sr2)]),
+                        ("ADD", vec![register(dr), register(dr), number(1)]),
+                        ("ADD", vec![register(dr), register(sr1), register(dr)]),
+                    ],
+                    Operand::Register(sr2, _) => {
+                        let sr2 = *sr2;
+                        vec![
+                            ("NOT", vec![register(sr2), register(sr2)]),
+                            ("ADD", vec![register(sr2), register(sr2), number(1)]),
+                            ("ADD", vec![register(dr), register(dr), register(sr2)]),
+                            ("NOT", vec![register(sr2), register(sr2)]),
+                            ("ADD", vec![register(sr2), register(sr2), number(1)]),
+                            // COND has to come from the result, not the restored sr2
+                            ("ADD", vec![register(dr), register(dr), number(0)]),
+                        ]
+                    }
+                    operand => {
+                        return Err(self.error(
+                            statement,
+                            operand.span(),
+                            "expected a register or a number",
+                        ))
+                    }
+                }
+            }
+            "LDIMM" => {
+                self.expect_count(statement, 2)?;
+                let r = self.register(statement, 0)?;
+                let expr = self.expect_expr(statement, 1, 2)?;
+                // values not known yet, e.g. later labels, always go to the pool
+                match self.eval(statement, expr) {
+                    Ok(Value {
+                        value: value @ -16..=15,
+                        base: Base::Absolute,
+                    }) => vec![
+                        ("AND", vec![register(r), register(r), number(0)]),
+                        ("ADD", vec![register(r), register(r), number(value)]),
+                    ],
+                    _ => {
+                        let literal = self.literal(statement, expr, pool);
+                        vec![("LD", vec![register(r), literal])]
+                    }
+                }
+            }
+            "CALL" if far_calls.contains(&statement.line) => {
+                let target = self.expect_expr(statement, 0, 1)?;
+                let literal = self.literal(statement, target, pool);
+                vec![
+                    ("LD", vec![register(7), literal]),
+                    ("JSRR", vec![register(7)]),
+                ]
+            }
+            _ => return Ok(vec![statement.clone()]),
+        };
+
+        Ok(instructions
+            .into_iter()
+            .enumerate()
+            .map(|(i, (op, operands))| Statement {
+                line: statement.line,
+                label: if i == 0 {
+                    statement.label.clone()
+                } else {
+                    None
+                },
+                op: Some((op.to_string(), op_span)),
+                operands,
+                address: 0,
+                provenance: Provenance::Expansion,
+            })
+            .collect())
+    }
+
+    /// Operand loading `expr` from the literal pool, equal literals share an entry
+    fn literal(
+        &mut self,
+        statement: &Statement,
+        expr: &Expr,
+        pool: &mut Vec<(Option<String>, Statement)>,
+    ) -> Operand {
+        let key = match expr {
+            Expr::Symbol(name, _) => Some(name.clone()),
+            _ => match self.eval(statement, expr) {
+                Ok(value) if value.base == Base::Absolute => Some(format!("#{}", value.value)),
+                _ => None,
+            },
+        };
+        let existing = pool
+            .iter()
+            .find(|(other, _)| key.is_some() && *other == key)
+            .and_then(|(_, literal)| literal.label.clone());
+        let (label, span) = existing.unwrap_or_else(|| {
+            self.literal_count += 1;
+            // = can not start a label in the source, so these never clash
+            let label = (format!("={}", self.literal_count), expr.span());
+            pool.push((
+                key,
+                Statement {
+                    line: statement.line,
+                    label: Some(label.clone()),
+                    op: Some((".FILL".to_string(), expr.span())),
+                    operands: vec![Operand::Expr(expr.clone())],
+                    address: 0,
+                    provenance: Provenance::Literal,
+                },
+            ));
+            label
+        });
+        Operand::Expr(Expr::Symbol(label, span))
+    }
+
+    /// Lines of the CALLs assembled as JSR whose target turned out to be out of range
+    fn far_calls(&self) -> Vec<usize> {
+        self.calls
+            .iter()
+            .filter(|(_, address, target)| {
+                let Ok(value) = target.eval(&|name| self.lookup(name)) else {
+                    return false;
+                };
+                // imports are patched by the linker, which checks the range itself
+                if let Base::Extern(_) = value.base {
+                    return false;
+                }
+                let offset = value.value - (*address as i32 + 1);
+                let limit = 1 << (OFFSET11 - 1);
+                !(-limit..limit).contains(&offset)
+            })
+            .map(|(line, _, _)| *line)
+            .collect()
+    }
+
+    fn define(
+        &mut self,
+        statement: &Statement,
+        name: &str,
+        span: Span,
+        value: Value,
+    ) -> Result<(), Diagnostic> {
+        if let Some((_, first)) = self.symbols.get(name) {
+            return Err(self
+                .error(statement, span, format!("{} is already defined", name))
+                .with_note(*first, "first defined here"));
+        }
+        self.symbols.insert(name.to_string(), (value, span));
+        Ok(())
+    }
+
+    /// `NAME .EQU expr` or `.EQU NAME, expr`
+    fn define_constant(&mut self, statement: &Statement) -> Result<(), Diagnostic> {
+        let op_span = statement.op.as_ref().unwrap().1;
+        let (name, span, operand) = match (&statement.label, statement.operands.as_slice()) {
+            (Some((name, span)), [operand]) => (name.clone(), *span, operand),
+            (None, [Operand::Expr(Expr::Symbol(name, span)), operand]) => {
+                (name.clone(), *span, operand)
+            }
+            _ => return Err(self.error(statement, op_span, "expected NAME .EQU value")),
+        };
+        let Operand::Expr(expr) = operand else {
+            return Err(self.error(statement, operand.span(), "expected a value"));
+        };
+        let value = expr
+            .eval(&|name| self.lookup(name))
+            .map_err(|e| self.with_expansion(statement, e))?;
+        self.define(statement, &name, span, value)
+    }
+
+    /// `.GLOBAL a, b` exports labels, `.EXTERN a, b` imports them
+    fn declare(&mut self, statement: &Statement) -> Result<(), Diagnostic> {
+        let (op, op_span) = statement.op.clone().unwrap();
+        if op == ".EXTERN" && !self.options.relocatable {
+            return Err(self.error(
+                statement,
+                op_span,
+                ".EXTERN needs relocatable output (--relocatable)",
+            ));
+        }
+        for operand in &statement.operands {
+            let Operand::Expr(Expr::Symbol(name, span)) = operand else {
+                return Err(self.error(statement, operand.span(), "expected a label name"));
+            };
+            if op == ".GLOBAL" {
+                self.globals.insert(name.clone());
+            } else {
+                let value = Value {
+                    value: 0,
+                    base: Base::Extern(name.clone()),
+                };
+                self.define(statement, name, *span, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Words the statement occupies
+    fn size(&self, statement: &Statement) -> Result<usize, Diagnostic> {
+        let Some((op, _)) = &statement.op else {
+            return Ok(0);
+        };
+        Ok(match op.as_str() {
+            ".FILL" => 1,
+            ".BLKW" => self.constant(statement, 0, 0xffff)? as usize,
+            ".STRINGZ" => match statement.operands.as_slice() {
+                [Operand::Str(text, _)] => text.chars().count() + 1,
+                _ => {
+                    return Err(self.error(
+                        statement,
+                        statement.op.as_ref().unwrap().1,
+                        ".STRINGZ expects a string",
+                    ))
+                }
+            },
+            op if op.starts_with('.') => 0,
+            _ => 1,
+        })
+    }
+
+    /// First operand as a number in min..=max, evaluated right away
+    fn constant(&self, statement: &Statement, min: i32, max: i32) -> Result<i32, Diagnostic> {
+        let op_span = statement.op.as_ref().unwrap().1;
+        let Some(Operand::Expr(expr)) = statement.operands.first() else {
+            return Err(self.error(statement, op_span, "expected a number"));
+        };
+        let value = expr
+            .eval(&|name| self.lookup(name))
+            .map_err(|e| self.with_expansion(statement, e))?;
+        if value.base != Base::Absolute {
+            return Err(self.error(statement, expr.span(), "expected a number, not an address"));
+        }
+        if value.value < min || value.value > max {
+            return Err(self.error(
+                statement,
+                expr.span(),
+                format!("{} is not in {}..{}", value.value, min, max),
+            ));
+        }
+        Ok(value.value)
+    }
+
+    fn lookup(&self, name: &str) -> Option<Value> {
+        self.symbols.get(name).map(|(value, _)| value.clone())
+    }
+
+    /// Add the macro expansion notes of the statement to an expression error
+    fn with_expansion(&self, statement: &Statement, diagnostic: Diagnostic) -> Diagnostic {
+        self.error(statement, diagnostic.span, diagnostic.message)
+    }
+
+    fn second_pass(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            let words = self.statement_words(statement);
+            // keep the addresses of everything after a bad statement
+            let size = self.size(statement).unwrap_or(0);
+            let words = self.report(words).unwrap_or_else(|| vec![0; size]);
+            self.list(statement, &words);
+            self.words.extend(words);
+        }
+    }
+
+    fn statement_words(&mut self, statement: &Statement) -> Result<Vec<u16>, Diagnostic> {
+        let (op, op_span) = statement.op.clone().unwrap_or_default();
+        Ok(match op.as_str() {
+            "" => vec![],
+            ".FILL" => {
+                let expr = self.expect_expr(statement, 0, 1)?;
+                vec![self.fill(statement, expr)?]
+            }
+            ".BLKW" => {
+                let count = self.constant(statement, 0, 0xffff)? as usize;
+                let fill = match statement.operands.get(1) {
+                    Some(_) => {
+                        let expr = self.expect_expr(statement, 1, 2)?;
+                        self.fill(statement, expr)?
+                    }
+                    None => 0,
+                };
+                vec![fill; count]
+            }
+            ".STRINGZ" => {
+                let Some(Operand::Str(text, _)) = statement.operands.first() else {
+                    return Err(self.error(statement, op_span, ".STRINGZ expects a string"));
+                };
+                text.chars().map(|c| c as u16).chain([0]).collect()
+            }
+            op if op.starts_with('.') => vec![],
+            _ => vec![self.encode(statement, &op, op_span)?],
+        })
+    }
+
+    /// Add the words to the listing, an expansion is one entry
+    fn list(&mut self, statement: &Statement, words: &[u16]) {
+        let line = self.lines[statement.line].span;
+        if let Some(last) = self.listing.last_mut() {
+            let continues = last.line == line
+                && last.address as usize + last.words.len() == statement.address as usize;
+            if statement.provenance == Provenance::Expansion
+                && last.provenance == Provenance::Expansion
+                && continues
+            {
+                last.words.extend(words);
+                return;
+            }
+        }
+        let column = match (&statement.op, &statement.label) {
+            (Some((_, span)), _) => span.column,
+            (None, Some((_, span))) => span.column + span.len,
+            (None, None) => 1,
+        };
+        let op = statement.op.as_ref().map(|(op, _)| op.clone());
+        let op = op.unwrap_or_default();
+        let data = statement.provenance == Provenance::Literal
+            || matches!(op.as_str(), ".FILL" | ".BLKW" | ".STRINGZ");
+        let expansions = &self.lines[statement.line].expansions;
+        self.listing.push(Listed {
+            address: statement.address,
+            words: words.to_vec(),
+            line,
+            column,
+            call: expansions.first().map(|(call, _)| *call),
+            provenance: statement.provenance,
+            op,
+            data,
+        });
+    }
+
+    /// Encode one instruction
+    fn encode(
+        &mut self,
+        statement: &Statement,
+        op: &str,
+        op_span: Span,
+    ) -> Result<u16, Diagnostic> {
+        let opcode = |opcode: Opcode| u16::from(opcode) << 12;
+        let word = match op {
+            "ADD" | "AND" => {
+                self.expect_count(statement, 3)?;
+                let base = opcode(if op == "ADD" {
+                    Opcode::ADD
+                } else {
+                    Opcode::AND
+                });
+                let dr = self.register(statement, 0)?;
+                let sr1 = self.register(statement, 1)?;
+                match &statement.operands[2] {
+                    Operand::Register(sr2, _) => base | dr << 9 | sr1 << 6 | sr2,
+                    Operand::Expr(expr) => {
+                        let imm5 = self.immediate(statement, expr, IMM5)?;
+                        base | dr << 9 | sr1 << 6 | 1 << 5 | imm5
+                    }
+                    operand => {
+                        return Err(self.error(
+                            statement,
+                            operand.span(),
+                            "expected a register or a number",
+                        ))
+                    }
+                }
+            }
+            "NOT" => {
+                self.expect_count(statement, 2)?;
+                opcode(Opcode::NOT)
+                    | self.register(statement, 0)? << 9
+                    | self.register(statement, 1)? << 6
+                    | 0x3f
+            }
+            "JMP" | "JSRR" => {
+                self.expect_count(statement, 1)?;
+                let base = if op == "JMP" {
+                    opcode(Opcode::JMP)
+                } else {
+                    opcode(Opcode::JSR)
+                };
+                base | self.register(statement, 0)? << 6
+            }
+            "RET" => {
+                self.expect_count(statement, 0)?;
+                opcode(Opcode::JMP) | 7 << 6
+            }
+            "RTI" => {
+                self.expect_count(statement, 0)?;
+                opcode(Opcode::RTI)
+            }
+            "JSR" | "CALL" => {
+                let expr = self.expect_expr(statement, 0, 1)?;
+                opcode(Opcode::JSR)
+                    | 1 << 11
+                    | self.pc_offset(statement, expr, RelocationKind::Offset11)?
+            }
+            "LD" | "LDI" | "ST" | "STI" | "LEA" => {
+                self.expect_count(statement, 2)?;
+                let base = opcode(match op {
+                    "LD" => Opcode::LD,
+                    "LDI" => Opcode::LDI,
+                    "ST" => Opcode::ST,
+                    "STI" => Opcode::STI,
+                    _ => Opcode::LEA,
+                });
+                let register = self.register(statement, 0)?;
+                let expr = self.expect_expr(statement, 1, 2)?;
+                base | register << 9 | self.pc_offset(statement, expr, RelocationKind::Offset9)?
+            }
+            "LDR" | "STR" => {
+                self.expect_count(statement, 3)?;
+                let base = opcode(if op == "LDR" {
+                    Opcode::LDR
+                } else {
+                    Opcode::STR
+                });
+                let register = self.register(statement, 0)?;
+                let base_register = self.register(statement, 1)?;
+                let expr = self.expect_expr(statement, 2, 3)?;
+                base | register << 9 | base_register << 6 | self.offset6(statement, expr)?
+            }
+            "TRAP" => {
+                let expr = self.expect_expr(statement, 0, 1)?;
+                let value = self.absolute(statement, expr)?;
+                if !(0..=mask(TRAP_VECTOR) as i32).contains(&value) {
+                    return Err(self.error(
+                        statement,
+                        expr.span(),
+                        format!("trap vector {} is not in 0..{}", value, mask(TRAP_VECTOR)),
+                    ));
+                }
+                opcode(Opcode::TRAP) | value as u16
+            }
+            _ => match trap_alias(op) {
+                Some(vector) => {
+                    self.expect_count(statement, 0)?;
+                    opcode(Opcode::TRAP) | vector
+                }
+                None => {
+                    let nzp = branch_flags(op).ok_or_else(|| {
+                        self.error(statement, op_span, format!("unknown instruction {}", op))
+                    })?;
+                    let expr = self.expect_expr(statement, 0, 1)?;
+                    opcode(Opcode::BR)
+                        | nzp << 9
+                        | self.pc_offset(statement, expr, RelocationKind::Offset9)?
+                }
+            },
+        };
+        Ok(word)
+    }
+
+    fn expect_count(&self, statement: &Statement, count: usize) -> Result<(), Diagnostic> {
+        if statement.operands.len() == count {
+            return Ok(());
+        }
+        let (op, span) = statement.op.as_ref().unwrap();
+        Err(self.error(
+            statement,
+            *span,
+            format!(
+                "{} expects {} operands, {} given",
+                op,
+                count,
+                statement.operands.len()
+            ),
+        ))
+    }
+
+    /// Operand `index` as an expression, `count` is the operand count of the statement
+    fn expect_expr<'s>(
+        &self,
+        statement: &'s Statement,
+        index: usize,
+        count: usize,
+    ) -> Result<&'s Expr, Diagnostic> {
+        let (op, span) = statement.op.as_ref().unwrap();
+        if statement.operands.len() > count || statement.operands.len() <= index {
+            let message = format!(
+                "{} expects {} operands, {} given",
+                op,
+                count,
+                statement.operands.len()
+            );
+            return Err(self.error(statement, *span, message));
+        }
+        match &statement.operands[index] {
+            Operand::Expr(expr) => Ok(expr),
+            operand => Err(self.error(statement, operand.span(), "expected a label or a number")),
+        }
+    }
+
+    fn register(&self, statement: &Statement, index: usize) -> Result<u16, Diagnostic> {
+        match &statement.operands[index] {
+            Operand::Register(register, _) => Ok(*register),
+            operand => Err(self.error(statement, operand.span(), "expected a register")),
+        }
+    }
+
+    fn eval(&self, statement: &Statement, expr: &Expr) -> Result<Value, Diagnostic> {
+        expr.eval(&|name| self.lookup(name))
+            .map_err(|e| self.with_expansion(statement, e))
+    }
+
+    /// Value that must be a plain number
+    fn absolute(&self, statement: &Statement, expr: &Expr) -> Result<i32, Diagnostic> {
+        let value = self.eval(statement, expr)?;
+        if value.base != Base::Absolute {
+            return Err(self.error(statement, expr.span(), "expected a number, not an address"));
+        }
+        Ok(value.value)
+    }
+
+    /// Signed immediate of `bits` bits
+    fn immediate(&self, statement: &Statement, expr: &Expr, bits: u8) -> Result<u16, Diagnostic> {
+        let value = self.absolute(statement, expr)?;
+        self.fit(statement, expr.span(), value, bits, &format!("imm{}", bits))
+    }
+
+    fn fit(
+        &self,
+        statement: &Statement,
+        span: Span,
+        value: i32,
+        bits: u8,
+        field: &str,
+    ) -> Result<u16, Diagnostic> {
+        let limit = 1 << (bits - 1);
+        if value < -limit || value >= limit {
+            let message = format!(
+                "{} does not fit in {} ({}..{})",
+                value,
+                field,
+                -limit,
+                limit - 1
+            );
+            return Err(self.error(statement, span, message));
+        }
+        Ok(value as u16 & mask(bits))
+    }
+
+    /// PC relative offset to a label
+    fn pc_offset(
+        &mut self,
+        statement: &Statement,
+        expr: &Expr,
+        kind: RelocationKind,
+    ) -> Result<u16, Diagnostic> {
+        let (bits, field) = match kind {
+            RelocationKind::Offset11 => (OFFSET11, "offset11"),
+            _ => (OFFSET9, "offset9"),
+        };
+        let value = self.eval(statement, expr)?;
+        let next = statement.address as i32 + 1;
+        match value.base {
+            Base::Absolute if self.options.relocatable => Err(self.error(
+                statement,
+                expr.span(),
+                "relocatable code can not jump to an absolute address",
+            )),
+            Base::Extern(name) => {
+                self.relocate(statement, kind, &name, value.value);
+                Ok(0)
+            }
+            // in absolute code every address is absolute, so both cases are a plain distance
+            _ => {
+                let offset = ((value.value - next) as i16) as i32;
+                self.fit(statement, expr.span(), offset, bits, field)
+            }
+        }
+    }
+
+    /// Base register offset of LDR / STR
+    fn offset6(&mut self, statement: &Statement, expr: &Expr) -> Result<u16, Diagnostic> {
+        let value = self.eval(statement, expr)?;
+        match value.base {
+            Base::Absolute => self.fit(statement, expr.span(), value.value, OFFSET6, "offset6"),
+            Base::Extern(name) => {
+                self.relocate(statement, RelocationKind::Offset6, &name, value.value);
+                Ok(0)
+            }
+            Base::Section => {
+                Err(self.error(statement, expr.span(), "expected a number, not an address"))
+            }
+        }
+    }
+
+    /// .FILL / .BLKW value, addresses in relocatable code become relocations
+    fn fill(&mut self, statement: &Statement, expr: &Expr) -> Result<u16, Diagnostic> {
+        let value = self.eval(statement, expr)?;
+        match value.base {
+            Base::Absolute => {
+                if !(-0x8000..=0xffff).contains(&value.value) {
+                    return Err(self.error(
+                        statement,
+                        expr.span(),
+                        format!("{} does not fit in a word", value.value),
+                    ));
+                }
+                Ok(value.value as u16)
+            }
+            Base::Section => {
+                self.relocate(statement, RelocationKind::Word, SECTION_SYMBOL, value.value);
+                Ok(0)
+            }
+            Base::Extern(name) => {
+                self.relocate(statement, RelocationKind::Word, &name, value.value);
+                Ok(0)
+            }
+        }
+    }
+
+    /// Relocation for the word being emitted
+    fn relocate(&mut self, statement: &Statement, kind: RelocationKind, symbol: &str, addend: i32) {
+        let symbol = self.object_symbol(symbol);
+        // .BLKW repeats its word, every copy gets its own relocation
+        let offset = self.words.len() as u16;
+        let count = match statement.op.as_ref().map(|(op, _)| op.as_str()) {
+            Some(".BLKW") => self.constant(statement, 0, 0xffff).unwrap_or(1) as u16,
+            _ => 1,
+        };
+        for i in 0..count {
+            self.object.relocations.push(Relocation {
+                section: 0,
+                offset: offset + i,
+                kind,
+                symbol,
+                addend: addend as i16,
+            });
+        }
+    }
+
+    /// Index of a symbol in the object, imports are added on first use
+    fn object_symbol(&mut self, name: &str) -> u16 {
+        if let Some(index) = self.symbol_indices.get(name) {
+            return *index;
+        }
+        let index = self.object.symbols.len() as u16;
+        self.object.symbols.push(Symbol {
+            name: name.to_string(),
+            binding: Binding::Import,
+            section: 0,
+            offset: 0,
+        });
+        self.symbol_indices.insert(name.to_string(), index);
+        index
+    }
+
+    fn finish(mut self) -> Assembly {
+        let origin = self.origin.unwrap_or(0x3000);
+        let mut symbols = SymbolTable::new();
+        for label in &self.labels {
+            let (value, _) = &self.symbols[label];
+            let address = if self.options.relocatable {
+                origin.wrapping_add(value.value as u16)
+            } else {
+                value.value as u16
+            };
+            symbols.insert(label, address);
+        }
+
+        if self.options.relocatable {
+            let mut object = ObjectFile {
+                sections: vec![Section {
+                    name: SECTION_NAME.to_string(),
+                    origin: None,
+                    words: self.words.clone(),
+                }],
+                symbols: vec![Symbol {
+                    name: SECTION_SYMBOL.to_string(),
+                    binding: Binding::Local,
+                    section: 0,
+                    offset: 0,
+                }],
+                relocations: vec![],
+            };
+            let mut indices: HashMap<String, u16> = HashMap::new();
+            indices.insert(SECTION_SYMBOL.to_string(), 0);
+            for label in &self.labels {
+                let (value, _) = &self.symbols[label];
+                let binding = if self.globals.contains(label) {
+                    Binding::Export
+                } else {
+                    Binding::Local
+                };
+                indices.insert(label.clone(), object.symbols.len() as u16);
+                object.symbols.push(Symbol {
+                    name: label.clone(),
+                    binding,
+                    section: 0,
+                    offset: value.value as u16,
+                });
+            }
+            // imports, renumbered after the labels
+            let mut renumber = vec![0; self.object.symbols.len()];
+            for (old, symbol) in self.object.symbols.iter().enumerate() {
+                renumber[old] = *indices.entry(symbol.name.clone()).or_insert_with(|| {
+                    object.symbols.push(symbol.clone());
+                    object.symbols.len() as u16 - 1
+                });
+            }
+            for relocation in &mut self.object.relocations {
+                relocation.symbol = renumber[relocation.symbol as usize];
+            }
+            object.relocations = std::mem::take(&mut self.object.relocations);
+            self.object = object;
+        }
+
+        Assembly {
+            program: Program {
+                origin,
+                words: self.words,
+            },
+            symbols,
+            object: self.object,
+            listing: self.listing,
+            definitions: vec![],
+            warnings: vec![],
+        }
+    }
+}
+
+/// Split a line into label, mnemonic and operands
+fn parse_statement(index: usize, line: &Line) -> Result<Option<Statement>, Diagnostic> {
+    let tokens = &line.tokens;
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let mut position = 0;
+    let mut label = None;
+    if let Some(name) = tokens[0].ident() {
+        if !is_mnemonic(name) {
+            if register(name).is_some() {
+                return Err(line.error(
+                    tokens[0].span,
+                    format!("{} is a register, not a label", name),
+                ));
+            }
+            label = Some((name.to_string(), tokens[0].span));
+            position = 1;
+            if tokens.get(1).map(|token| &token.kind) == Some(&TokenKind::Colon) {
+                position = 2;
+            }
+        }
+    }
+
+    let Some(token) = tokens.get(position) else {
+        return Ok(Some(Statement {
+            line: index,
+            label,
+            op: None,
+            operands: vec![],
+            address: 0,
+            provenance: Provenance::Source,
+        }));
+    };
+    let op = match &token.kind {
+        TokenKind::Directive(name) => format!(".{}", name),
+        TokenKind::Ident(name) if is_mnemonic(name) => name.to_ascii_uppercase(),
+        TokenKind::Ident(name) => {
+            // `ADDD R1, R2, R3` reads as label ADDD and instruction R1
+            let unknown = match &label {
+                Some((label, span))
+                    if register(name).is_some()
+                        || tokens.len() > position + 1
+                            && tokens[position + 1].kind == TokenKind::Comma =>
+                {
+                    (label.clone(), *span)
+                }
+                _ => (name.clone(), token.span),
+            };
+            return Err(line.error(unknown.1, format!("unknown instruction {}", unknown.0)));
+        }
+        _ => return Err(line.error(token.span, "expected an instruction or a directive")),
+    };
+
+    let mut operands = vec![];
+    for group in split_operands(&tokens[position + 1..]) {
+        operands.push(parse_operand(line, token.span, group)?);
+    }
+    Ok(Some(Statement {
+        line: index,
+        label,
+        op: Some((op, token.span)),
+        operands,
+        address: 0,
+        provenance: Provenance::Source,
+    }))
+}
+
+fn parse_operand(line: &Line, op_span: Span, tokens: &[Token]) -> Result<Operand, Diagnostic> {
+    match tokens {
+        [] => Err(line.error(op_span, "missing operand")),
+        [token] => match &token.kind {
+            TokenKind::Ident(name) if register(name).is_some() => {
+                Ok(Operand::Register(register(name).unwrap(), token.span))
+            }
+            TokenKind::Str(text) => Ok(Operand::Str(text.clone(), token.span)),
+            _ => Ok(Operand::Expr(
+                Expr::parse(tokens).map_err(|e| line.error(e.span, e.message))?,
+            )),
+        },
+        _ => Ok(Operand::Expr(
+            Expr::parse(tokens).map_err(|e| line.error(e.span, e.message))?,
+        )),
+    }
+}
+
+/// Operands are separated by commas, an operand can be several tokens
+fn split_operands(tokens: &[Token]) -> Vec<&[Token]> {
+    if tokens.is_empty() {
+        return vec![];
+    }
+    tokens
+        .split(|token| token.kind == TokenKind::Comma)
+        .collect()
+}
+
+/// R0 - R7
+fn register(name: &str) -> Option<u16> {
+    let digit = name.strip_prefix(['R', 'r'])?;
+    match digit.parse::<u16>() {
+        Ok(register) if register < 8 && digit.len() == 1 => Some(register),
+        _ => None,
+    }
+}
+
+/// True if execution never continues with the next word
+fn ends_flow(statement: &Statement) -> bool {
+    let Some((op, _)) = &statement.op else {
+        return false;
+    };
+    match op.as_str() {
+        "BR" | "BRNZP" | "JMP" | "RET" | "RTI" | "HALT" => true,
+        "TRAP" => matches!(
+            statement.operands.first(),
+            Some(Operand::Expr(Expr::Number(0x25, _)))
+        ),
+        _ => false,
+    }
+}
+
+/// Instructions and pseudo-instructions, without trap aliases and branch variants
+pub const MNEMONICS: [&str; 23] = [
+    "ADD", "AND", "NOT", "JMP", "JSRR", "RET", "RTI", "JSR", "LD", "LDI", "ST", "STI", "LEA",
+    "LDR", "STR", "TRAP", "PUSH", "POP", "MOV", "CLR", "SUB", "LDIMM", "CALL",
+];
+
+/// Instruction names, pseudo-instructions, trap aliases and branch variants, any case
+pub fn is_mnemonic(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    MNEMONICS.contains(&name.as_str())
+        || trap_alias(&name).is_some()
+        || branch_flags(&name).is_some()
+}
+
+fn trap_alias(name: &str) -> Option<u16> {
+    match name {
+        "GETC" => Some(0x20),
+        "OUT" => Some(0x21),
+        "PUTS" => Some(0x22),
+        "IN" => Some(0x23),
+        "PUTSP" => Some(0x24),
+        "HALT" => Some(0x25),
+        _ => None,
+    }
+}
+
+/// nzp bits of BR, BRn, BRzp, ... (plain BR is BRnzp)
+fn branch_flags(name: &str) -> Option<u16> {
+    let flags = name.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some(0b111);
+    }
+    let mut nzp = 0;
+    let mut last = 8;
+    for c in flags.chars() {
+        let bit = match c {
+            'N' => 4,
+            'Z' => 2,
+            'P' => 1,
+            _ => return None,
+        };
+        // flags must be in n, z, p order without repeats
+        if bit >= last {
+            return None;
+        }
+        last = bit;
+        nzp |= bit;
+    }
+    Some(nzp)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble, Assembly, Options};
+    use crate::object::{Binding, RelocationKind};
+    use crate::source::{Severity, Sources};
+    use crate::vm::{Register, VM};
+    use std::path::Path;
+
+    fn assemble_text(text: &str, options: &Options) -> Result<Assembly, String> {
+        let mut sources = Sources::new();
+        let file = sources.add(Path::new("test.asm"), text.to_string());
+        assemble(&mut sources, file, options)
+            .map_err(|diagnostics| diagnostics.iter().map(|d| d.render(&sources)).collect())
+    }
+
+    #[test]
+    fn test_assemble_program() {
+        let assembly = assemble_text(
+            "        .ORIG x3000
+SIZE    .EQU 2
+        LEA R0, MSG
+        PUTS
+        AND R1, R1, #0
+LOOP    ADD R1, R1, #SIZE*2-5
+        BRnp LOOP
+        LDR R2, R6, #-1
+        JSR DONE
+DONE    HALT
+MSG     .STRINGZ \"hi\"
+        .FILL MSG+1
+        .BLKW 2, #-1
+        .END
+        ADD R9 ; ignored after .END",
+            &Options::default(),
+        )
+        .unwrap();
+
+        assert_eq!(assembly.program.origin, 0x3000);
+        assert_eq!(
+            assembly.program.words,
+            vec![
+                0xe007, // LEA R0, MSG
+                0xf022, // PUTS
+                0x5260, // AND R1, R1, #0
+                0x127f, // ADD R1, R1, #-1
+                0x0bfe, // BRnp LOOP
+                0x65bf, // LDR R2, R6, #-1
+                0x4800, // JSR DONE
+                0xf025, // HALT
+                0x0068, 0x0069, 0x0000, // "hi"
+                0x3009, // .FILL MSG+1
+                0xffff, 0xffff, // .BLKW
+            ]
+        );
+        assert_eq!(assembly.symbols.address("LOOP"), Some(0x3003));
+        assert_eq!(assembly.symbols.address("SIZE"), None);
+    }
+
+    #[test]
+    fn test_macro_error_points_at_definition_and_call() {
+        let error = assemble_text(
+            ".ORIG x3000
+.MACRO INC reg, n
+ADD reg, reg, n
+.ENDM
+INC R1, #1
+INC R1, #100
+.END",
+            &Options::default(),
+        )
+        .err()
+        .unwrap();
+        let expected = [
+            "test.asm:6:9: error: 100 does not fit in imm5 (-16..15)",
+            " 6 | INC R1, #100",
+            "   |         ^^^^",
+            "test.asm:3:1: note: in this line of macro INC",
+            " 3 | ADD reg, reg, n",
+            "   | ^^^^^^^^^^^^^^^",
+            "test.asm:6:1: note: in expansion of macro INC",
+            " 6 | INC R1, #100",
+            "   | ^^^",
+        ];
+        assert_eq!(error, expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let assembly = assemble_text(
+            "        .ORIG x3000
+        POP R2
+        MOV R1, R2
+        CLR R3
+        SUB R1, R2, R3
+        SUB R1, R1, R3
+        SUB R1, R2, #5
+        LDIMM R4, #-3
+        CALL NEAR
+NEAR    CALL FAR
+        LDIMM R5, FAR
+        .POOL
+        .BLKW 1100
+FAR     RET
+        .END",
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            assembly.program.words[..26],
+            [
+                0x6580, 0x1da1, // POP R2
+                0x12a0, // MOV R1, R2
+                0x56e0, // CLR R3
+                0x92ff, 0x1261, 0x1281, // SUB R1, R2, R3
+                0x96ff, 0x16e1, 0x1243, 0x96ff, 0x16e1, 0x1260, // SUB R1, R1, R3
+                0x12bb, // SUB R1, R2, #5
+                0x5920, 0x193d, // LDIMM R4, #-3
+                0x4800, // CALL NEAR stays a JSR
+                0x2e02, 0x41c0, // CALL FAR loads the address from the pool
+                0x2a00, // LDIMM R5, FAR shares the pool entry
+                0x3461, // .POOL
+                0, 0, 0, 0, 0,
+            ]
+        );
+        assert_eq!(assembly.symbols.address("FAR"), Some(0x3461));
+    }
+
+    #[test]
+    fn test_sub_in_place_sets_cond_from_the_result() {
+        let assembly = assemble_text(
+            "        .ORIG x3000
+        LD R1, TWO
+        LD R2, FIVE
+        SUB R1, R1, R2
+        HALT
+TWO     .FILL 2
+FIVE    .FILL 5
+        .END",
+            &Options::default(),
+        )
+        .unwrap();
+        let mut vm = VM::init();
+        assembly.program.load(&mut vm);
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        vm.run();
+
+        assert_eq!(vm.reg(Register::R1.into()), (-3_i16) as u16);
+        assert_eq!(vm.reg(Register::R2.into()), 5);
+        // negative
+        assert_eq!(vm.reg(Register::COND.into()), 0b100);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = |text: &str| assemble_text(text, &Options::default()).err().unwrap();
+        assert!(error(".ORIG x3000\nLOOP ADD R1, R1, #1\nLOOP HALT\n.END")
+            .contains("3:1: error: LOOP is already defined"));
+        assert!(error(".ORIG x3000\nADDD R1, R1, #1\n.END").contains("unknown instruction ADDD"));
+        assert!(error(".ORIG x3000\nBR NOWHERE\n.END").contains("undefined symbol NOWHERE"));
+        assert!(error("HALT\n").contains("code before .ORIG"));
+        assert!(
+            error(".ORIG x3000\nLD R1, FAR\n.BLKW 300\nFAR .FILL 0\n.END")
+                .contains("does not fit in offset9")
+        );
+        assert!(error(".ORIG x3000\nADD R1, R1\n.END").contains("ADD expects 3 operands, 2 given"));
+    }
+
+    #[test]
+    fn test_reports_every_error_and_warning() {
+        let mut sources = Sources::new();
+        let text = ".ORIG x3000
+        ADD R1, R1, #99
+        BR NOWHERE
+        LD R0, DATA
+DATA    .FILL 1
+        JSR FAR
+        .BLKW 2000
+FAR     RET";
+        let file = sources.add(Path::new("test.asm"), text.to_string());
+        let diagnostics = assemble(&mut sources, file, &Options::default())
+            .err()
+            .unwrap();
+        let found: Vec<(Severity, usize, &str)> = diagnostics
+            .iter()
+            .map(|d| (d.severity, d.span.line, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Severity::Error, 2, "99 does not fit in imm5 (-16..15)"),
+                (Severity::Error, 3, "undefined symbol NOWHERE"),
+                (Severity::Warning, 5, "execution falls through into data"),
+                (
+                    Severity::Error,
+                    6,
+                    "2000 does not fit in offset11 (-1024..1023)"
+                ),
+                (Severity::Warning, 7, "execution falls through into data"),
+                (Severity::Warning, 8, "missing .END"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_relocatable_output() {
+        let assembly = assemble_text(
+            "        .GLOBAL MAIN
+        .EXTERN PRINT_NUM
+MAIN    LD R0, VALUE
+        JSR PRINT_NUM
+        HALT
+VALUE   .FILL MAIN+1",
+            &Options { relocatable: true },
+        )
+        .unwrap();
+
+        let object = &assembly.object;
+        assert_eq!(object.sections[0].origin, None);
+        assert_eq!(
+            object.sections[0].words,
+            vec![0x2002, 0x4800, 0xf025, 0x0000]
+        );
+        let main = object.symbols.iter().find(|s| s.name == "MAIN").unwrap();
+        assert_eq!(main.binding, Binding::Export);
+        let import = object.imports().next().unwrap();
+        assert_eq!(import.name, "PRINT_NUM");
+
+        let kinds: Vec<(u16, RelocationKind, &str, i16)> = object
+            .relocations
+            .iter()
+            .map(|r| {
+                (
+                    r.offset,
+                    r.kind,
+                    object.symbols[r.symbol as usize].name.as_str(),
+                    r.addend,
+                )
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (1, RelocationKind::Offset11, "PRINT_NUM", 0),
+                (3, RelocationKind::Word, ".text", 1)
+            ]
+        );
+    }
+}
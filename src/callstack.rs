@@ -0,0 +1,169 @@
+use crate::decode_instruction::DecodedInstruction;
+use crate::display::address;
+use crate::symbols::SymbolTable;
+use crate::vm::{Opcode, Register, VM};
+
+/// Active subroutine call
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    // first instruction of the subroutine
+    pub entry: u16,
+    // address of the JSR / JSRR
+    pub call_site: u16,
+    // where RET is expected to go
+    pub return_addr: u16,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CallEvent {
+    Call(Frame),
+    Return(Frame),
+}
+
+/// Shadow call stack, pushed on JSR / JSRR and popped on RET (JMP R7)
+///
+/// Trap routines run natively and return in the same step, so a TRAP
+/// never shows up on the stack, and RTI always faults in user mode. The VM keeps one for every instruction it
+/// executes, see VM::call_stack.
+#[derive(Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Add a call made before the stack was kept, e.g. from a snapshot
+    pub fn push(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    /// Update the stack after the instruction at pc has executed
+    pub fn update(
+        &mut self,
+        vm: &VM,
+        pc: u16,
+        instruction: &DecodedInstruction,
+    ) -> Option<CallEvent> {
+        // an instruction that faulted did not run
+        if vm.error().is_some() {
+            return None;
+        }
+        match instruction.opcode {
+            Opcode::JSR => {
+                let frame = Frame {
+                    entry: vm.reg(Register::PC.into()),
+                    call_site: pc,
+                    return_addr: pc.wrapping_add(1),
+                };
+                self.frames.push(frame.clone());
+                Some(CallEvent::Call(frame))
+            }
+            Opcode::JMP if is_ret(instruction) => self.frames.pop().map(CallEvent::Return),
+            _ => None,
+        }
+    }
+}
+
+/// Call sites found by following the R5 frame links of the lc3 calling
+/// convention, R5 + 1 holds the caller's R5 and R5 + 2 the return address
+pub fn frame_links(vm: &mut VM, max_frames: usize) -> Vec<u16> {
+    let mut call_sites = vec![];
+    let mut frame_pointer = vm.reg(Register::R5.into());
+    while frame_pointer != 0 && call_sites.len() < max_frames {
+        let caller_frame = *vm.mem_mut(frame_pointer.wrapping_add(1));
+        let return_addr = *vm.mem_mut(frame_pointer.wrapping_add(2));
+        if return_addr == 0 {
+            break;
+        }
+        call_sites.push(return_addr.wrapping_sub(1));
+        // the stack grows down, a caller frame always sits higher
+        if caller_frame <= frame_pointer {
+            break;
+        }
+        frame_pointer = caller_frame;
+    }
+    call_sites
+}
+
+/// Backtrace from the R5 frame links, the innermost frame first
+pub fn frame_link_backtrace(vm: &mut VM, symbols: &SymbolTable, max_frames: usize) -> String {
+    let pc = vm.reg(Register::PC.into());
+    let mut text = format!("  #0 {}\n", address(pc, symbols));
+    for (i, call_site) in frame_links(vm, max_frames).iter().enumerate() {
+        text += &format!("  #{} {}\n", i + 1, address(*call_site, symbols));
+    }
+    text
+}
+
+/// The innermost frame first: pc, then the call site of every active
+/// subroutine with the subroutine it called
+pub fn backtrace(frames: &[Frame], pc: u16, symbols: &SymbolTable) -> String {
+    let mut text = format!("  #0 {}\n", address(pc, symbols));
+    for (i, frame) in frames.iter().rev().enumerate() {
+        text += &format!(
+            "  #{} {} calls {}\n",
+            i + 1,
+            address(frame.call_site, symbols),
+            symbols.describe(frame.entry)
+        );
+    }
+    text
+}
+
+/// RET is encoded as JMP R7
+pub fn is_ret(instruction: &DecodedInstruction) -> bool {
+    instruction.opcode == Opcode::JMP && instruction.base_r == u16::from(Register::R7)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::callstack::{frame_links, CallEvent, CallStack};
+    use crate::decode_instruction::decode_instruction;
+    use crate::vm::{Register, VM};
+
+    #[test]
+    fn test_call_and_return() {
+        let mut vm = VM::init();
+        let mut stack = CallStack::new();
+
+        // JSR at x3000 into x3010
+        *vm.reg_mut(Register::PC.into()) = 0x3010;
+        let event = stack.update(&vm, 0x3000, &decode_instruction(0x480f));
+        assert!(matches!(event, Some(CallEvent::Call(ref frame)) if frame.entry == 0x3010));
+        assert_eq!(stack.depth(), 1);
+
+        // ADD is ignored
+        assert_eq!(stack.update(&vm, 0x3010, &decode_instruction(0x1021)), None);
+
+        // RET
+        let event = stack.update(&vm, 0x3011, &decode_instruction(0xc1c0));
+        assert!(matches!(event, Some(CallEvent::Return(ref frame)) if frame.return_addr == 0x3001));
+        assert_eq!(stack.depth(), 0);
+
+        // unbalanced RET
+        assert_eq!(stack.update(&vm, 0x3011, &decode_instruction(0xc1c0)), None);
+    }
+
+    #[test]
+    fn test_frame_links() {
+        let mut vm = VM::init();
+        // innermost frame at xFD00 links to xFD10, which links to nothing
+        *vm.reg_mut(Register::R5.into()) = 0xFD00;
+        *vm.mem_mut(0xFD01) = 0xFD10;
+        *vm.mem_mut(0xFD02) = 0x3011;
+        *vm.mem_mut(0xFD12) = 0x3003;
+        assert_eq!(frame_links(&mut vm, 8), vec![0x3010, 0x3002]);
+        assert_eq!(frame_links(&mut vm, 1), vec![0x3010]);
+    }
+}
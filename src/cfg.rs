@@ -0,0 +1,346 @@
+use crate::decode_instruction::{decode_instruction, DecodedInstruction};
+use crate::display::{disassemble, trap_name};
+use crate::loader::Program;
+use crate::symbols::SymbolTable;
+use crate::vm::Opcode;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+const HALT: u16 = 0x25;
+
+/// Control flow graph of a program, recovered by following the
+/// control flow from the origin so data between the code is never decoded
+pub struct Cfg {
+    pub blocks: BTreeMap<u16, Block>,
+    pub subroutines: Vec<Subroutine>,
+}
+
+/// Straight line code, only the last instruction transfers control
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    // last instruction, inclusive
+    pub end: u16,
+    // blocks control continues at in the same subroutine,
+    // the taken branch first and the fall through last
+    pub successors: Vec<u16>,
+    // JSR, JSRR or TRAP ending the block, control comes back to the fall through
+    pub call: Option<Call>,
+    // ends in a JMP through a register other than R7, the target is unknown
+    pub indirect: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Call {
+    Subroutine(u16),
+    // JSRR through a register
+    Register(u16),
+    Trap(u16),
+}
+
+/// Code reachable from an entry without following calls
+#[derive(Debug, PartialEq)]
+pub struct Subroutine {
+    pub entry: u16,
+    // start of every block, ascending
+    pub blocks: Vec<u16>,
+}
+
+impl Cfg {
+    /// Recursive descent disassembly from the origin of the program
+    pub fn recover(program: &Program) -> Self {
+        let mut code = BTreeSet::new();
+        let mut leaders = BTreeSet::from([program.origin]);
+        let mut entries = BTreeSet::from([program.origin]);
+        let mut pending = vec![program.origin];
+        while let Some(addr) = pending.pop() {
+            if !program.contains(addr) || !code.insert(addr) {
+                continue;
+            }
+            let instruction = decode_instruction(word(program, addr));
+            let exit = exit(addr, &instruction);
+            if exit.ends_block {
+                leaders.insert(addr.wrapping_add(1));
+                leaders.extend(&exit.successors);
+            }
+            pending.extend(&exit.successors);
+            if let Some(Call::Subroutine(entry)) = exit.call {
+                if program.contains(entry) {
+                    leaders.insert(entry);
+                    entries.insert(entry);
+                    pending.push(entry);
+                }
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        for start in leaders.iter().filter(|addr| code.contains(addr)) {
+            let mut end = *start;
+            let exit = loop {
+                let exit = exit(end, &decode_instruction(word(program, end)));
+                let next = end.wrapping_add(1);
+                if exit.ends_block || leaders.contains(&next) || !code.contains(&next) {
+                    break exit;
+                }
+                end = next;
+            };
+            let successors = exit
+                .successors
+                .into_iter()
+                .filter(|addr| code.contains(addr))
+                .collect();
+            let block = Block {
+                start: *start,
+                end,
+                successors,
+                call: exit.call,
+                indirect: exit.indirect,
+            };
+            blocks.insert(*start, block);
+        }
+
+        let subroutines = entries
+            .iter()
+            .map(|entry| Subroutine {
+                entry: *entry,
+                blocks: reachable(&blocks, *entry),
+            })
+            .collect();
+        Cfg {
+            blocks,
+            subroutines,
+        }
+    }
+
+    /// Whether the word at addr is reached as an instruction
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.blocks
+            .range(..=addr)
+            .next_back()
+            .is_some_and(|(_, block)| addr <= block.end)
+    }
+
+    /// Graphviz graph with a cluster per subroutine and dashed call edges
+    pub fn to_dot(&self, program: &Program, symbols: &SymbolTable) -> String {
+        let mut dot = "digraph cfg {\n    node [shape=box fontname=\"monospace\"];\n".to_string();
+        let mut placed = BTreeSet::new();
+        for subroutine in &self.subroutines {
+            dot += &format!(
+                "    subgraph cluster_{:04X} {{\n        label=\"{}\";\n",
+                subroutine.entry,
+                escape(&symbols.describe(subroutine.entry))
+            );
+            // a block shared by several subroutines is drawn in the first
+            for start in &subroutine.blocks {
+                if placed.insert(*start) {
+                    let text = self.block_text(&self.blocks[start], program, symbols);
+                    dot += &format!("        {} [label=\"{}\"];\n", node(*start), text);
+                }
+            }
+            dot += "    }\n";
+        }
+
+        let mut traps = BTreeSet::new();
+        for block in self.blocks.values() {
+            for target in &block.successors {
+                dot += &format!("    {} -> {};\n", node(block.start), node(*target));
+            }
+            let callee = match block.call {
+                Some(Call::Subroutine(entry)) => node(entry),
+                Some(Call::Trap(vector)) => {
+                    traps.insert(vector);
+                    format!("\"trap_x{:02X}\"", vector)
+                }
+                _ => continue,
+            };
+            dot += &format!("    {} -> {} [style=dashed];\n", node(block.start), callee);
+        }
+        for vector in traps {
+            let name = trap_name(vector).map_or(format!("TRAP x{:02X}", vector), String::from);
+            dot += &format!(
+                "    \"trap_x{:02X}\" [label=\"{}\" shape=ellipse];\n",
+                vector, name
+            );
+        }
+        dot += "}\n";
+        dot
+    }
+
+    pub fn to_json(&self, symbols: &SymbolTable) -> Value {
+        let hex = |addr: u16| format!("x{:04X}", addr);
+        let blocks: Vec<Value> = self
+            .blocks
+            .values()
+            .map(|block| {
+                let call = match block.call {
+                    Some(Call::Subroutine(entry)) => json!({ "subroutine": hex(entry) }),
+                    Some(Call::Register(register)) => json!({ "register": format!("R{}", register) }),
+                    Some(Call::Trap(vector)) => json!({ "trap": format!("x{:02X}", vector) }),
+                    None => Value::Null,
+                };
+                json!({
+                    "start": hex(block.start),
+                    "end": hex(block.end),
+                    "label": symbols.label(block.start),
+                    "successors": block.successors.iter().map(|addr| hex(*addr)).collect::<Vec<_>>(),
+                    "call": call,
+                    "indirect": block.indirect,
+                })
+            })
+            .collect();
+        let subroutines: Vec<Value> = self
+            .subroutines
+            .iter()
+            .map(|subroutine| {
+                json!({
+                    "entry": hex(subroutine.entry),
+                    "name": symbols.describe(subroutine.entry),
+                    "blocks": subroutine.blocks.iter().map(|addr| hex(*addr)).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        json!({ "blocks": blocks, "subroutines": subroutines })
+    }
+
+    /// Disassembly of a block, left aligned lines for a DOT record
+    fn block_text(&self, block: &Block, program: &Program, symbols: &SymbolTable) -> String {
+        let mut text = String::new();
+        if let Some(label) = symbols.label(block.start) {
+            text += &format!("{}:\\l", escape(label));
+        }
+        let mut addr = block.start;
+        loop {
+            let line = disassemble(addr, word(program, addr), symbols);
+            text += &format!("x{:04X}  {}\\l", addr, escape(&line));
+            if addr == block.end {
+                return text;
+            }
+            addr = addr.wrapping_add(1);
+        }
+    }
+}
+
+/// Where control goes after one instruction
+struct Exit {
+    successors: Vec<u16>,
+    call: Option<Call>,
+    indirect: bool,
+    ends_block: bool,
+}
+
+fn exit(addr: u16, instruction: &DecodedInstruction) -> Exit {
+    let next = addr.wrapping_add(1);
+    let target = next.wrapping_add(instruction.offset);
+    let mut exit = Exit {
+        successors: vec![next],
+        call: None,
+        indirect: false,
+        ends_block: true,
+    };
+    match instruction.opcode {
+        // without flags BR never branches
+        Opcode::BR if instruction.nzp == 0 => exit.ends_block = false,
+        Opcode::BR if instruction.nzp == 0b111 => exit.successors = vec![target],
+        Opcode::BR => exit.successors.insert(0, target),
+        Opcode::JMP => {
+            // RET goes back to the caller, JMP through another register anywhere
+            exit.successors.clear();
+            exit.indirect = instruction.base_r != 7;
+        }
+        Opcode::JSR if instruction.flag == 1 => exit.call = Some(Call::Subroutine(target)),
+        Opcode::JSR => exit.call = Some(Call::Register(instruction.base_r)),
+        Opcode::TRAP if instruction.trap_code == HALT => exit.successors.clear(),
+        Opcode::TRAP => exit.call = Some(Call::Trap(instruction.trap_code)),
+        Opcode::RTI | Opcode::RES => exit.successors.clear(),
+        _ => exit.ends_block = false,
+    }
+    exit
+}
+
+/// Blocks reachable from entry, ascending
+fn reachable(blocks: &BTreeMap<u16, Block>, entry: u16) -> Vec<u16> {
+    let mut seen = BTreeSet::new();
+    let mut pending = vec![entry];
+    while let Some(start) = pending.pop() {
+        if let Some(block) = blocks.get(&start) {
+            if seen.insert(start) {
+                pending.extend(&block.successors);
+            }
+        }
+    }
+    seen.into_iter().collect()
+}
+
+fn word(program: &Program, addr: u16) -> u16 {
+    program.words[addr.wrapping_sub(program.origin) as usize]
+}
+
+fn node(addr: u16) -> String {
+    format!("\"x{:04X}\"", addr)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::{Block, Call, Cfg};
+    use crate::loader::Program;
+    use crate::symbols::SymbolTable;
+
+    #[test]
+    fn test_recover_blocks_and_subroutines() {
+        let program = Program {
+            origin: 0x3000,
+            words: vec![
+                0x5260, // x3000 AND R1, R1, #0
+                0x4804, // x3001 JSR x3006
+                0x1261, // x3002 LOOP ADD R1, R1, #1
+                0x0bfe, // x3003 BRnp LOOP
+                0xf025, // x3004 HALT
+                0x0048, // x3005 .FILL 'H', never reached
+                0xe1fe, // x3006 LEA R0, x3005
+                0xf022, // x3007 PUTS
+                0xc1c0, // x3008 RET
+            ],
+        };
+        let cfg = Cfg::recover(&program);
+
+        assert_eq!(
+            cfg.blocks.keys().copied().collect::<Vec<u16>>(),
+            vec![0x3000, 0x3002, 0x3004, 0x3006, 0x3008]
+        );
+        assert_eq!(
+            cfg.blocks[&0x3000],
+            Block {
+                start: 0x3000,
+                end: 0x3001,
+                successors: vec![0x3002],
+                call: Some(Call::Subroutine(0x3006)),
+                indirect: false,
+            }
+        );
+        assert_eq!(cfg.blocks[&0x3002].successors, vec![0x3002, 0x3004]);
+        assert_eq!(cfg.blocks[&0x3006].call, Some(Call::Trap(0x22)));
+        assert!(cfg.blocks[&0x3008].successors.is_empty());
+        assert!(!cfg.is_code(0x3005));
+        assert!(cfg.is_code(0x3003));
+
+        assert_eq!(cfg.subroutines.len(), 2);
+        assert_eq!(cfg.subroutines[0].blocks, vec![0x3000, 0x3002, 0x3004]);
+        assert_eq!(cfg.subroutines[1].entry, 0x3006);
+        assert_eq!(cfg.subroutines[1].blocks, vec![0x3006, 0x3008]);
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("PRINT", 0x3006);
+        let dot = cfg.to_dot(&program, &symbols);
+        assert!(dot.contains("label=\"PRINT\""));
+        assert!(dot.contains("\"x3000\" -> \"x3006\" [style=dashed];"));
+        assert!(dot.contains("\"x3002\" -> \"x3002\";"));
+        assert!(dot.contains("\"trap_x22\" [label=\"PUTS\" shape=ellipse];"));
+        let json = cfg.to_json(&symbols);
+        assert_eq!(json["subroutines"][1]["name"], "PRINT");
+        assert_eq!(json["blocks"][0]["call"]["subroutine"], "x3006");
+    }
+}
@@ -8,14 +8,252 @@ pub(crate) struct Cli {
 
 #[derive(Subcommand)]
 pub(crate) enum Commands {
-    /// Execute lc3 binary file
+    /// Execute lc3 binary files
     Execute {
-        /// Path to binary
-        path: String,
+        /// Paths to binaries, each is loaded at its own origin
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Starting PC, an address or a label, defaults to the origin of the first binary
+        #[arg(long, value_name = "ADDRESS")]
+        entry: Option<String>,
+        /// Save the VM state to this file when the program halts
+        #[arg(long, value_name = "SNAPSHOT")]
+        save_state_on_halt: Option<String>,
+        /// Write a profile report to this file when the program halts,
+        /// folded stacks for flamegraphs go to <REPORT>.folded
+        #[arg(long, value_name = "REPORT")]
+        profile: Option<String>,
+        /// Write an annotated coverage listing to this file when the program halts,
+        /// an lcov tracefile goes to <REPORT>.info
+        #[arg(long, value_name = "REPORT")]
+        coverage: Option<String>,
+        /// Log every executed instruction and the registers to this file
+        #[arg(long, value_name = "FILE")]
+        trace: Option<String>,
+        /// Symbol table for labels, defaults to the .sym files next to the binaries
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+        /// Check at every RET that R7 holds the return address, the callee saved
+        /// registers are restored and R6 is back where it was at the JSR / JSRR
+        #[arg(long)]
+        check_calls: bool,
+        /// Callee saved registers for --check-calls, defaults to R1,R2,R3,R4,R5
+        #[arg(
+            long,
+            value_name = "REGISTERS",
+            value_delimiter = ',',
+            requires = "check_calls"
+        )]
+        callee_saved: Vec<String>,
+        /// Report reads of memory words that were never written, by LD, LDR,
+        /// LDI or instruction fetch, "break" stops the program before the read
+        #[arg(long, value_name = "MODE", value_parser = ["warn", "break"])]
+        uninitialized: Option<String>,
+        /// Fill memory with random words instead of zeros before loading,
+        /// the seed defaults to the current time, --random-memory=SEED sets it
+        #[arg(
+            long,
+            value_name = "SEED",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "0"
+        )]
+        random_memory: Option<u64>,
+        /// Stop when R6 leaves the stack or a store through R6 lands outside it,
+        /// LIMIT is the lowest word and TOP one past the highest, addresses or labels,
+        /// defaults to the STACK_LIMIT and STACK_TOP labels when both exist
+        #[arg(long, value_name = "LIMIT:TOP")]
+        stack: Option<String>,
     },
     /// Disassemble lc3 binary file
     Disassemble {
         /// Path to binary
         path: String,
+        /// Symbol table for labels, defaults to the .sym file next to the binary
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+    },
+    /// List the text strings found in lc3 binary file
+    Strings {
+        /// Path to binary
+        path: String,
+        /// Symbol table for labels, defaults to the .sym file next to the binary
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+    },
+    /// Analyze lc3 binary files
+    Analyze {
+        #[command(subcommand)]
+        action: AnalyzeAction,
+    },
+    /// Convert between .obj, .hex and .bin program files
+    Convert {
+        /// Program to read, the format is detected
+        input: String,
+        /// Program to write, the format comes from the extension
+        output: String,
+    },
+    /// Assemble a source file into a program and a .sym, or a relocatable object
+    Assemble {
+        /// Path to the assembly source
+        source: String,
+        /// File to write, defaults to the source with .obj, or .lobj when relocatable
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Emit a relocatable object for the linker, allows .EXTERN and no .ORIG
+        #[arg(long)]
+        relocatable: bool,
+        /// Write a listing of every word with its source line and a symbol cross reference
+        #[arg(long, value_name = "FILE")]
+        listing: Option<String>,
     },
+    /// Align the columns of assembly sources and normalize their spelling
+    Fmt {
+        /// Assembly sources, rewritten in place
+        #[arg(required = true)]
+        sources: Vec<String>,
+        /// Only report the sources that are not formatted, exit with 1 if there are any
+        #[arg(long)]
+        check: bool,
+    },
+    /// Check assembly sources for common LC-3 mistakes
+    Lint {
+        /// Assembly sources
+        #[arg(required = true)]
+        sources: Vec<String>,
+    },
+    /// Link relocatable objects into a loadable program and a combined .sym
+    Link {
+        /// Relocatable objects and archives, only the archive members
+        /// defining otherwise undefined symbols are linked
+        #[arg(required = true)]
+        objects: Vec<String>,
+        /// Program to write, .obj unless the extension is .hex or .bin
+        #[arg(short, long, default_value = "a.obj")]
+        output: String,
+        /// Place every section with this name at an address, SECTION=ADDRESS
+        #[arg(long, value_name = "SECTION=ADDRESS")]
+        place: Vec<String>,
+        /// First address for sections without a placement, defaults to x3000
+        #[arg(long, value_name = "ADDRESS")]
+        base: Option<String>,
+    },
+    /// Bundle relocatable objects into a static library archive
+    Ar {
+        #[command(subcommand)]
+        action: ArAction,
+    },
+    /// Serve lc3 binary to a gdb frontend over the remote serial protocol
+    Gdbserver {
+        /// Paths to binaries, each is loaded at its own origin
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Starting PC, an address or a label, defaults to the origin of the first binary
+        #[arg(long, value_name = "ADDRESS")]
+        entry: Option<String>,
+        /// Address to listen on, host:port or unix:<socket path>
+        #[arg(long, default_value = "127.0.0.1:1234")]
+        listen: String,
+    },
+    /// Debug Adapter Protocol server over stdio, for editor integration
+    Dap,
+    /// Language Server Protocol server over stdio, for editing .asm files
+    Lsp,
+    /// Terminal debugger with disassembly, register, memory and console panes
+    Tui {
+        /// Paths to binaries, each is loaded at its own origin
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Starting PC, an address or a label, defaults to the origin of the first binary
+        #[arg(long, value_name = "ADDRESS")]
+        entry: Option<String>,
+        /// Symbol table for labels, defaults to the .sym files next to the binaries
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+    },
+    /// Resume execution from a VM snapshot
+    Resume {
+        /// Path to snapshot
+        snapshot: String,
+        /// Save the VM state to this file when the program halts
+        #[arg(long, value_name = "SNAPSHOT")]
+        save_state_on_halt: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub(crate) enum AnalyzeAction {
+    /// Recover the control flow graph by following the code from the origin,
+    /// with a cluster per subroutine
+    Cfg {
+        /// Path to binary
+        path: String,
+        /// Symbol table for labels, defaults to the .sym file next to the binary
+        #[arg(long, value_name = "FILE")]
+        symbols: Option<String>,
+        /// Write JSON instead of Graphviz DOT
+        #[arg(long)]
+        json: bool,
+        /// File to write, defaults to stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub(crate) enum ArAction {
+    /// Add objects to an archive, creating it if needed and replacing members with the same name
+    Add {
+        /// Path to archive
+        archive: String,
+        /// Relocatable objects
+        #[arg(required = true)]
+        objects: Vec<String>,
+    },
+    /// List the members of an archive and the symbols they export
+    List {
+        /// Path to archive
+        archive: String,
+    },
+    /// Write archive members to the current directory, every member if none are named
+    Extract {
+        /// Path to archive
+        archive: String,
+        /// Member names
+        members: Vec<String>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{Cli, Commands};
+    use clap::Parser;
+
+    fn random_memory(args: &[&str]) -> (Option<u64>, Vec<String>) {
+        let cli = Cli::try_parse_from(args).unwrap();
+        match cli.command {
+            Commands::Execute {
+                random_memory,
+                paths,
+                ..
+            } => (random_memory, paths),
+            _ => panic!("not execute"),
+        }
+    }
+
+    #[test]
+    fn test_random_memory_seed_needs_equals() {
+        assert_eq!(
+            random_memory(&["lc3", "execute", "--random-memory", "prog.obj"]),
+            (Some(0), vec!["prog.obj".to_string()])
+        );
+        assert_eq!(
+            random_memory(&["lc3", "execute", "--random-memory=42", "prog.obj"]),
+            (Some(42), vec!["prog.obj".to_string()])
+        );
+        assert_eq!(
+            random_memory(&["lc3", "execute", "prog.obj"]),
+            (None, vec!["prog.obj".to_string()])
+        );
+    }
 }
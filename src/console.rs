@@ -0,0 +1,74 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+/// Terminal the lc3 program talks to through the keyboard
+/// device registers and the io traps
+pub trait Console {
+    /// Next key press, None when no input is available yet
+    fn read_key(&mut self) -> Option<u8>;
+
+    /// Write program output
+    fn write(&mut self, text: &str);
+}
+
+/// Console backed by the process stdin and stdout
+/// reading blocks until a key is pressed
+pub struct StdConsole;
+
+impl Console for StdConsole {
+    fn read_key(&mut self) -> Option<u8> {
+        let mut buffer = [0; 1];
+        std::io::stdin().read_exact(&mut buffer).ok()?;
+        Some(buffer[0])
+    }
+
+    fn write(&mut self, text: &str) {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(text.as_bytes()).unwrap();
+        stdout.flush().unwrap();
+    }
+}
+
+#[derive(Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    output: String,
+}
+
+/// In memory console, clones share the same buffers so a frontend
+/// (debugger, tui) can feed input and collect output while the VM owns a copy
+#[derive(Clone, Default)]
+pub struct BufferConsole {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+impl BufferConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_input(&self, text: &str) {
+        self.buffers.borrow_mut().input.extend(text.bytes());
+    }
+
+    pub fn has_input(&self) -> bool {
+        !self.buffers.borrow().input.is_empty()
+    }
+
+    /// Output written since the last call
+    pub fn take_output(&self) -> String {
+        std::mem::take(&mut self.buffers.borrow_mut().output)
+    }
+}
+
+impl Console for BufferConsole {
+    fn read_key(&mut self) -> Option<u8> {
+        self.buffers.borrow_mut().input.pop_front()
+    }
+
+    fn write(&mut self, text: &str) {
+        self.buffers.borrow_mut().output.push_str(text);
+    }
+}
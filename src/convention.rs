@@ -0,0 +1,167 @@
+use crate::callstack::{is_ret, Frame};
+use crate::decode_instruction::DecodedInstruction;
+use crate::display::address;
+use crate::symbols::SymbolTable;
+use crate::vm::{report, Register, Tracer, VM};
+use std::io::Write;
+
+// registers a subroutine has to restore before RET unless told otherwise
+pub const DEFAULT_CALLEE_SAVED: [u16; 5] = [1, 2, 3, 4, 5];
+
+/// Checks every RET of a subroutine called by JSR / JSRR: R7 has to hold
+/// the return address, the callee saved registers their value at the call
+/// and R6 has to be back where it was
+pub struct ConventionChecker<'a, W: Write> {
+    output: W,
+    symbols: &'a SymbolTable,
+    callee_saved: Vec<u16>,
+    // R0-R7 right after each call on the call stack of the VM
+    saved: Vec<[u16; 8]>,
+    violations: usize,
+}
+
+impl<'a, W: Write> ConventionChecker<'a, W> {
+    pub fn new(output: W, symbols: &'a SymbolTable, callee_saved: &[u16]) -> Self {
+        Self {
+            output,
+            symbols,
+            callee_saved: callee_saved.to_vec(),
+            saved: vec![],
+            violations: 0,
+        }
+    }
+
+    pub fn violations(&self) -> usize {
+        self.violations
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    fn check_return(&mut self, vm: &VM, pc: u16) {
+        let (Some(frame), Some(saved)) = (vm.call_stack().frames().last(), self.saved.last())
+        else {
+            return;
+        };
+        let mut problems = vec![];
+        let r7 = vm.reg(Register::R7.into());
+        if r7 != frame.return_addr {
+            problems.push(format!(
+                "R7 is x{:04X}, not the return address x{:04X}",
+                r7, frame.return_addr
+            ));
+        }
+        for reg in &self.callee_saved {
+            let value = vm.reg(*reg);
+            if value != saved[*reg as usize] {
+                problems.push(format!(
+                    "R{} is x{:04X}, it was x{:04X} at the call",
+                    reg, value, saved[*reg as usize]
+                ));
+            }
+        }
+        let r6 = vm.reg(Register::R6.into());
+        let before = saved[Register::R6 as usize];
+        if r6 != before {
+            problems.push(format!(
+                "R6 is x{:04X}, it was x{:04X} at the call, the stack is unbalanced",
+                r6, before
+            ));
+        }
+
+        let frame = frame.clone();
+        for problem in problems {
+            self.report(pc, &frame, &problem);
+        }
+    }
+
+    fn report(&mut self, pc: u16, frame: &Frame, problem: &str) {
+        self.violations += 1;
+        report(
+            &mut self.output,
+            format_args!(
+                "calling convention: RET at {} from {} called at {}: {}\n",
+                address(pc, self.symbols),
+                self.symbols.describe(frame.entry),
+                address(frame.call_site, self.symbols),
+                problem
+            ),
+        );
+    }
+}
+
+impl<W: Write> Tracer for ConventionChecker<'_, W> {
+    fn before_step(&mut self, vm: &mut VM, pc: u16, instruction: &DecodedInstruction) {
+        if is_ret(instruction) {
+            self.check_return(vm, pc);
+        }
+    }
+
+    fn after_step(&mut self, vm: &mut VM, _pc: u16, _instruction: &DecodedInstruction) {
+        // a call pushes one frame, a return pops one
+        let depth = vm.call_stack().depth();
+        if depth > self.saved.len() {
+            let mut registers = [0; 8];
+            for (reg, value) in registers.iter_mut().enumerate() {
+                *value = vm.reg(reg as u16);
+            }
+            self.saved.push(registers);
+        }
+        self.saved.truncate(depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::convention::{ConventionChecker, DEFAULT_CALLEE_SAVED};
+    use crate::symbols::SymbolTable;
+    use crate::vm::{Register, VM};
+
+    #[test]
+    fn test_checks_every_return() {
+        let mut vm = VM::init();
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        *vm.reg_mut(Register::R6.into()) = 0xFE00;
+        let program = [
+            0x4803, // x3000 JSR GOOD
+            0x4806, // x3001 JSR BAD
+            0xf025, // x3002 HALT
+            0xf025, // x3003 HALT, where BAD returns to
+            0x3202, // x3004 GOOD ST R1, SAVE
+            0x2201, // x3005 LD R1, SAVE
+            0xc1c0, // x3006 RET
+            0x0000, // x3007 SAVE
+            0x1261, // x3008 BAD ADD R1, R1, #1
+            0x1dbf, // x3009 ADD R6, R6, #-1
+            0x1fe1, // x300A ADD R7, R7, #1
+            0xc1c0, // x300B RET
+        ];
+        for (i, word) in program.iter().enumerate() {
+            *vm.mem_mut(0x3000 + i as u16) = *word;
+        }
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("MAIN", 0x3000);
+        symbols.insert("GOOD", 0x3004);
+        symbols.insert("BAD", 0x3008);
+        let mut checker = ConventionChecker::new(vec![], &symbols, &DEFAULT_CALLEE_SAVED);
+        vm.run_traced(&mut [&mut checker]);
+
+        assert_eq!(checker.violations(), 3);
+        let output = String::from_utf8(checker.into_inner()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        let prefix = "calling convention: RET at x300B (BAD+3) from BAD called at x3001 (MAIN+1): ";
+        assert_eq!(
+            lines,
+            vec![
+                format!("{}R7 is x3003, not the return address x3002", prefix),
+                format!("{}R1 is x0001, it was x0000 at the call", prefix),
+                format!(
+                    "{}R6 is xFDFF, it was xFE00 at the call, the stack is unbalanced",
+                    prefix
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use crate::debuginfo::DebugInfo;
+use crate::decode_instruction::{decode_instruction, DecodedInstruction};
+use crate::display::source_or_disassembly;
+use crate::loader::Program;
+use crate::symbols::SymbolTable;
+use crate::vm::{Opcode, Register, Tracer, MEMORY_SIZE, VM};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Records which words were executed as instructions and,
+/// for every BR, how often it was taken and not taken
+pub struct Coverage {
+    hits: Vec<u64>,
+    // address -> (taken, not taken)
+    branches: BTreeMap<u16, (u64, u64)>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            hits: vec![0; MEMORY_SIZE],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    pub fn hits(&self, addr: u16) -> u64 {
+        self.hits[addr as usize]
+    }
+
+    pub fn branch(&self, addr: u16) -> Option<(u64, u64)> {
+        self.branches.get(&addr).copied()
+    }
+
+    /// Every word of the program with its execution count,
+    /// words never executed are marked with #####
+    pub fn listing(&self, program: &Program, symbols: &SymbolTable, debug: &DebugInfo) -> String {
+        let mut listing = String::new();
+        let mut executed = 0;
+        let mut branches_total = 0;
+        let mut branches_covered = 0;
+
+        for (addr, word) in program.addresses().zip(program.words.iter()) {
+            let hits = self.hits(addr);
+            let count = if hits == 0 {
+                "#####".to_string()
+            } else {
+                executed += 1;
+                hits.to_string()
+            };
+            let instruction = decode_instruction(*word);
+            listing += &format!(
+                "{:>10}  x{:04X}  {:04X}  {:<12} {}",
+                count,
+                addr,
+                word,
+                symbols.label(addr).unwrap_or_default(),
+                source_or_disassembly(addr, *word, symbols, debug)
+            );
+
+            if is_conditional_branch(&instruction) && hits > 0 {
+                let (taken, not_taken) = self.branch(addr).unwrap_or_default();
+                branches_total += 2;
+                branches_covered += (taken > 0) as usize + (not_taken > 0) as usize;
+                listing += &format!("  [taken {}, not taken {}]", taken, not_taken);
+            }
+            listing += "\n";
+        }
+
+        listing += &format!(
+            "\nexecuted {} of {} words ({:.1}%)\n",
+            executed,
+            program.words.len(),
+            percent(executed, program.words.len())
+        );
+        listing += &format!(
+            "branch directions covered {} of {} ({:.1}%)\n",
+            branches_covered,
+            branches_total,
+            percent(branches_covered, branches_total)
+        );
+        listing
+    }
+
+    /// lcov tracefile with one record per assembly source file of the
+    /// program, empty if the debug info covers none of its instructions
+    ///
+    /// A line is counted by its first word, data words are left out.
+    pub fn lcov_for_source(&self, program: &Program, debug: &DebugInfo) -> String {
+        // file -> line -> first address
+        let mut files: BTreeMap<usize, BTreeMap<u32, u16>> = BTreeMap::new();
+        for addr in program.addresses() {
+            if let Some(location) = debug.location(addr).filter(|location| !location.data) {
+                files
+                    .entry(location.file)
+                    .or_default()
+                    .entry(location.line as u32)
+                    .or_insert(addr);
+            }
+        }
+        let mut lcov = String::new();
+        for (file, lines) in files {
+            let source = debug.path(file).unwrap_or(Path::new(""));
+            lcov += &self.lcov_for_lines(&source.to_string_lossy(), lines.into_iter(), program);
+        }
+        lcov
+    }
+
+    /// lcov tracefile, line n of `source` is the nth word of the program
+    pub fn lcov(&self, program: &Program, source: &str) -> String {
+        let lines = program
+            .addresses()
+            .enumerate()
+            .map(|(i, addr)| (i as u32 + 1, addr));
+        self.lcov_for_lines(source, lines, program)
+    }
+
+    /// lcov tracefile for (line, address) pairs
+    pub fn lcov_for_lines(
+        &self,
+        source: &str,
+        lines: impl Iterator<Item = (u32, u16)>,
+        program: &Program,
+    ) -> String {
+        let mut lcov = format!("TN:\nSF:{}\n", source);
+        let mut lines_found = 0;
+        let mut lines_hit = 0;
+        let mut branches_found = 0;
+        let mut branches_hit = 0;
+
+        for (line, addr) in lines {
+            let hits = self.hits(addr);
+            lines_found += 1;
+            lines_hit += (hits > 0) as usize;
+            lcov += &format!("DA:{},{}\n", line, hits);
+
+            if !program.contains(addr) {
+                continue;
+            }
+            let word = program.words[addr.wrapping_sub(program.origin) as usize];
+            if !is_conditional_branch(&decode_instruction(word)) {
+                continue;
+            }
+            branches_found += 2;
+            let (taken, not_taken) = self.branch(addr).unwrap_or_default();
+            for (id, count) in [(0, taken), (1, not_taken)] {
+                let count = if hits == 0 {
+                    "-".to_string()
+                } else {
+                    branches_hit += (count > 0) as usize;
+                    count.to_string()
+                };
+                lcov += &format!("BRDA:{},0,{},{}\n", line, id, count);
+            }
+        }
+
+        lcov += &format!("BRF:{}\nBRH:{}\n", branches_found, branches_hit);
+        lcov += &format!("LF:{}\nLH:{}\nend_of_record\n", lines_found, lines_hit);
+        lcov
+    }
+}
+
+/// BR with some but not all of n, z, p set can go either way
+fn is_conditional_branch(instruction: &DecodedInstruction) -> bool {
+    instruction.opcode == Opcode::BR && instruction.nzp != 0 && instruction.nzp != 0b111
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+impl Tracer for Coverage {
+    fn before_step(&mut self, vm: &mut VM, pc: u16, instruction: &DecodedInstruction) {
+        self.hits[pc as usize] += 1;
+        if instruction.opcode == Opcode::BR {
+            let taken = instruction.nzp & vm.reg(Register::COND.into()) != 0;
+            let counts = self.branches.entry(pc).or_default();
+            if taken {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble, Options};
+    use crate::coverage::Coverage;
+    use crate::debuginfo::DebugInfo;
+    use crate::loader::Program;
+    use crate::source::Sources;
+    use crate::symbols::SymbolTable;
+    use crate::vm::VM;
+
+    fn program() -> Program {
+        Program {
+            origin: 0x3000,
+            words: vec![
+                0x5020, // x3000 AND R0, R0, #0
+                0x1022, // x3001 ADD R0, R0, #2
+                0x103f, // x3002 LOOP ADD R0, R0, #-1
+                0x03fe, // x3003 BRp LOOP
+                0x0401, // x3004 BRz DONE
+                0xf021, // x3005 OUT (never reached)
+                0xf025, // x3006 DONE HALT
+            ],
+        }
+    }
+
+    #[test]
+    fn test_coverage_counts() {
+        let program = program();
+        let mut vm = VM::init();
+        program.load(&mut vm);
+        let mut coverage = Coverage::new();
+        vm.run_traced(&mut [&mut coverage]);
+
+        assert_eq!(coverage.hits(0x3002), 2);
+        assert_eq!(coverage.hits(0x3005), 0);
+        assert_eq!(coverage.branch(0x3003), Some((1, 1)));
+        assert_eq!(coverage.branch(0x3004), Some((1, 0)));
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP", 0x3002);
+        let listing = coverage.listing(&program, &symbols, &DebugInfo::new());
+        assert!(listing.contains("#####  x3005"));
+        assert!(listing.contains("LOOP         ADD R0 R0 #-1"));
+        assert!(listing.contains("BRp LOOP  [taken 1, not taken 1]"));
+        assert!(listing.contains("executed 6 of 7 words"));
+        assert!(listing.contains("branch directions covered 3 of 4"));
+    }
+
+    #[test]
+    fn test_lcov_report() {
+        let program = program();
+        let mut vm = VM::init();
+        program.load(&mut vm);
+        let mut coverage = Coverage::new();
+        vm.run_traced(&mut [&mut coverage]);
+
+        let lcov = coverage.lcov(&program, "loop.obj");
+        assert!(lcov.starts_with("TN:\nSF:loop.obj\n"));
+        assert!(lcov.contains("DA:3,2\n"));
+        assert!(lcov.contains("DA:6,0\n"));
+        assert!(lcov.contains("BRDA:5,0,0,1\nBRDA:5,0,1,0\n"));
+        assert!(lcov.contains("BRF:4\nBRH:3\nLF:7\nLH:6\nend_of_record\n"));
+        assert_eq!(coverage.lcov_for_source(&program, &DebugInfo::new()), "");
+    }
+
+    #[test]
+    fn test_lcov_maps_to_source_lines() {
+        let text = "        .ORIG x3000
+        AND R0, R0, #0
+        ADD R0, R0, #2
+
+LOOP    ADD R0, R0, #-1
+        BRp LOOP
+        BRz DONE
+        OUT
+DONE    HALT
+MSG     .STRINGZ \"hi\"
+        .END";
+        let dir = std::env::temp_dir();
+        let source = dir.join("coverage_test.asm");
+        std::fs::write(&source, text).unwrap();
+        let mut sources = Sources::new();
+        let file = sources.load(&source).unwrap();
+        let assembly = assemble(&mut sources, file, &Options::default())
+            .unwrap_or_else(|diagnostics| panic!("{:?}", diagnostics));
+        let dbg = dir.join("coverage_test.dbg");
+        let info = DebugInfo::from_assembly(&assembly, &sources);
+        std::fs::write(&dbg, info.to_text()).unwrap();
+        let debug = DebugInfo::load(&dbg.to_string_lossy()).unwrap();
+
+        let program = assembly.program;
+        let mut vm = VM::init();
+        program.load(&mut vm);
+        let mut coverage = Coverage::new();
+        vm.run_traced(&mut [&mut coverage]);
+
+        let lcov = coverage.lcov_for_source(&program, &debug);
+        let path = source.canonicalize().unwrap();
+        assert!(lcov.starts_with(&format!("TN:\nSF:{}\n", path.display())));
+        assert!(lcov.contains("DA:2,1\nDA:3,1\nDA:5,2\n"));
+        assert!(lcov.contains("BRDA:6,0,0,1\nBRDA:6,0,1,1\n"));
+        assert!(lcov.contains("DA:8,0\nDA:9,1\n"));
+        // the string is data, not a line to cover
+        assert!(!lcov.contains("DA:10,"));
+        assert!(lcov.contains("LF:7\nLH:6\nend_of_record\n"));
+    }
+}
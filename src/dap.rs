@@ -0,0 +1,1077 @@
+use crate::callstack::frame_links;
+use crate::console::BufferConsole;
+use crate::debuginfo::DebugInfo;
+use crate::decode_instruction::decode_instruction;
+use crate::display::cond_flags;
+use crate::display::disassemble;
+use crate::loader::{read_program, Program};
+use crate::symbols::{parse_number, SymbolTable};
+use crate::vm::{Opcode, Register, REGISTER_COUNT, VM};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::{BufRead, Write};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+// Debug Adapter Protocol server
+// see: https://microsoft.github.io/debug-adapter-protocol/specification
+//
+// The loaded image is exposed as a virtual source (sourceReference 1)
+// with one line per word, so line n is the word at origin + n - 1.
+// Programs with debug info from the assembler use their real source files.
+// Text typed in the debug console is queued as keyboard input.
+
+const THREAD_ID: i64 = 1;
+const PROGRAM_SOURCE: i64 = 1;
+
+// variablesReference values
+const REGISTERS_REF: i64 = 1;
+const STACK_REF: i64 = 2;
+const MEMORY_REF: i64 = 3;
+// memory pages are MEMORY_PAGE_REF + page number
+const MEMORY_PAGE_REF: i64 = 0x1000;
+const PAGE_SIZE: u16 = 0x100;
+const PAGE_COUNT: i64 = 0x100;
+
+// words shown in the stack scope starting at R6
+const STACK_WINDOW: u16 = 16;
+// stop walking R5 frame links after this many frames
+const MAX_FRAMES: usize = 64;
+// instructions executed between checks for new requests
+const SLICE_SIZE: usize = 10_000;
+
+const REGISTER_NAMES: [&str; REGISTER_COUNT] =
+    ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7", "PC", "COND"];
+
+/// Where a running step request should stop
+enum StepTarget {
+    // continue until a breakpoint, halt or pause
+    Continue,
+    // step over a subroutine call, stop once PC reaches the address
+    Address(u16),
+    // step out, stop after the RET that leaves the current subroutine
+    Return { depth: usize },
+}
+
+pub struct DapServer<W: Write> {
+    output: W,
+    seq: i64,
+    vm: Box<VM>,
+    console: BufferConsole,
+    program: Option<Program>,
+    program_name: String,
+    symbols: SymbolTable,
+    debug: DebugInfo,
+    breakpoints: HashSet<u16>,
+    // source path -> addresses of its breakpoints, replaced on every setBreakpoints
+    source_breakpoints: HashMap<String, Vec<u16>>,
+    stop_on_entry: bool,
+    // Some while the program is executing
+    step_target: Option<StepTarget>,
+    // ignore a breakpoint at PC when resuming from it
+    skip_breakpoint: bool,
+    // waiting for input message has been shown
+    input_notice: bool,
+    // follow the R5 frame links when the call stack is empty
+    frame_links: bool,
+    // the fault was reported as an exception, resuming ends the session
+    fault_reported: bool,
+}
+
+/// Serve a single debug session over stdin and stdout
+pub fn serve() -> io::Result<()> {
+    let messages = spawn_reader(io::BufReader::new(io::stdin()));
+    DapServer::new(io::stdout()).serve(messages)
+}
+
+/// Read messages on a separate thread so the program
+/// can keep executing while we wait for requests
+fn spawn_reader<R: BufRead + Send + 'static>(input: R) -> Receiver<Value> {
+    let (sender, receiver) = channel();
+    let mut input = input;
+    std::thread::spawn(move || {
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Read a single Content-Length framed message
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(length) = line.strip_prefix("Content-Length:") {
+            content_length = length.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = content_length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(output: W) -> Self {
+        let console = BufferConsole::new();
+        let mut vm = Box::new(VM::init());
+        vm.set_console(Box::new(console.clone()));
+        Self {
+            output,
+            seq: 1,
+            vm,
+            console,
+            program: None,
+            program_name: String::new(),
+            symbols: SymbolTable::new(),
+            debug: DebugInfo::new(),
+            breakpoints: HashSet::new(),
+            source_breakpoints: HashMap::new(),
+            stop_on_entry: false,
+            step_target: None,
+            skip_breakpoint: false,
+            input_notice: false,
+            frame_links: false,
+            fault_reported: false,
+        }
+    }
+
+    pub fn serve(&mut self, messages: Receiver<Value>) -> io::Result<()> {
+        loop {
+            let message = if self.is_executing() {
+                match messages.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match messages.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => break,
+                }
+            };
+
+            if let Some(message) = message {
+                if !self.handle_message(&message)? {
+                    break;
+                }
+            }
+            if self.is_executing() {
+                self.execute(SLICE_SIZE)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Program is running and not blocked on keyboard input
+    fn is_executing(&self) -> bool {
+        self.step_target.is_some() && (!self.vm.is_awaiting_input() || self.console.has_input())
+    }
+
+    /// Returns false once the session is over
+    pub fn handle_message(&mut self, message: &Value) -> io::Result<bool> {
+        if message["type"] != "request" {
+            return Ok(true);
+        }
+        let command = message["command"].as_str().unwrap_or_default();
+        let arguments = &message["arguments"];
+
+        let result = match command {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => Ok(self.set_breakpoints(arguments)),
+            "setFunctionBreakpoints" => Ok(self.set_function_breakpoints(arguments)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(arguments)),
+            "configurationDone" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "lc3" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(scopes()),
+            "variables" => Ok(self.variables(arguments)),
+            "setVariable" => self.set_variable(arguments),
+            "source" => self.source(),
+            "evaluate" => self.evaluate(arguments),
+            "continue" => {
+                self.resume(StepTarget::Continue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => Ok(self.next()),
+            "stepIn" => Ok(json!({})),
+            "stepOut" => {
+                self.resume(StepTarget::Return { depth: 0 });
+                Ok(json!({}))
+            }
+            "pause" => Ok(json!({})),
+            "disconnect" | "terminate" => {
+                self.respond(message, Ok(json!({})))?;
+                return Ok(false);
+            }
+            _ => Err(format!("unsupported request {}", command)),
+        };
+        let success = result.is_ok();
+        self.respond(message, result)?;
+
+        // events that must follow the response
+        match command {
+            "launch" if success => self.send_event("initialized", json!({}))?,
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.send_stopped("entry")?;
+                } else {
+                    self.step_target = Some(StepTarget::Continue);
+                }
+            }
+            "stepIn" => {
+                self.step()?;
+                if self.vm.is_running() {
+                    self.send_stopped("step")?;
+                }
+            }
+            "next" if self.step_target.is_none() && self.vm.is_running() => {
+                self.send_stopped("step")?;
+            }
+            "pause" => {
+                self.step_target = None;
+                self.send_stopped("pause")?;
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["program"]
+            .as_str()
+            .ok_or("launch requires a program")?;
+        let program = read_program(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        program.load(&mut self.vm);
+        self.vm.set_running(true);
+
+        self.symbols = match arguments["symbolFile"].as_str() {
+            Some(symbol_file) => SymbolTable::load(symbol_file)
+                .map_err(|e| format!("failed to read {}: {}", symbol_file, e))?,
+            None => SymbolTable::for_program(path),
+        };
+        self.debug = match arguments["debugFile"].as_str() {
+            Some(debug_file) => DebugInfo::load(debug_file)
+                .map_err(|e| format!("failed to read {}: {}", debug_file, e))?,
+            None => DebugInfo::for_program(path),
+        };
+
+        self.program_name = std::path::Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.program = Some(program);
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.frame_links = arguments["frameLinks"].as_bool().unwrap_or(false);
+        self.fault_reported = false;
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        let origin = self.program.as_ref().map(|p| p.origin).unwrap_or(0);
+        let path = arguments["source"]["path"].as_str();
+        let lines: Vec<i64> = arguments["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|breakpoint| breakpoint["line"].as_i64())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if let Some(path) = path.filter(|_| !self.debug.is_empty()) {
+            return self.set_source_breakpoints(path, &lines);
+        }
+
+        // source breakpoints replace all breakpoints of the program source
+        if let Some(program) = &self.program {
+            self.breakpoints.retain(|addr| !program.contains(*addr));
+        }
+        let breakpoints: Vec<Value> = lines
+            .into_iter()
+            .map(|line| {
+                let addr = origin.wrapping_add((line - 1) as u16);
+                let verified = line > 0 && self.program.as_ref().is_some_and(|p| p.contains(addr));
+                if verified {
+                    self.breakpoints.insert(addr);
+                }
+                json!({ "verified": verified, "line": line })
+            })
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// Breakpoints in an assembly source file, a line without code moves
+    /// the breakpoint to the next line that has some
+    fn set_source_breakpoints(&mut self, path: &str, lines: &[i64]) -> Value {
+        for addr in self.source_breakpoints.remove(path).unwrap_or_default() {
+            self.breakpoints.remove(&addr);
+        }
+        let mut addresses = vec![];
+        let breakpoints: Vec<Value> = lines
+            .iter()
+            .map(|line| match self.debug.address_of(path, *line as usize) {
+                Some((addr, actual)) if *line > 0 => {
+                    self.breakpoints.insert(addr);
+                    addresses.push(addr);
+                    json!({ "verified": true, "line": actual })
+                }
+                _ => json!({ "verified": false, "line": line }),
+            })
+            .collect();
+        self.source_breakpoints.insert(path.to_string(), addresses);
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_function_breakpoints(&mut self, arguments: &Value) -> Value {
+        let names: Vec<String> = arguments["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|breakpoint| breakpoint["name"].as_str())
+                    .map(|name| name.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let breakpoints: Vec<Value> = names
+            .iter()
+            .map(|name| match self.symbols.resolve(name) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr);
+                    json!({ "verified": true, "instructionReference": format!("0x{:04x}", addr) })
+                }
+                None => json!({ "verified": false, "message": format!("unknown label {}", name) }),
+            })
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Value {
+        let breakpoints: Vec<Value> = arguments["breakpoints"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .iter()
+            .map(|breakpoint| {
+                let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+                match breakpoint["instructionReference"]
+                    .as_str()
+                    .and_then(parse_number)
+                {
+                    Some(addr) => {
+                        let addr = addr.wrapping_add(offset as u16);
+                        self.breakpoints.insert(addr);
+                        json!({ "verified": true, "instructionReference": format!("0x{:04x}", addr) })
+                    }
+                    None => json!({ "verified": false }),
+                }
+            })
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// Frames are the call sites of the shadow call stack, or with
+    /// `frameLinks` and no calls on it those found by following the R5
+    /// frame links: R5 + 1 holds the caller's R5, R5 + 2 the return address
+    fn stack_trace(&mut self) -> Value {
+        let mut call_sites: Vec<u16> = self
+            .vm
+            .call_stack()
+            .frames()
+            .iter()
+            .rev()
+            .take(MAX_FRAMES - 1)
+            .map(|frame| frame.call_site)
+            .collect();
+        if call_sites.is_empty() && self.frame_links {
+            call_sites = frame_links(&mut self.vm, MAX_FRAMES - 1);
+        }
+        let mut frames = vec![self.frame(0, self.vm.reg(Register::PC.into()))];
+        for call_site in call_sites {
+            frames.push(self.frame(frames.len(), call_site));
+        }
+
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn frame(&self, id: usize, addr: u16) -> Value {
+        let name = match self.symbols.containing(addr) {
+            Some(_) => self.symbols.describe(addr),
+            None => format!("x{:04X}", addr),
+        };
+        let source = self
+            .debug
+            .location(addr)
+            .and_then(|location| Some((location, self.debug.path(location.file)?)));
+        if let Some((location, path)) = source {
+            let source_name = path.file_name().unwrap_or_default().to_string_lossy();
+            return json!({
+                "id": id,
+                "name": name,
+                "source": { "name": source_name, "path": path.to_string_lossy() },
+                "line": location.line,
+                "column": 0,
+                "instructionPointerReference": format!("0x{:04x}", addr),
+            });
+        }
+        let line = match &self.program {
+            Some(program) if program.contains(addr) => addr.wrapping_sub(program.origin) as i64 + 1,
+            _ => 0,
+        };
+        json!({
+            "id": id,
+            "name": name,
+            "source": { "name": self.program_name, "sourceReference": PROGRAM_SOURCE },
+            "line": line,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:04x}", addr),
+        })
+    }
+
+    fn variables(&mut self, arguments: &Value) -> Value {
+        let reference = arguments["variablesReference"].as_i64().unwrap_or(0);
+        let variables: Vec<Value> = match reference {
+            REGISTERS_REF => (0..REGISTER_COUNT)
+                .map(|i| {
+                    let value = self.vm.reg(i as u16);
+                    let rendered = if i == Register::COND as usize {
+                        cond_flags(value)
+                    } else {
+                        format!("x{:04X} ({})", value, value as i16)
+                    };
+                    variable(REGISTER_NAMES[i], rendered, 0)
+                })
+                .collect(),
+            STACK_REF => {
+                let stack_pointer = self.vm.reg(Register::R6.into());
+                (0..STACK_WINDOW)
+                    .map(|i| stack_pointer.wrapping_add(i))
+                    .map(|addr| self.memory_variable(addr))
+                    .collect()
+            }
+            MEMORY_REF => (0..PAGE_COUNT as u16)
+                .map(|page| {
+                    let start = page * PAGE_SIZE;
+                    let name = format!("x{:04X}-x{:04X}", start, start + (PAGE_SIZE - 1));
+                    variable(&name, String::new(), MEMORY_PAGE_REF + page as i64)
+                })
+                .collect(),
+            reference if (MEMORY_PAGE_REF..MEMORY_PAGE_REF + PAGE_COUNT).contains(&reference) => {
+                let start = (reference - MEMORY_PAGE_REF) as u16 * PAGE_SIZE;
+                (0..PAGE_SIZE)
+                    .map(|i| self.memory_variable(start + i))
+                    .collect()
+            }
+            _ => vec![],
+        };
+        json!({ "variables": variables })
+    }
+
+    fn memory_variable(&mut self, addr: u16) -> Value {
+        let value = *self.vm.mem_mut(addr);
+        let mut name = format!("x{:04X}", addr);
+        if let Some(label) = self.symbols.label(addr) {
+            name = format!("{} {}", name, label);
+        }
+        variable(&name, format!("x{:04X} ({})", value, value as i16), 0)
+    }
+
+    fn set_variable(&mut self, arguments: &Value) -> Result<Value, String> {
+        let name = arguments["name"].as_str().unwrap_or_default();
+        let value = arguments["value"]
+            .as_str()
+            .and_then(|value| self.symbols.resolve(value))
+            .ok_or("invalid value")?;
+
+        match arguments["variablesReference"].as_i64().unwrap_or(0) {
+            REGISTERS_REF => {
+                let register = REGISTER_NAMES
+                    .iter()
+                    .position(|register| *register == name)
+                    .ok_or("unknown register")?;
+                *self.vm.reg_mut(register as u16) = value;
+            }
+            _ => {
+                // memory variables are named "xADDR [LABEL]"
+                let addr = name
+                    .split_whitespace()
+                    .next()
+                    .and_then(parse_number)
+                    .ok_or("unknown variable")?;
+                *self.vm.mem_mut(addr) = value;
+            }
+        }
+        Ok(json!({ "value": format!("x{:04X} ({})", value, value as i16) }))
+    }
+
+    /// Disassembly of the loaded image, one line per word
+    fn source(&mut self) -> Result<Value, String> {
+        let program = self.program.as_ref().ok_or("no program loaded")?;
+        let content: Vec<String> = program
+            .addresses()
+            .zip(program.words.iter())
+            .map(|(addr, word)| {
+                let label = self.symbols.label(addr).unwrap_or_default();
+                format!(
+                    "x{:04X}  {:04X}  {:<12} {}",
+                    addr,
+                    word,
+                    label,
+                    disassemble(addr, *word, &self.symbols)
+                )
+            })
+            .collect();
+        Ok(json!({ "content": content.join("\n"), "mimeType": "text/x-lc3" }))
+    }
+
+    /// Console input goes to the keyboard, watch and hover expressions
+    /// are registers, labels, addresses or `x/<n> <location>`
+    fn evaluate(&mut self, arguments: &Value) -> Result<Value, String> {
+        let expression = arguments["expression"].as_str().unwrap_or_default();
+        if arguments["context"] == "repl" {
+            // an empty line sends enter
+            let input = if expression.is_empty() {
+                "\n"
+            } else {
+                expression
+            };
+            self.console.push_input(input);
+            self.input_notice = false;
+            return Ok(json!({ "result": "", "variablesReference": 0 }));
+        }
+
+        if let Some(examine) = expression.strip_prefix("x/") {
+            let (count, location) = examine.split_once(' ').unwrap_or((examine, ""));
+            let count: u16 = count
+                .parse()
+                .map_err(|_| format!("invalid count {}", count))?;
+            let addr = self
+                .symbols
+                .resolve(location.trim())
+                .ok_or_else(|| format!("cannot evaluate {}", location.trim()))?;
+            let values: Vec<String> = (0..count)
+                .map(|i| format!("x{:04X}", *self.vm.mem_mut(addr.wrapping_add(i))))
+                .collect();
+            let result = format!("{}: {}", self.symbols.describe(addr), values.join(" "));
+            return Ok(json!({ "result": result, "variablesReference": 0 }));
+        }
+
+        let value = match REGISTER_NAMES
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(expression))
+        {
+            Some(register) => self.vm.reg(register as u16),
+            None => {
+                let addr = self
+                    .symbols
+                    .resolve(expression)
+                    .ok_or_else(|| format!("cannot evaluate {}", expression))?;
+                *self.vm.mem_mut(addr)
+            }
+        };
+        Ok(
+            json!({ "result": format!("x{:04X} ({})", value, value as i16), "variablesReference": 0 }),
+        )
+    }
+
+    /// Step over subroutine calls, anything else is a single step
+    fn next(&mut self) -> Value {
+        let pc = self.vm.reg(Register::PC.into());
+        let instruction = decode_instruction(*self.vm.mem_mut(pc));
+        if instruction.opcode == Opcode::JSR {
+            self.resume(StepTarget::Address(pc.wrapping_add(1)));
+        } else {
+            // output and halt events are sent by the caller
+            let _ = self.step();
+        }
+        json!({})
+    }
+
+    fn resume(&mut self, target: StepTarget) {
+        self.step_target = Some(target);
+        self.skip_breakpoint = true;
+    }
+
+    /// Execute a single instruction and forward its effects
+    fn step(&mut self) -> io::Result<()> {
+        if self.vm.is_running() {
+            self.vm.step();
+        }
+        self.flush_output()?;
+        if !self.vm.is_running() {
+            self.send_finished()?;
+        }
+        Ok(())
+    }
+
+    /// Run up to `count` instructions towards the current step target
+    fn execute(&mut self, count: usize) -> io::Result<()> {
+        for _ in 0..count {
+            if !self.vm.is_running() {
+                break;
+            }
+            if self.vm.is_awaiting_input() && !self.console.has_input() {
+                if !self.input_notice {
+                    self.input_notice = true;
+                    self.send_output(
+                        "console",
+                        "program is waiting for input, type in the debug console\n",
+                    )?;
+                }
+                break;
+            }
+
+            let pc = self.vm.reg(Register::PC.into());
+            if self.breakpoints.contains(&pc) && !self.skip_breakpoint {
+                self.step_target = None;
+                self.flush_output()?;
+                return self.send_stopped("breakpoint");
+            }
+            self.skip_breakpoint = false;
+
+            let instruction = decode_instruction(*self.vm.mem_mut(pc));
+            self.vm.step();
+
+            let reached = match &mut self.step_target {
+                Some(StepTarget::Address(addr)) => self.vm.reg(Register::PC.into()) == *addr,
+                Some(StepTarget::Return { depth }) => match instruction.opcode {
+                    Opcode::JSR => {
+                        *depth += 1;
+                        false
+                    }
+                    Opcode::JMP if instruction.base_r == u16::from(Register::R7) => {
+                        if *depth == 0 {
+                            true
+                        } else {
+                            *depth -= 1;
+                            false
+                        }
+                    }
+                    _ => false,
+                },
+                _ => false,
+            };
+            if reached && self.vm.is_running() {
+                self.step_target = None;
+                self.flush_output()?;
+                return self.send_stopped("step");
+            }
+        }
+
+        self.flush_output()?;
+        if !self.vm.is_running() && self.step_target.is_some() {
+            self.step_target = None;
+            self.send_finished()?;
+        }
+        Ok(())
+    }
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        let output = self.console.take_output();
+        if output.is_empty() {
+            return Ok(());
+        }
+        self.send_output("stdout", &output)
+    }
+
+    fn send_output(&mut self, category: &str, output: &str) -> io::Result<()> {
+        self.send_event("output", json!({ "category": category, "output": output }))
+    }
+
+    fn send_stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.send_event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )
+    }
+
+    /// The program stopped running, on an error it stays stopped at the
+    /// faulting instruction so the stack can be inspected until it is resumed
+    fn send_finished(&mut self) -> io::Result<()> {
+        match self.vm.error().map(str::to_string) {
+            Some(error) if !self.fault_reported => {
+                self.fault_reported = true;
+                self.send_output("stderr", &format!("{}\n", error))?;
+                self.send_stopped("exception")
+            }
+            _ => self.send_exited(),
+        }
+    }
+
+    fn send_exited(&mut self) -> io::Result<()> {
+        self.send_event("exited", json!({ "exitCode": 0 }))?;
+        self.send_event("terminated", json!({}))
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+        });
+        match result {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(message) => {
+                response["success"] = json!(false);
+                response["message"] = json!(message);
+            }
+        }
+        self.send(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsFunctionBreakpoints": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsSetVariable": true,
+        "supportsEvaluateForHovers": true,
+    })
+}
+
+fn scopes() -> Value {
+    json!({ "scopes": [
+        { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+        { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+        { "name": "Memory", "variablesReference": MEMORY_REF, "expensive": true },
+    ]})
+}
+
+fn variable(name: &str, value: String, reference: i64) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": reference })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble, Options};
+    use crate::dap::{read_message, DapServer};
+    use crate::debuginfo::DebugInfo;
+    use crate::source::Sources;
+    use serde_json::{json, Value};
+    use std::io::BufReader;
+
+    fn request(seq: i64, command: &str, arguments: Value) -> Value {
+        json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+    }
+
+    /// Decode everything the server wrote
+    fn messages(output: &[u8]) -> Vec<Value> {
+        let mut reader = BufReader::new(output);
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn write_program(name: &str, words: &[u16]) -> String {
+        let path = std::env::temp_dir().join(name);
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        std::fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_launch_break_and_continue() {
+        let path = write_program(
+            "dap_test_launch.obj",
+            &[
+                0x3000, // .ORIG x3000
+                0xe002, // LEA R0, #2
+                0xf022, // PUTS
+                0xf025, // HALT
+                0x0068, // 'h'
+                0x0069, // 'i'
+                0x0000,
+            ],
+        );
+
+        let mut server = DapServer::new(vec![]);
+        server
+            .handle_message(&request(1, "initialize", json!({})))
+            .unwrap();
+        server
+            .handle_message(&request(2, "launch", json!({ "program": path })))
+            .unwrap();
+        server
+            .handle_message(&request(
+                3,
+                "setBreakpoints",
+                json!({ "source": { "sourceReference": 1 }, "breakpoints": [{ "line": 3 }] }),
+            ))
+            .unwrap();
+        server
+            .handle_message(&request(4, "configurationDone", json!({})))
+            .unwrap();
+        server.execute(100).unwrap();
+
+        let sent = messages(&server.output);
+        let breakpoints = sent
+            .iter()
+            .find(|m| m["command"] == "setBreakpoints")
+            .unwrap();
+        assert_eq!(breakpoints["body"]["breakpoints"][0]["verified"], true);
+        let stopped = sent.iter().find(|m| m["event"] == "stopped").unwrap();
+        assert_eq!(stopped["body"]["reason"], "breakpoint");
+        let output = sent.iter().find(|m| m["event"] == "output").unwrap();
+        assert_eq!(output["body"]["output"], "hi");
+
+        server.output.clear();
+        server
+            .handle_message(&request(5, "stackTrace", json!({ "threadId": 1 })))
+            .unwrap();
+        server
+            .handle_message(&request(6, "continue", json!({ "threadId": 1 })))
+            .unwrap();
+        server.execute(100).unwrap();
+
+        let sent = messages(&server.output);
+        let frames = &sent[0]["body"]["stackFrames"];
+        assert_eq!(frames.as_array().unwrap().len(), 1);
+        assert_eq!(frames[0]["line"], 3);
+        assert_eq!(frames[0]["instructionPointerReference"], "0x3002");
+        assert!(sent.iter().any(|m| m["event"] == "terminated"));
+    }
+
+    #[test]
+    fn test_fault_stops_with_the_call_stack() {
+        let path = write_program(
+            "dap_test_fault.obj",
+            &[
+                0x3000, // .ORIG x3000
+                0x4801, // JSR BAD
+                0xf025, // HALT
+                0xf030, // BAD TRAP x30
+            ],
+        );
+
+        let mut server = DapServer::new(vec![]);
+        server
+            .handle_message(&request(1, "launch", json!({ "program": path })))
+            .unwrap();
+        server
+            .handle_message(&request(2, "configurationDone", json!({})))
+            .unwrap();
+        server.execute(100).unwrap();
+        server
+            .handle_message(&request(3, "stackTrace", json!({ "threadId": 1 })))
+            .unwrap();
+
+        let sent = messages(&server.output);
+        let stopped = sent.iter().find(|m| m["event"] == "stopped").unwrap();
+        assert_eq!(stopped["body"]["reason"], "exception");
+        let trace = sent.iter().find(|m| m["command"] == "stackTrace").unwrap();
+        let frames = &trace["body"]["stackFrames"];
+        assert_eq!(frames.as_array().unwrap().len(), 2);
+        assert_eq!(frames[0]["instructionPointerReference"], "0x3002");
+        assert_eq!(frames[1]["instructionPointerReference"], "0x3000");
+        assert!(!sent.iter().any(|m| m["event"] == "terminated"));
+
+        server.output.clear();
+        server
+            .handle_message(&request(4, "continue", json!({ "threadId": 1 })))
+            .unwrap();
+        server.execute(100).unwrap();
+
+        let sent = messages(&server.output);
+        assert!(!sent.iter().any(|m| m["event"] == "stopped"));
+        assert!(sent.iter().any(|m| m["event"] == "terminated"));
+    }
+
+    #[test]
+    fn test_console_input() {
+        let path = write_program(
+            "dap_test_input.obj",
+            &[
+                0x3000, // .ORIG x3000
+                0xf020, // GETC
+                0xf021, // OUT
+                0xf025, // HALT
+            ],
+        );
+
+        let mut server = DapServer::new(vec![]);
+        server
+            .handle_message(&request(1, "launch", json!({ "program": path })))
+            .unwrap();
+        server
+            .handle_message(&request(2, "configurationDone", json!({})))
+            .unwrap();
+        server.execute(100).unwrap();
+        assert!(!server.is_executing());
+
+        server
+            .handle_message(&request(
+                3,
+                "evaluate",
+                json!({ "expression": "k", "context": "repl" }),
+            ))
+            .unwrap();
+        assert!(server.is_executing());
+        server.execute(100).unwrap();
+
+        let sent = messages(&server.output);
+        assert!(sent
+            .iter()
+            .any(|m| m["event"] == "output" && m["body"]["output"] == "k"));
+        assert!(sent.iter().any(|m| m["event"] == "exited"));
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let path = write_program("dap_test_vars.obj", &[0x3000, 0x1261, 0xf025]);
+
+        let mut server = DapServer::new(vec![]);
+        server
+            .handle_message(&request(1, "launch", json!({ "program": path })))
+            .unwrap();
+        server
+            .handle_message(&request(
+                2,
+                "setVariable",
+                json!({ "variablesReference": 1, "name": "R1", "value": "x10" }),
+            ))
+            .unwrap();
+        server
+            .handle_message(&request(3, "stepIn", json!({ "threadId": 1 })))
+            .unwrap();
+        server.output.clear();
+        server
+            .handle_message(&request(4, "variables", json!({ "variablesReference": 1 })))
+            .unwrap();
+        server
+            .handle_message(&request(
+                5,
+                "variables",
+                json!({ "variablesReference": 0x1030 }),
+            ))
+            .unwrap();
+        server
+            .handle_message(&request(
+                6,
+                "evaluate",
+                json!({ "expression": "x/2 x3000", "context": "watch" }),
+            ))
+            .unwrap();
+        server
+            .handle_message(&request(
+                7,
+                "variables",
+                json!({ "variablesReference": 0x1100 }),
+            ))
+            .unwrap();
+
+        let sent = messages(&server.output);
+        let registers = &sent[0]["body"]["variables"];
+        assert_eq!(registers[1]["value"], "x0011 (17)");
+        assert_eq!(registers[8]["value"], "x3001 (12289)");
+        assert_eq!(registers[9]["value"], "--p");
+        let page = &sent[1]["body"]["variables"];
+        assert_eq!(page[0]["name"], "x3000");
+        assert_eq!(page[0]["value"], "x1261 (4705)");
+        assert_eq!(sent[2]["body"]["result"], "x3000: x1261 xF025");
+        assert_eq!(sent[3]["body"]["variables"], json!([]));
+    }
+
+    #[test]
+    fn test_breakpoints_in_source_file() {
+        let dir = std::env::temp_dir();
+        let source = dir.join("dap_test_source.asm");
+        let text = "        .ORIG x3000
+        ; print twice
+        LEA R0, MSG
+        PUTS
+
+        PUTS            ; again
+        HALT
+MSG     .STRINGZ \"hi\"
+        .END
+";
+        std::fs::write(&source, text).unwrap();
+        let mut sources = Sources::new();
+        let file = sources.load(&source).unwrap();
+        let assembly = assemble(&mut sources, file, &Options::default())
+            .unwrap_or_else(|diagnostics| panic!("{:?}", diagnostics));
+        let mut words = vec![assembly.program.origin];
+        words.extend(&assembly.program.words);
+        let path = write_program("dap_test_source.obj", &words);
+        let debug = DebugInfo::from_assembly(&assembly, &sources);
+        std::fs::write(dir.join("dap_test_source.dbg"), debug.to_text()).unwrap();
+
+        let mut server = DapServer::new(vec![]);
+        server
+            .handle_message(&request(1, "launch", json!({ "program": path })))
+            .unwrap();
+        let source_path = source.to_string_lossy();
+        server
+            .handle_message(&request(
+                2,
+                "setBreakpoints",
+                json!({
+                    "source": { "path": source_path },
+                    "breakpoints": [{ "line": 5 }, { "line": 8 }]
+                }),
+            ))
+            .unwrap();
+        server
+            .handle_message(&request(3, "configurationDone", json!({})))
+            .unwrap();
+        server.execute(100).unwrap();
+        server
+            .handle_message(&request(4, "stackTrace", json!({ "threadId": 1 })))
+            .unwrap();
+
+        let sent = messages(&server.output);
+        let response = sent
+            .iter()
+            .find(|m| m["command"] == "setBreakpoints")
+            .unwrap();
+        let breakpoints = &response["body"]["breakpoints"];
+        // the empty line moves to the next instruction, a string is no code
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[0]["line"], 6);
+        assert_eq!(breakpoints[1]["verified"], false);
+        let trace = sent.iter().find(|m| m["command"] == "stackTrace").unwrap();
+        let frame = &trace["body"]["stackFrames"][0];
+        assert_eq!(frame["line"], 6);
+        assert_eq!(frame["source"]["name"], "dap_test_source.asm");
+        assert_eq!(frame["instructionPointerReference"], "0x3002");
+    }
+}
@@ -0,0 +1,290 @@
+use crate::assembler::Assembly;
+use crate::source::Sources;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Source file and line of every word, written by the assembler to a
+/// .dbg file next to the program
+///
+/// // lc3 debug info
+/// file 0 /home/student/hello.asm
+/// label LOOP x3001 0 6
+/// label MSG x3004 0 11 data
+/// x3000 0 5
+/// x3004 0 11 data
+#[derive(Default)]
+pub struct DebugInfo {
+    files: Vec<PathBuf>,
+    // lines of every file, empty if it could not be read
+    texts: Vec<Vec<String>>,
+    words: BTreeMap<u16, Location>,
+    labels: Vec<(String, u16, Location)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub file: usize,
+    // 1 based
+    pub line: usize,
+    // .FILL, .BLKW, .STRINGZ or a literal, never executed on purpose
+    pub data: bool,
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_assembly(assembly: &Assembly, sources: &Sources) -> Self {
+        let mut info = Self::new();
+        for file in sources.files() {
+            let path = file.path.canonicalize().unwrap_or(file.path.clone());
+            info.files.push(path);
+            info.texts
+                .push(file.text.lines().map(|line| line.to_string()).collect());
+        }
+        for listed in &assembly.listing {
+            let location = Location {
+                file: listed.line.file,
+                line: listed.line.line,
+                data: listed.data,
+            };
+            for addr in (listed.address..).take(listed.words.len()) {
+                info.words.insert(addr, location);
+            }
+        }
+        for definition in &assembly.definitions {
+            if let Some(addr) = assembly.symbols.address(&definition.name) {
+                let location = Location {
+                    file: definition.span.file,
+                    line: definition.span.line,
+                    data: info.is_data(addr),
+                };
+                info.labels.push((definition.name.clone(), addr, location));
+            }
+        }
+        info
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        Ok(Self::parse(&text, dir))
+    }
+
+    /// Debug info from the .dbg file next to a program, empty if there is none
+    pub fn for_program(path: &str) -> Self {
+        let dbg_path = Path::new(path).with_extension("dbg");
+        Self::load(&dbg_path.to_string_lossy()).unwrap_or_default()
+    }
+
+    /// Parse the .dbg format, source files that moved are looked for in `dir`
+    pub fn parse(text: &str, dir: &Path) -> Self {
+        let mut info = Self::new();
+        for line in text.lines() {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.as_slice() {
+                ["file", _, ..] => {
+                    let path = line.splitn(3, char::is_whitespace).nth(2).unwrap_or("");
+                    let mut path = PathBuf::from(path.trim());
+                    if !path.exists() {
+                        if let Some(name) = path.file_name() {
+                            path = dir.join(name);
+                        }
+                    }
+                    let text = std::fs::read_to_string(&path).unwrap_or_default();
+                    info.texts
+                        .push(text.lines().map(|line| line.to_string()).collect());
+                    info.files.push(path);
+                }
+                ["label", name, addr, file, line, rest @ ..] => {
+                    let data = rest == ["data"];
+                    if let (Some(addr), Some(location)) = (hex(addr), location(file, line, data)) {
+                        info.labels.push((name.to_string(), addr, location));
+                    }
+                }
+                [addr, file, line, rest @ ..] => {
+                    let data = rest == ["data"];
+                    if let (Some(addr), Some(location)) = (hex(addr), location(file, line, data)) {
+                        info.words.insert(addr, location);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // lines are 1 based and every entry has to name a file listed above
+        let files = info.files.len();
+        let valid = |location: &Location| location.file < files && location.line > 0;
+        info.words.retain(|_, location| valid(location));
+        info.labels.retain(|(_, _, location)| valid(location));
+        info
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = "// lc3 debug info\n".to_string();
+        for (i, path) in self.files.iter().enumerate() {
+            text += &format!("file {} {}\n", i, path.display());
+        }
+        for (name, addr, location) in &self.labels {
+            let data = if location.data { " data" } else { "" };
+            text += &format!(
+                "label {} x{:04X} {} {}{}\n",
+                name, addr, location.file, location.line, data
+            );
+        }
+        for (addr, location) in &self.words {
+            let data = if location.data { " data" } else { "" };
+            text += &format!(
+                "x{:04X} {} {}{}\n",
+                addr, location.file, location.line, data
+            );
+        }
+        text
+    }
+
+    /// Add the debug info of another program
+    pub fn extend(&mut self, other: DebugInfo) {
+        let offset = self.files.len();
+        let moved = |location: Location| Location {
+            file: location.file + offset,
+            ..location
+        };
+        self.files.extend(other.files);
+        self.texts.extend(other.texts);
+        for (addr, location) in other.words {
+            self.words.entry(addr).or_insert(moved(location));
+        }
+        for (name, addr, location) in other.labels {
+            self.labels.push((name, addr, moved(location)));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    pub fn location(&self, addr: u16) -> Option<Location> {
+        self.words.get(&addr).copied()
+    }
+
+    pub fn path(&self, file: usize) -> Option<&Path> {
+        self.files.get(file).map(|path| path.as_path())
+    }
+
+    /// Source line the word at addr was assembled from, comments included
+    pub fn source_line(&self, addr: u16) -> Option<&str> {
+        let location = self.location(addr)?;
+        self.texts
+            .get(location.file)?
+            .get(location.line.checked_sub(1)?)
+            .map(|line| line.as_str())
+    }
+
+    pub fn is_data(&self, addr: u16) -> bool {
+        self.location(addr).is_some_and(|location| location.data)
+    }
+
+    /// Labels with where they were defined
+    pub fn labels(&self) -> &[(String, u16, Location)] {
+        &self.labels
+    }
+
+    /// First instruction on `line` of `file`, or on the next line that has one
+    ///
+    /// The file matches by its full path or by its trailing components,
+    /// so `hello.asm` finds `/home/student/hello.asm`. Returns the address
+    /// and the line it is on.
+    pub fn address_of(&self, file: &str, line: usize) -> Option<(u16, usize)> {
+        let wanted = Path::new(file);
+        let wanted = wanted.canonicalize().unwrap_or(wanted.to_path_buf());
+        let files: Vec<usize> = (0..self.files.len())
+            .filter(|i| self.files[*i] == wanted || self.files[*i].ends_with(file))
+            .collect();
+        self.words
+            .iter()
+            .filter(|(_, location)| {
+                files.contains(&location.file) && location.line >= line && !location.data
+            })
+            .min_by_key(|(addr, location)| (location.line, **addr))
+            .map(|(addr, location)| (*addr, location.line))
+    }
+
+    /// Address of a `file:line` location
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        let (file, line) = text.rsplit_once(':')?;
+        let line = line.trim().parse().ok()?;
+        self.address_of(file.trim(), line).map(|(addr, _)| addr)
+    }
+}
+
+fn hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text.trim_start_matches(['x', 'X']), 16).ok()
+}
+
+fn location(file: &str, line: &str, data: bool) -> Option<Location> {
+    Some(Location {
+        file: file.parse().ok()?,
+        line: line.parse().ok()?,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble, Options};
+    use crate::debuginfo::DebugInfo;
+    use crate::source::Sources;
+    use std::path::Path;
+
+    #[test]
+    fn test_debug_info_round_trip() {
+        let mut sources = Sources::new();
+        let text = "        .ORIG x3000
+        ; print a star
+        LD R0, STAR
+        OUT             ; one
+        HALT
+STAR    .FILL '*'
+        .END";
+        let file = sources.add(Path::new("star.asm"), text.to_string());
+        let assembly = assemble(&mut sources, file, &Options::default())
+            .unwrap_or_else(|diagnostics| panic!("{:?}", diagnostics));
+        let info = DebugInfo::from_assembly(&assembly, &sources);
+
+        assert_eq!(
+            info.source_line(0x3001),
+            Some("        OUT             ; one")
+        );
+        assert!(info.is_data(0x3003));
+        assert_eq!(info.address_of("star.asm", 2), Some((0x3000, 3)));
+        assert_eq!(info.resolve("star.asm:5"), Some(0x3002));
+        assert_eq!(info.resolve("other.asm:5"), None);
+        // nothing but data after line 5
+        assert_eq!(info.resolve("star.asm:6"), None);
+
+        let parsed = DebugInfo::parse(&info.to_text(), Path::new(""));
+        assert_eq!(parsed.location(0x3003), info.location(0x3003));
+        assert_eq!(parsed.labels(), info.labels());
+        assert_eq!(parsed.labels()[0].0, "STAR");
+    }
+
+    #[test]
+    fn test_parse_drops_bad_locations() {
+        let text = "file 0 star.asm
+label STAR x3003 0 6 data
+label GONE x3004 3 1
+x3000 0 3
+x3001 1 4
+x3002 0 0
+";
+        let info = DebugInfo::parse(text, Path::new(""));
+        assert!(info.location(0x3000).is_some());
+        assert_eq!(info.location(0x3001), None);
+        assert_eq!(info.location(0x3002), None);
+        assert_eq!(info.source_line(0x3001), None);
+        assert_eq!(info.labels().len(), 1);
+        assert!(info.path(0).is_some());
+        assert_eq!(info.path(1), None);
+    }
+}
@@ -1,8 +1,15 @@
 use crate::opcodes::mask;
-use crate::vm::{sext, Opcode, Register};
-use std::fmt::{Display, Formatter};
+use crate::vm::{sext, Opcode};
 
-pub(crate) struct DecodedInstruction {
+// widths of the immediate and offset fields, the assembler checks its operands against them
+pub const IMM5: u8 = 5;
+pub const OFFSET6: u8 = 6;
+pub const OFFSET9: u8 = 9;
+pub const OFFSET11: u8 = 11;
+pub const TRAP_VECTOR: u8 = 8;
+
+#[derive(Clone, Copy)]
+pub struct DecodedInstruction {
     pub(crate) opcode: Opcode,
     // destination register
     pub(crate) dr: u16,
@@ -43,22 +50,22 @@ impl DecodedInstruction {
 
 pub fn decode_instruction(instruction: u16) -> DecodedInstruction {
     let opcode = Opcode::try_from(instruction >> 12).expect("invalid instruction");
-    let mut decoded_instruction = DecodedInstruction::init(opcode.clone());
+    let mut decoded_instruction = DecodedInstruction::init(opcode);
 
     decoded_instruction.dr = (instruction >> 9) & mask(3);
     decoded_instruction.sr1 = (instruction >> 6) & mask(3);
     decoded_instruction.sr2 = instruction & mask(3);
-    decoded_instruction.imm5 = sext(instruction & mask(5), 5);
+    decoded_instruction.imm5 = sext(instruction & mask(IMM5), IMM5 as usize);
     decoded_instruction.nzp = (instruction >> 9) & mask(3);
     decoded_instruction.base_r = (instruction >> 6) & mask(3);
-    decoded_instruction.trap_code = instruction & mask(8);
+    decoded_instruction.trap_code = instruction & mask(TRAP_VECTOR);
     decoded_instruction.offset = match opcode {
         // offset6
-        Opcode::STR | Opcode::LDR => sext(instruction & mask(6), 6),
+        Opcode::STR | Opcode::LDR => sext(instruction & mask(OFFSET6), OFFSET6 as usize),
         // offset11
-        Opcode::JSR => sext(instruction & mask(11), 11),
+        Opcode::JSR => sext(instruction & mask(OFFSET11), OFFSET11 as usize),
         // offset9
-        _ => sext(instruction & mask(9), 9),
+        _ => sext(instruction & mask(OFFSET9), OFFSET9 as usize),
     };
     decoded_instruction.flag = match opcode {
         Opcode::ADD | Opcode::AND => (instruction >> 5) & mask(1),
@@ -69,6 +76,7 @@ pub fn decode_instruction(instruction: u16) -> DecodedInstruction {
 }
 
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
 mod tests {
     use crate::decode_instruction::decode_instruction;
     use crate::vm::{Opcode, Register};
@@ -77,9 +85,9 @@ mod tests {
     fn test_instruction_decoding() {
         let decoded_instruction = decode_instruction(0b0001_000_001_0_00_010);
         assert_eq!(decoded_instruction.opcode, Opcode::ADD);
-        assert_eq!(decoded_instruction.dr, Register::R0.into());
-        assert_eq!(decoded_instruction.sr1, Register::R1.into());
+        assert_eq!(decoded_instruction.dr, u16::from(Register::R0));
+        assert_eq!(decoded_instruction.sr1, u16::from(Register::R1));
         assert_eq!(decoded_instruction.flag, 0); // register mode
-        assert_eq!(decoded_instruction.sr2, Register::R2.into());
+        assert_eq!(decoded_instruction.sr2, u16::from(Register::R2));
     }
 }
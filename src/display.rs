@@ -1,6 +1,8 @@
-use crate::decode_instruction::DecodedInstruction;
+use crate::debuginfo::DebugInfo;
+use crate::decode_instruction::{decode_instruction, DecodedInstruction};
+use crate::symbols::SymbolTable;
 use crate::vm::{Opcode, Register};
-use std::fmt::{format, Display, Formatter, Write};
+use std::fmt::{Display, Formatter};
 
 impl Display for Opcode {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -28,7 +30,7 @@ impl Display for Opcode {
 impl Display for Register {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Register::R0 => f.write_str("RO"),
+            Register::R0 => f.write_str("R0"),
             Register::R1 => f.write_str("R1"),
             Register::R2 => f.write_str("R2"),
             Register::R3 => f.write_str("R3"),
@@ -82,14 +84,9 @@ impl Display for DecodedInstruction {
                 result += format!(" {}", r(self.base_r)).as_str();
             }
             Opcode::RES => result += "unused",
-            Opcode::TRAP => match self.trap_code {
-                0x20 => result += " GETC",
-                0x21 => result += " OUT",
-                0x22 => result += " PUTS",
-                0x23 => result += " IN",
-                0x24 => result += " PUTSp",
-                0x25 => result += " HALT",
-                _ => result += "unrecognized",
+            Opcode::TRAP => match trap_name(self.trap_code) {
+                Some(name) => result += format!(" {}", name).as_str(),
+                None => result += " unrecognized",
             },
         }
 
@@ -97,6 +94,123 @@ impl Display for DecodedInstruction {
     }
 }
 
+/// The source line the word at addr came from, or its disassembly
+/// when there is no debug info for it
+pub(crate) fn source_or_disassembly(
+    addr: u16,
+    word: u16,
+    symbols: &SymbolTable,
+    debug: &DebugInfo,
+) -> String {
+    match debug.source_line(addr) {
+        Some(line) => line.trim().to_string(),
+        None => disassemble(addr, word, symbols),
+    }
+}
+
+/// Disassemble the word at addr, PC relative operands are shown
+/// as the label at the target when there is one, else as the absolute address
+pub(crate) fn disassemble(addr: u16, word: u16, symbols: &SymbolTable) -> String {
+    let instruction = decode_instruction(word);
+    let target = addr.wrapping_add(1).wrapping_add(instruction.offset);
+    let target = match symbols.label(target) {
+        Some(label) => label.to_string(),
+        None => format!("x{:04X}", target),
+    };
+
+    match instruction.opcode {
+        Opcode::BR => {
+            let flags: String = [(4, 'n'), (2, 'z'), (1, 'p')]
+                .iter()
+                .filter(|(bit, _)| instruction.nzp & bit != 0)
+                .map(|(_, flag)| *flag)
+                .collect();
+            match instruction.nzp {
+                0 => "NOP".to_string(),
+                0b111 => format!("BR {}", target),
+                _ => format!("BR{} {}", flags, target),
+            }
+        }
+        Opcode::ADD | Opcode::AND if instruction.flag == 1 => format!(
+            "{} {} {} #{}",
+            instruction.opcode,
+            r(instruction.dr),
+            r(instruction.sr1),
+            instruction.imm5 as i16
+        ),
+        Opcode::LD | Opcode::LDI | Opcode::ST | Opcode::STI | Opcode::LEA => {
+            format!("{} {} {}", instruction.opcode, r(instruction.dr), target)
+        }
+        Opcode::JSR if instruction.flag == 1 => format!("JSR {}", target),
+        Opcode::JSR => format!("JSRR {}", r(instruction.base_r)),
+        Opcode::LDR | Opcode::STR => format!(
+            "{} {} {} #{}",
+            instruction.opcode,
+            r(instruction.dr),
+            r(instruction.base_r),
+            instruction.offset as i16
+        ),
+        Opcode::JMP if instruction.base_r == u16::from(Register::R7) => "RET".to_string(),
+        _ => instruction.to_string(),
+    }
+}
+
+/// An address with the label it is at or after, x3005 (PRINT+2),
+/// only the address when there is no label before it
+pub(crate) fn address(addr: u16, symbols: &SymbolTable) -> String {
+    match symbols.containing(addr) {
+        Some(_) => format!("x{:04X} ({})", addr, symbols.describe(addr)),
+        None => format!("x{:04X}", addr),
+    }
+}
+
+/// Name of a trap routine
+pub(crate) fn trap_name(trap_code: u16) -> Option<&'static str> {
+    match trap_code {
+        0x20 => Some("GETC"),
+        0x21 => Some("OUT"),
+        0x22 => Some("PUTS"),
+        0x23 => Some("IN"),
+        0x24 => Some("PUTSP"),
+        0x25 => Some("HALT"),
+        _ => None,
+    }
+}
+
+/// Render the COND register as nzp flags, e.g. "-z-"
+pub(crate) fn cond_flags(cond: u16) -> String {
+    let flag = |bit: u16, c: char| if cond & bit != 0 { c } else { '-' };
+    format!("{}{}{}", flag(4, 'n'), flag(2, 'z'), flag(1, 'p'))
+}
+
 fn r(reg: u16) -> Register {
     Register::try_from(reg).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::display::disassemble;
+    use crate::symbols::SymbolTable;
+
+    #[test]
+    fn test_disassemble_with_symbols() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("PRINT_NUM", 0x3010);
+        symbols.insert("LOOP", 0x3000);
+
+        // JSR PRINT_NUM
+        assert_eq!(disassemble(0x3004, 0x480b, &symbols), "JSR PRINT_NUM");
+        // BRp LOOP
+        assert_eq!(disassemble(0x3004, 0x03fb, &symbols), "BRp LOOP");
+        // LEA R0 to an address without a label
+        assert_eq!(disassemble(0x3004, 0xe002, &symbols), "LEA R0 x3007");
+        // ADD R1 R1 #-1
+        assert_eq!(disassemble(0x3004, 0x127f, &symbols), "ADD R1 R1 #-1");
+        // LDR R0 R6 #-2
+        assert_eq!(disassemble(0x3004, 0x61be, &symbols), "LDR R0 R6 #-2");
+        // JSRR R2, RET
+        assert_eq!(disassemble(0x3004, 0x4080, &symbols), "JSRR R2");
+        assert_eq!(disassemble(0x3004, 0xc1c0, &symbols), "RET");
+        assert_eq!(disassemble(0x3004, 0xf022, &symbols), "TRAP PUTS");
+    }
+}
@@ -0,0 +1,280 @@
+use crate::lexer::{Token, TokenKind};
+use crate::source::{Diagnostic, Span};
+
+/// Operand expression: numbers, labels and constants combined with
+/// + - * / % and parentheses, e.g. `LABEL+2` or `x10*4`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i32, Span),
+    Symbol(String, Span),
+    Negate(Box<Expr>, Span),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>, Span),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+/// What a value is relative to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Base {
+    // a plain number
+    Absolute,
+    // offset from the start of the section, only in relocatable code
+    Section,
+    // offset from a symbol defined in another object
+    Extern(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Value {
+    pub value: i32,
+    pub base: Base,
+}
+
+impl Value {
+    pub fn absolute(value: i32) -> Self {
+        Self {
+            value,
+            base: Base::Absolute,
+        }
+    }
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Number(_, span)
+            | Expr::Symbol(_, span)
+            | Expr::Negate(_, span)
+            | Expr::Binary(_, _, _, span) => *span,
+        }
+    }
+
+    /// Every symbol the expression refers to
+    pub fn symbols(&self) -> Vec<(&str, Span)> {
+        match self {
+            Expr::Number(_, _) => vec![],
+            Expr::Symbol(name, span) => vec![(name, *span)],
+            Expr::Negate(inner, _) => inner.symbols(),
+            Expr::Binary(_, left, right, _) => {
+                let mut symbols = left.symbols();
+                symbols.extend(right.symbols());
+                symbols
+            }
+        }
+    }
+
+    /// Parse a whole operand, every token must be used
+    pub fn parse(tokens: &[Token]) -> Result<Expr, Diagnostic> {
+        let Some(first) = tokens.first() else {
+            return Err(Diagnostic::new(Span::default(), "expected an expression"));
+        };
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            end: first.span,
+        };
+        let expr = parser.sum()?;
+        match tokens.get(parser.position) {
+            Some(token) => Err(Diagnostic::new(
+                token.span,
+                "unexpected token in expression",
+            )),
+            None => Ok(expr),
+        }
+    }
+
+    /// Evaluate with `lookup` resolving symbols
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<Value>) -> Result<Value, Diagnostic> {
+        match self {
+            Expr::Number(value, _) => Ok(Value::absolute(*value)),
+            Expr::Symbol(name, span) => lookup(name)
+                .ok_or_else(|| Diagnostic::new(*span, format!("undefined symbol {}", name))),
+            Expr::Negate(inner, span) => {
+                let inner = inner.eval(lookup)?;
+                if inner.base != Base::Absolute {
+                    return Err(Diagnostic::new(*span, "can not negate an address"));
+                }
+                Ok(Value::absolute(inner.value.wrapping_neg()))
+            }
+            Expr::Binary(op, left, right, span) => {
+                let (left, right) = (left.eval(lookup)?, right.eval(lookup)?);
+                let value = match op {
+                    BinaryOp::Add => left.value.wrapping_add(right.value),
+                    BinaryOp::Sub => left.value.wrapping_sub(right.value),
+                    BinaryOp::Mul => left.value.wrapping_mul(right.value),
+                    BinaryOp::Div | BinaryOp::Rem if right.value == 0 => {
+                        return Err(Diagnostic::new(*span, "division by zero"))
+                    }
+                    BinaryOp::Div => left.value.wrapping_div(right.value),
+                    BinaryOp::Rem => left.value.wrapping_rem(right.value),
+                };
+                let base = match (op, left.base, right.base) {
+                    (_, Base::Absolute, Base::Absolute) => Base::Absolute,
+                    (BinaryOp::Add, base, Base::Absolute)
+                    | (BinaryOp::Add, Base::Absolute, base) => base,
+                    (BinaryOp::Sub, base, Base::Absolute) => base,
+                    // distance between two labels of the same section
+                    (BinaryOp::Sub, Base::Section, Base::Section) => Base::Absolute,
+                    _ => {
+                        return Err(Diagnostic::new(
+                            *span,
+                            "expression is not an address plus a constant",
+                        ))
+                    }
+                };
+                Ok(Value { value, base })
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    // span of the last token taken, for errors at the end of the input
+    end: Span,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.position).map(|token| &token.kind)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.position)?;
+        self.position += 1;
+        self.end = token.span;
+        Some(token)
+    }
+
+    fn sum(&mut self) -> Result<Expr, Diagnostic> {
+        let mut left = self.product()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Plus) => BinaryOp::Add,
+                Some(TokenKind::Minus) => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.next();
+            let right = self.product()?;
+            let span = left.span().to(&right.span());
+            left = Expr::Binary(op, Box::new(left), Box::new(right), span);
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, Diagnostic> {
+        let mut left = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Star) => BinaryOp::Mul,
+                Some(TokenKind::Slash) => BinaryOp::Div,
+                Some(TokenKind::Percent) => BinaryOp::Rem,
+                _ => return Ok(left),
+            };
+            self.next();
+            let right = self.unary()?;
+            let span = left.span().to(&right.span());
+            left = Expr::Binary(op, Box::new(left), Box::new(right), span);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, Diagnostic> {
+        let end = self.end;
+        let Some(token) = self.next().cloned() else {
+            let span = Span {
+                column: end.column + end.len,
+                len: 1,
+                ..end
+            };
+            return Err(Diagnostic::new(span, "expected an expression"));
+        };
+        match token.kind {
+            TokenKind::Number(value) => Ok(Expr::Number(value, token.span)),
+            TokenKind::Ident(name) => Ok(Expr::Symbol(name, token.span)),
+            TokenKind::Minus => {
+                let inner = self.unary()?;
+                let span = token.span.to(&inner.span());
+                Ok(Expr::Negate(Box::new(inner), span))
+            }
+            TokenKind::Plus => self.unary(),
+            TokenKind::LParen => {
+                let inner = self.sum()?;
+                match self.next() {
+                    Some(Token {
+                        kind: TokenKind::RParen,
+                        ..
+                    }) => Ok(inner),
+                    _ => Err(Diagnostic::new(token.span, "unclosed parenthesis")),
+                }
+            }
+            _ => Err(Diagnostic::new(token.span, "expected a number or a label")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expression::{Base, Expr, Value};
+    use crate::lexer::tokenize;
+
+    fn eval(text: &str) -> Result<Value, String> {
+        let tokens = tokenize(text, 0, 1).unwrap();
+        let lookup = |name: &str| match name {
+            "DATA" => Some(Value::absolute(0x3010)),
+            "COUNT" => Some(Value::absolute(4)),
+            "LOCAL" => Some(Value {
+                value: 6,
+                base: Base::Section,
+            }),
+            "PRINT" => Some(Value {
+                value: 0,
+                base: Base::Extern("PRINT".to_string()),
+            }),
+            _ => None,
+        };
+        Expr::parse(&tokens)
+            .and_then(|expr| expr.eval(&lookup))
+            .map_err(|e| e.message)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(eval("DATA+2").unwrap().value, 0x3012);
+        assert_eq!(eval("x10*COUNT").unwrap().value, 64);
+        assert_eq!(eval("-(COUNT+1)*2 % 4").unwrap().value, -2);
+        assert_eq!(eval("1+2*3-#4/2").unwrap().value, 5);
+        assert_eq!(eval("LOCAL+1").unwrap().base, Base::Section);
+        let tokens = tokenize("-(DATA+COUNT*2)", 0, 1).unwrap();
+        let expr = Expr::parse(&tokens).unwrap();
+        let names: Vec<&str> = expr.symbols().iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["DATA", "COUNT"]);
+        assert_eq!(eval("LOCAL-LOCAL").unwrap().base, Base::Absolute);
+        assert_eq!(
+            eval("PRINT+2").unwrap(),
+            Value {
+                value: 2,
+                base: Base::Extern("PRINT".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        assert_eq!(eval("NOPE").unwrap_err(), "undefined symbol NOPE");
+        assert_eq!(eval("1/0").unwrap_err(), "division by zero");
+        assert_eq!(eval("(1+2").unwrap_err(), "unclosed parenthesis");
+        assert_eq!(eval("1+").unwrap_err(), "expected an expression");
+        assert_eq!(eval("1 2").unwrap_err(), "unexpected token in expression");
+        assert_eq!(
+            eval("LOCAL*2").unwrap_err(),
+            "expression is not an address plus a constant"
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use crate::assembler::is_mnemonic;
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::source::{Diagnostic, Sources};
+use std::collections::HashSet;
+use std::path::Path;
+
+// columns are multiples of this
+const TAB_WIDTH: usize = 4;
+// lines with longer code do not push the comment column further right
+const COMMENT_LIMIT: usize = 40;
+
+/// One source line split into its columns
+enum Formatted {
+    Blank,
+    // a comment on its own line, true if it started in the first column
+    Comment(String, bool),
+    Code {
+        label: String,
+        op: String,
+        operands: String,
+        comment: Option<String>,
+    },
+}
+
+/// Re-emit a source file with aligned label, opcode, operand and comment columns
+///
+/// Mnemonics, directives and registers are upper cased (branch flags stay
+/// lower case, as in BRnz), hex numbers are written xFF and decimal immediates
+/// #10. Comments are kept as written. `macros` are the upper cased names of
+/// the macros the file can call, which are not labels.
+pub fn format_source(
+    text: &str,
+    file: usize,
+    macros: &HashSet<String>,
+) -> Result<String, Diagnostic> {
+    let mut lines = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let tokens = tokenize(line, file, i + 1)?;
+        lines.push(format_line(line, &tokens, macros));
+    }
+
+    let label_width = widest(&lines, |line| match line {
+        Formatted::Code { label, .. } => label.chars().count(),
+        _ => 0,
+    });
+    let op_column = round_up(label_width + 1).max(2 * TAB_WIDTH);
+    let op_width = widest(&lines, |line| match line {
+        Formatted::Code { op, operands, .. } if !operands.is_empty() => op.chars().count(),
+        _ => 0,
+    });
+    let operand_column = op_column + op_width + 1;
+    let code = |label: &str, op: &str, operands: &str| {
+        let mut code = format!("{:<width$}{}", label, op, width = op_column);
+        if !operands.is_empty() {
+            code = format!("{:<width$}{}", code, operands, width = operand_column);
+        }
+        code.trim_end().to_string()
+    };
+    let comment_column = widest(&lines, |line| match line {
+        Formatted::Code {
+            label,
+            op,
+            operands,
+            comment: Some(_),
+        } => {
+            let len = code(label, op, operands).chars().count();
+            if len <= COMMENT_LIMIT {
+                round_up(len + 1)
+            } else {
+                0
+            }
+        }
+        _ => 0,
+    });
+
+    let mut formatted = String::new();
+    for line in &lines {
+        let text = match line {
+            Formatted::Blank => String::new(),
+            Formatted::Comment(comment, true) => comment.clone(),
+            Formatted::Comment(comment, false) => format!("{:op_column$}{}", "", comment),
+            Formatted::Code {
+                label,
+                op,
+                operands,
+                comment,
+            } => {
+                let code = code(label, op, operands);
+                match comment {
+                    Some(comment) => {
+                        let width = comment_column.saturating_sub(1);
+                        let code = format!("{:<width$}", code, width = width);
+                        format!("{} {}", code, comment)
+                    }
+                    None => code,
+                }
+            }
+        };
+        formatted += &text;
+        formatted += "\n";
+    }
+    Ok(formatted)
+}
+
+/// Upper cased names of the macros defined in a file and the files it includes
+pub fn macro_names(path: &Path) -> HashSet<String> {
+    let mut names = HashSet::new();
+    let mut sources = Sources::new();
+    collect_macros(&mut sources, path, &mut names);
+    names
+}
+
+fn collect_macros(sources: &mut Sources, path: &Path, names: &mut HashSet<String>) {
+    if sources.files().iter().any(|file| file.path == path) {
+        return;
+    }
+    let Ok(file) = sources.load(path) else {
+        return;
+    };
+    let text = sources.file(file).text.clone();
+    for (i, line) in text.lines().enumerate() {
+        let Ok(tokens) = tokenize(line, file, i + 1) else {
+            continue;
+        };
+        match (
+            tokens.first().map(|t| &t.kind),
+            tokens.get(1).map(|t| &t.kind),
+        ) {
+            (Some(TokenKind::Directive(directive)), Some(TokenKind::Ident(name)))
+                if directive == "MACRO" =>
+            {
+                names.insert(name.to_ascii_uppercase());
+            }
+            (Some(TokenKind::Directive(directive)), Some(TokenKind::Str(include)))
+                if directive == "INCLUDE" =>
+            {
+                let dir = path.parent().unwrap_or(Path::new(""));
+                collect_macros(sources, &dir.join(include), names);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn format_line(line: &str, tokens: &[Token], macros: &HashSet<String>) -> Formatted {
+    let chars: Vec<char> = line.chars().collect();
+    let code_end = tokens
+        .last()
+        .map_or(0, |token| token.span.column - 1 + token.span.len);
+    let comment = chars[code_end..]
+        .iter()
+        .position(|c| *c == ';')
+        .map(|i| chars[code_end + i..].iter().collect::<String>())
+        .map(|comment| comment.trim_end().to_string());
+
+    if tokens.is_empty() {
+        return match comment {
+            Some(comment) => Formatted::Comment(comment, line.starts_with(';')),
+            None => Formatted::Blank,
+        };
+    }
+    let is_op = |token: Option<&Token>| match token.map(|token| &token.kind) {
+        Some(TokenKind::Directive(_)) => true,
+        Some(TokenKind::Ident(name)) => {
+            is_mnemonic(name) || macros.contains(&name.to_ascii_uppercase())
+        }
+        _ => false,
+    };
+
+    // a label is anything before the op, when the op is unknown a name in
+    // the first column is taken as the label as in the textbook
+    let mut position = 0;
+    if matches!(tokens[0].kind, TokenKind::Ident(_)) && !is_op(tokens.first()) {
+        let after = match tokens.get(1).map(|token| &token.kind) {
+            Some(TokenKind::Colon) => 2,
+            _ => 1,
+        };
+        let first_column = tokens[0].span.column == 1;
+        if is_op(tokens.get(after)) || tokens.len() == after || first_column {
+            position = after;
+        }
+    }
+    let label: String = tokens[..position]
+        .iter()
+        .map(|token| text(&chars, token))
+        .collect();
+
+    let op = match tokens.get(position) {
+        Some(token) => op_text(&chars, token),
+        None => String::new(),
+    };
+    let is_instruction = matches!(
+        tokens.get(position).map(|token| &token.kind),
+        Some(TokenKind::Ident(name)) if is_mnemonic(name)
+    );
+    let rest = tokens.get(position + 1..).unwrap_or_default();
+    let operands: Vec<String> = split_operands(rest)
+        .iter()
+        .map(|operand| operand_text(&chars, operand, is_instruction))
+        .collect();
+    Formatted::Code {
+        label,
+        op,
+        operands: operands.join(", "),
+        comment,
+    }
+}
+
+/// Tokens between commas, commas inside parentheses do not split
+fn split_operands(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut operands = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::LParen => depth += 1,
+            TokenKind::RParen => depth -= 1,
+            TokenKind::Comma if depth <= 0 => {
+                operands.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < tokens.len() {
+        operands.push(&tokens[start..]);
+    }
+    operands
+}
+
+fn op_text(chars: &[char], token: &Token) -> String {
+    match &token.kind {
+        TokenKind::Directive(name) => format!(".{}", name),
+        TokenKind::Ident(name) if is_mnemonic(name) => {
+            let upper = name.to_ascii_uppercase();
+            match upper.strip_prefix("BR") {
+                Some(flags) if !flags.is_empty() || upper == "BR" => {
+                    format!("BR{}", flags.to_ascii_lowercase())
+                }
+                _ => upper,
+            }
+        }
+        _ => text(chars, token),
+    }
+}
+
+/// An operand with its numbers and registers normalized, an instruction
+/// operand that is a decimal number is an immediate and gets a #
+fn operand_text(chars: &[char], tokens: &[Token], is_instruction: bool) -> String {
+    let mut operand = String::new();
+    let mut previous: Option<&Token> = None;
+    for (i, token) in tokens.iter().enumerate() {
+        let word = |token: &Token| {
+            matches!(
+                token.kind,
+                TokenKind::Ident(_)
+                    | TokenKind::Number(_)
+                    | TokenKind::Str(_)
+                    | TokenKind::Directive(_)
+            )
+        };
+        if previous.is_some_and(|previous| word(previous) && word(token)) {
+            operand += " ";
+        }
+        let before = (token.span.column >= 2).then(|| chars[token.span.column - 2]);
+        let mut text = match &token.kind {
+            TokenKind::Ident(name) if is_register(name) => name.to_ascii_uppercase(),
+            TokenKind::Number(_) => number_text(&text(chars, token)),
+            _ => text(chars, token),
+        };
+        let hex = matches!(token.kind, TokenKind::Number(_)) && text.starts_with('x');
+        let immediate = is_instruction
+            && (i == 0 || i == 1 && tokens[0].kind == TokenKind::Minus)
+            && matches!(text.chars().next(), Some('0'..='9'));
+        if immediate && i == 1 {
+            // -5 is written #-5
+            operand.pop();
+            text = format!("#-{}", text);
+        } else if immediate || before == Some('#') && !text.starts_with('#') && !hex {
+            text = format!("#{}", text);
+        }
+        operand += &text;
+        previous = Some(token);
+    }
+    operand
+}
+
+/// xFF for hex, #10 stays #10, the rest as written
+fn number_text(text: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let hex = lower
+        .strip_prefix("0x")
+        .or_else(|| lower.strip_prefix('x'))
+        .filter(|digits| !digits.is_empty());
+    match hex {
+        Some(digits) => format!("x{}", digits.to_ascii_uppercase()),
+        None if lower.starts_with("0b") => lower,
+        None => text.to_string(),
+    }
+}
+
+fn is_register(name: &str) -> bool {
+    matches!(name.as_bytes(), [b'r' | b'R', b'0'..=b'7'])
+}
+
+/// The token as written
+fn text(chars: &[char], token: &Token) -> String {
+    let start = token.span.column - 1;
+    chars[start..start + token.span.len].iter().collect()
+}
+
+fn widest(lines: &[Formatted], width: impl Fn(&Formatted) -> usize) -> usize {
+    lines.iter().map(width).max().unwrap_or(0)
+}
+
+fn round_up(column: usize) -> usize {
+    column.div_ceil(TAB_WIDTH) * TAB_WIDTH
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::formatter::format_source;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_format_aligns_and_normalizes() {
+        let text = "; counter
+  .orig 0X3000
+loop: add r1,r1,5 ; bump
+ ld r0 , 0x1f
+    ; indented
+msg .stringz \"a;b\" ;text
+ brnz loop
+  print 'A'
+ .fill #SIZE*2
+";
+        let macros = HashSet::from(["PRINT".to_string()]);
+        let expected = "; counter
+        .ORIG    x3000
+loop:   ADD      R1, R1, #5 ; bump
+        LD       R0, x1F
+        ; indented
+msg     .STRINGZ \"a;b\"      ;text
+        BRnz     loop
+        print    'A'
+        .FILL    #SIZE*2
+";
+        let formatted = format_source(text, 0, &macros).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(format_source(&formatted, 0, &macros).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_labels_and_immediates() {
+        let text = "START\n\tSUB R1, R1, -3\n    LONGER_LABEL .FILL 10\n";
+        let expected = "START\n                SUB   R1, R1, #-3\nLONGER_LABEL    .FILL 10\n";
+        assert_eq!(format_source(text, 0, &HashSet::new()).unwrap(), expected);
+
+        let error = format_source("  ADD R1, R1, #1x\n", 0, &HashSet::new()).unwrap_err();
+        assert_eq!(error.message, "invalid decimal number");
+    }
+}
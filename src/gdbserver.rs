@@ -0,0 +1,630 @@
+use crate::vm::{Register, MEMORY_SIZE, REGISTER_COUNT, VM};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+// GDB Remote Serial Protocol stub
+// see: https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+// Addresses in packets are lc3 word addresses, lengths are in bytes.
+// Every word (memory or register) is sent as 2 bytes, big endian
+// (same byte order as .obj files).
+//
+// Register numbers follow the Register enum: R0 - R7, PC, COND.
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="data_ptr"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="code_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="cond" bitsize="16" type="uint16"/>
+  </feature>
+</target>
+"#;
+
+// ctrl-c sent by gdb while the target is running
+const INTERRUPT: u8 = 0x03;
+
+// how many instructions to execute between checks for an interrupt
+const INTERRUPT_POLL_INTERVAL: usize = 1024;
+
+/// Byte stream the stub talks over (tcp or unix socket)
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Why the target stopped running
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    Step,
+    Breakpoint,
+    Watchpoint(u16),
+    Interrupted,
+    Halted,
+    // stopped by an error, e.g. an illegal opcode
+    Faulted,
+}
+
+impl StopReason {
+    fn reply(&self) -> String {
+        match self {
+            StopReason::Step | StopReason::Breakpoint => "S05".to_string(),
+            StopReason::Watchpoint(addr) => format!("T05watch:{:x};", addr),
+            StopReason::Interrupted => "S02".to_string(),
+            StopReason::Halted => "W00".to_string(),
+            StopReason::Faulted => "S04".to_string(),
+        }
+    }
+}
+
+pub struct GdbServer<'a, C: Connection> {
+    vm: &'a mut VM,
+    conn: C,
+    no_ack: bool,
+    // last packet sent, resent when gdb replies with a nack
+    last_packet: String,
+    breakpoints: HashSet<u16>,
+    // watched address -> last observed value
+    watchpoints: HashMap<u16, u16>,
+    // byte read while polling for an interrupt, the start of the next packet
+    pending: Option<u8>,
+}
+
+/// Listen on `addr` (host:port or unix:<path>) and serve a single gdb session
+pub fn serve(vm: &mut VM, addr: &str) -> io::Result<()> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        // a socket left over from an earlier session, never any other file
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} exists and is not a socket", path),
+                ))
+            }
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        eprintln!("gdbserver listening on {}", addr);
+        let (conn, _) = listener.accept()?;
+        GdbServer::new(vm, conn).serve()
+    } else {
+        let listener = TcpListener::bind(addr)?;
+        eprintln!("gdbserver listening on {}", addr);
+        let (conn, _) = listener.accept()?;
+        conn.set_nodelay(true)?;
+        GdbServer::new(vm, conn).serve()
+    }
+}
+
+impl<'a, C: Connection> GdbServer<'a, C> {
+    pub fn new(vm: &'a mut VM, conn: C) -> Self {
+        vm.set_running(true);
+        Self {
+            vm,
+            conn,
+            no_ack: false,
+            last_packet: String::new(),
+            breakpoints: HashSet::new(),
+            watchpoints: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    /// Process packets until gdb detaches, kills the target or disconnects
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle_packet(&packet) {
+                Some(reply) => self.write_packet(&reply)?,
+                None => {
+                    self.write_packet("OK")?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the reply for a packet, or None when the session should end
+    pub fn handle_packet(&mut self, packet: &str) -> Option<String> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.stop_reply(),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b's') => self.resume(true).reply(),
+            Some(b'c') => self.resume(false).reply(),
+            Some(b'Z') => self.insert_point(&packet[1..]),
+            Some(b'z') => self.remove_point(&packet[1..]),
+            Some(b'H') => "OK".to_string(),
+            Some(b'k') | Some(b'D') => return None,
+            Some(b'q') | Some(b'Q') => self.query(packet),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn stop_reply(&self) -> String {
+        if self.vm.is_running() {
+            "S05".to_string()
+        } else {
+            self.stopped().reply()
+        }
+    }
+
+    fn stopped(&self) -> StopReason {
+        match self.vm.error() {
+            Some(_) => StopReason::Faulted,
+            None => StopReason::Halted,
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string()
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_pair(args, ',') {
+                Some((offset, length)) => xfer_reply(TARGET_XML, offset, length),
+                None => "E01".to_string(),
+            }
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTER_COUNT as u16)
+            .map(|addr| format!("{:04x}", self.vm.reg(addr)))
+            .collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        match decode_words(data) {
+            Some(words) if words.len() == REGISTER_COUNT => {
+                for (addr, word) in words.into_iter().enumerate() {
+                    *self.vm.reg_mut(addr as u16) = word;
+                }
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        match u16::from_str_radix(args, 16) {
+            Ok(addr) if (addr as usize) < REGISTER_COUNT => format!("{:04x}", self.vm.reg(addr)),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(addr, value)| {
+            let addr = u16::from_str_radix(addr, 16).ok()?;
+            let value = decode_words(value)?;
+            Some((addr, value))
+        });
+        match parsed {
+            Some((addr, value)) if (addr as usize) < REGISTER_COUNT && value.len() == 1 => {
+                *self.vm.reg_mut(addr) = value[0];
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&mut self, args: &str) -> String {
+        match parse_pair(args, ',') {
+            // at most all of memory, 2 bytes per word
+            Some((addr, length)) if length <= MEMORY_SIZE * 2 => (0..length.div_ceil(2))
+                .map(|i| format!("{:04x}", *self.vm.mem_mut(addr.wrapping_add(i as u16))))
+                .collect::<String>()[..length * 2]
+                .to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+            let (addr, length) = parse_pair(range, ',')?;
+            let words = decode_words(data)?;
+            (words.len() * 2 == length).then_some((addr, words))
+        });
+        match parsed {
+            Some((addr, words)) => {
+                for (i, word) in words.into_iter().enumerate() {
+                    *self.vm.mem_mut(addr.wrapping_add(i as u16)) = word;
+                }
+                "OK".to_string()
+            }
+            None => "E01".to_string(),
+        }
+    }
+
+    fn insert_point(&mut self, args: &str) -> String {
+        match parse_point(args) {
+            // software breakpoint
+            Some((0, addr)) => {
+                self.breakpoints.insert(addr);
+                "OK".to_string()
+            }
+            // write watchpoint
+            Some((2, addr)) => {
+                let value = *self.vm.mem_mut(addr);
+                self.watchpoints.insert(addr, value);
+                "OK".to_string()
+            }
+            Some(_) => String::new(),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn remove_point(&mut self, args: &str) -> String {
+        match parse_point(args) {
+            Some((0, addr)) => {
+                self.breakpoints.remove(&addr);
+                "OK".to_string()
+            }
+            Some((2, addr)) => {
+                self.watchpoints.remove(&addr);
+                "OK".to_string()
+            }
+            Some(_) => String::new(),
+            None => "E01".to_string(),
+        }
+    }
+
+    /// Execute instructions until a stop condition is hit
+    pub fn resume(&mut self, single_step: bool) -> StopReason {
+        let mut executed = 0;
+        loop {
+            if !self.vm.is_running() {
+                return self.stopped();
+            }
+
+            self.vm.step();
+            executed += 1;
+
+            if let Some(addr) = self.changed_watchpoint() {
+                return StopReason::Watchpoint(addr);
+            }
+            if !self.vm.is_running() {
+                return self.stopped();
+            }
+            if single_step {
+                return StopReason::Step;
+            }
+            if self.breakpoints.contains(&self.vm.reg(Register::PC.into())) {
+                return StopReason::Breakpoint;
+            }
+            if executed % INTERRUPT_POLL_INTERVAL == 0 && self.interrupted() {
+                return StopReason::Interrupted;
+            }
+        }
+    }
+
+    /// Watchpoints compare values, a store of the same value is not reported
+    fn changed_watchpoint(&mut self) -> Option<u16> {
+        for (addr, last_value) in self.watchpoints.iter_mut() {
+            let value = *self.vm.mem_mut(*addr);
+            if value != *last_value {
+                *last_value = value;
+                return Some(*addr);
+            }
+        }
+        None
+    }
+
+    fn interrupted(&mut self) -> bool {
+        if self.conn.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut buffer = [0_u8; 1];
+        let result = self.conn.read(&mut buffer);
+        let _ = self.conn.set_nonblocking(false);
+        match result {
+            Ok(1) if buffer[0] == INTERRUPT => true,
+            Ok(1) => {
+                self.pending = Some(buffer[0]);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Read the next packet payload, None when the connection is closed
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let mut payload = vec![];
+            // skip acks and stray interrupts until the start of a packet
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(b'-') if !self.no_ack => {
+                        self.conn.write_all(self.last_packet.as_bytes())?;
+                        self.conn.flush()?;
+                    }
+                    Some(_) => continue,
+                    None => return Ok(None),
+                }
+            }
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => payload.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0_u8; 2];
+            self.conn.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if self.no_ack {
+                return Ok(Some(String::from_utf8_lossy(&payload).to_string()));
+            }
+            if expected == Some(checksum_of(&payload)) {
+                self.conn.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&payload).to_string()));
+            }
+            self.conn.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, payload: &str) -> io::Result<()> {
+        self.last_packet = format!("${}#{:02x}", payload, checksum_of(payload.as_bytes()));
+        self.conn.write_all(self.last_packet.as_bytes())?;
+        self.conn.flush()
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.take() {
+            return Ok(Some(byte));
+        }
+        let mut buffer = [0_u8; 1];
+        match self.conn.read(&mut buffer)? {
+            0 => Ok(None),
+            _ => Ok(Some(buffer[0])),
+        }
+    }
+}
+
+fn checksum_of(payload: &[u8]) -> u8 {
+    payload
+        .iter()
+        .fold(0_u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Parse "a,b" where both sides are hex
+fn parse_pair(args: &str, separator: char) -> Option<(u16, usize)> {
+    let (first, second) = args.split_once(separator)?;
+    let first = u16::from_str_radix(first, 16).ok()?;
+    let second = usize::from_str_radix(second, 16).ok()?;
+    Some((first, second))
+}
+
+/// Parse "type,addr,kind" from a Z or z packet
+fn parse_point(args: &str) -> Option<(u8, u16)> {
+    let mut parts = args.split(',');
+    let kind = parts.next()?.parse().ok()?;
+    let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+    Some((kind, addr))
+}
+
+/// Decode a hex string into big endian words
+fn decode_words(data: &str) -> Option<Vec<u16>> {
+    if !data.len().is_multiple_of(4) {
+        return None;
+    }
+    (0..data.len())
+        .step_by(4)
+        .map(|i| u16::from_str_radix(data.get(i..i + 4)?, 16).ok())
+        .collect()
+}
+
+fn xfer_reply(document: &str, offset: u16, length: usize) -> String {
+    let offset = offset as usize;
+    if offset >= document.len() {
+        return "l".to_string();
+    }
+    let end = (offset + length).min(document.len());
+    let prefix = if end == document.len() { "l" } else { "m" };
+    format!("{}{}", prefix, &document[offset..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gdbserver::{checksum_of, Connection, GdbServer, StopReason};
+    use crate::vm::{Register, VM};
+    use std::io;
+    use std::io::{Read, Write};
+
+    /// In memory connection, reads from `input` and collects writes in `output`
+    struct MockConnection {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MockConnection {
+        fn new(input: &[u8]) -> Self {
+            Self {
+                input: io::Cursor::new(input.to_vec()),
+                output: vec![],
+            }
+        }
+    }
+
+    impl Read for MockConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for MockConnection {
+        fn set_nonblocking(&self, _: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packet(payload: &str) -> String {
+        format!("${}#{:02x}", payload, checksum_of(payload.as_bytes()))
+    }
+
+    #[test]
+    fn test_register_access() {
+        let mut vm = VM::init();
+        *vm.reg_mut(Register::R1.into()) = 0xabcd;
+        let mut server = GdbServer::new(&mut vm, MockConnection::new(&[]));
+
+        let registers = server.handle_packet("g").unwrap();
+        assert_eq!(registers.len(), 40);
+        assert_eq!(&registers[4..8], "abcd");
+
+        assert_eq!(server.handle_packet("P8=3000").unwrap(), "OK");
+        assert_eq!(server.handle_packet("p8").unwrap(), "3000");
+        assert_eq!(server.handle_packet("pa").unwrap(), "E01");
+    }
+
+    #[test]
+    fn test_memory_access() {
+        let mut vm = VM::init();
+        let mut server = GdbServer::new(&mut vm, MockConnection::new(&[]));
+
+        assert_eq!(server.handle_packet("M3000,4:1234abcd").unwrap(), "OK");
+        assert_eq!(server.handle_packet("m3000,4").unwrap(), "1234abcd");
+        assert_eq!(server.handle_packet("m3001,2").unwrap(), "abcd");
+        assert_eq!(server.handle_packet("M3000,4:12").unwrap(), "E01");
+
+        let all = server.handle_packet("m0,20000").unwrap();
+        assert_eq!(all.len(), 0x40000);
+        assert_eq!(&all[0x3000 * 4..0x3000 * 4 + 8], "1234abcd");
+        assert_eq!(server.handle_packet("m0,20002").unwrap(), "E01");
+        assert_eq!(server.handle_packet("mffff,10000000").unwrap(), "E01");
+    }
+
+    #[test]
+    fn test_step_and_breakpoints() {
+        let mut vm = VM::init();
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        // ADD R0, R0, #1 (x3)
+        *vm.mem_mut(0x3000) = 0x1021;
+        *vm.mem_mut(0x3001) = 0x1021;
+        *vm.mem_mut(0x3002) = 0x1021;
+        // ST R0, #1
+        *vm.mem_mut(0x3003) = 0x3001;
+        // HALT
+        *vm.mem_mut(0x3004) = 0xf025;
+
+        let mut server = GdbServer::new(&mut vm, MockConnection::new(&[]));
+        assert_eq!(server.handle_packet("s").unwrap(), "S05");
+        assert_eq!(server.handle_packet("p8").unwrap(), "3001");
+
+        assert_eq!(server.handle_packet("Z0,3002,2").unwrap(), "OK");
+        assert_eq!(server.handle_packet("Z2,3005,2").unwrap(), "OK");
+        assert_eq!(server.resume(false), StopReason::Breakpoint);
+        assert_eq!(server.handle_packet("p8").unwrap(), "3002");
+
+        assert_eq!(server.resume(false), StopReason::Watchpoint(0x3005));
+        assert_eq!(server.handle_packet("m3005,2").unwrap(), "0003");
+
+        assert_eq!(server.handle_packet("z0,3002,2").unwrap(), "OK");
+        assert_eq!(server.handle_packet("c").unwrap(), "W00");
+    }
+
+    #[test]
+    fn test_fault_is_a_signal() {
+        let mut vm = VM::init();
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        // RTI, not allowed in user mode
+        *vm.mem_mut(0x3000) = 0x8000;
+
+        let mut server = GdbServer::new(&mut vm, MockConnection::new(&[]));
+        assert_eq!(server.handle_packet("c").unwrap(), "S04");
+        assert_eq!(server.handle_packet("?").unwrap(), "S04");
+        assert_eq!(server.handle_packet("p8").unwrap(), "3000");
+    }
+
+    #[test]
+    fn test_interrupt_poll_keeps_packets() {
+        let input = packet("g");
+        let mut vm = VM::init();
+        let mut server = GdbServer::new(&mut vm, MockConnection::new(input.as_bytes()));
+
+        assert!(!server.interrupted());
+        assert_eq!(server.read_packet().unwrap().unwrap(), "g");
+    }
+
+    #[test]
+    fn test_packet_framing() {
+        let input = format!("+{}{}", packet("qAttached"), packet("k"));
+        let mut vm = VM::init();
+        let mut server = GdbServer::new(&mut vm, MockConnection::new(input.as_bytes()));
+        server.serve().unwrap();
+
+        let output = String::from_utf8(server.conn.output.clone()).unwrap();
+        assert_eq!(output, format!("+{}+{}", packet("1"), packet("OK")));
+    }
+
+    #[test]
+    fn test_target_description() {
+        let mut vm = VM::init();
+        let mut server = GdbServer::new(&mut vm, MockConnection::new(&[]));
+
+        let first = server
+            .handle_packet("qXfer:features:read:target.xml:0,a")
+            .unwrap();
+        assert_eq!(first, "m<?xml vers");
+        let rest = server
+            .handle_packet("qXfer:features:read:target.xml:a,1000")
+            .unwrap();
+        assert!(rest.starts_with('l'));
+        assert!(rest.ends_with("</target>\n"));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::source::{Diagnostic, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Ident(String),
+    // .ORIG, .FILL, ... upper cased, without the dot
+    Directive(String),
+    Number(i32),
+    Str(String),
+    Comma,
+    Colon,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    LParen,
+    RParen,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn ident(&self) -> Option<&str> {
+        match &self.kind {
+            TokenKind::Ident(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Split one source line into tokens, everything after ; is a comment
+///
+/// Numbers are #decimal, decimal, xHEX, 0xHEX, 0bBINARY or a 'c'haracter.
+/// An identifier that reads as x followed by hex digits is a number, as in lc3as.
+pub fn tokenize(text: &str, file: usize, line: usize) -> Result<Vec<Token>, Diagnostic> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let span = |end: usize| Span {
+            file,
+            line,
+            column: start + 1,
+            len: end - start,
+        };
+        let c = chars[i];
+        let kind = match c {
+            ';' => break,
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ',' => single(&mut i, TokenKind::Comma),
+            ':' => single(&mut i, TokenKind::Colon),
+            '+' => single(&mut i, TokenKind::Plus),
+            '-' => single(&mut i, TokenKind::Minus),
+            '*' => single(&mut i, TokenKind::Star),
+            '/' => single(&mut i, TokenKind::Slash),
+            '%' => single(&mut i, TokenKind::Percent),
+            '(' => single(&mut i, TokenKind::LParen),
+            ')' => single(&mut i, TokenKind::RParen),
+            '"' => {
+                i += 1;
+                let mut value = String::new();
+                loop {
+                    match chars.get(i) {
+                        None => return Err(Diagnostic::new(span(i), "unterminated string")),
+                        Some('"') => break,
+                        Some('\\') => {
+                            value.push(escape(chars.get(i + 1).copied()).ok_or_else(|| {
+                                Diagnostic::new(span(i + 2), "unknown escape sequence")
+                            })?);
+                            i += 2;
+                        }
+                        Some(c) => {
+                            value.push(*c);
+                            i += 1;
+                        }
+                    }
+                }
+                i += 1;
+                TokenKind::Str(value)
+            }
+            '\'' => {
+                let (value, len) = match (chars.get(i + 1), chars.get(i + 2), chars.get(i + 3)) {
+                    (Some('\\'), Some(c), Some('\'')) => (escape(Some(*c)), 4),
+                    (Some(c), Some('\''), _) if *c != '\\' => (Some(*c), 3),
+                    _ => (None, 1),
+                };
+                let value = value
+                    .ok_or_else(|| Diagnostic::new(span(i + 1), "invalid character literal"))?;
+                i += len;
+                TokenKind::Number(value as i32)
+            }
+            // `#` before anything but a number only marks an immediate, as in #SIZE*2
+            '#' if !matches!(
+                (chars.get(i + 1), chars.get(i + 2)),
+                (Some('0'..='9'), _) | (Some('-'), Some('0'..='9'))
+            ) =>
+            {
+                i += 1;
+                continue;
+            }
+            '#' => {
+                i += 1;
+                if chars.get(i) == Some(&'-') {
+                    i += 1;
+                }
+                while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+                let text: String = chars[start + 1..i].iter().collect();
+                let value = text
+                    .parse::<i32>()
+                    .map_err(|_| Diagnostic::new(span(i), "invalid decimal number"))?;
+                TokenKind::Number(value)
+            }
+            '.' => {
+                i += 1;
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                let name: String = chars[start + 1..i].iter().collect();
+                if name.is_empty() {
+                    return Err(Diagnostic::new(span(i), "expected a directive after ."));
+                }
+                TokenKind::Directive(name.to_ascii_uppercase())
+            }
+            c if c.is_ascii_digit() || is_ident_start(c) => {
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                match number(&word) {
+                    Some(Ok(value)) => TokenKind::Number(value),
+                    Some(Err(())) => {
+                        return Err(Diagnostic::new(
+                            span(i),
+                            format!("invalid number '{}'", word),
+                        ))
+                    }
+                    None => TokenKind::Ident(word),
+                }
+            }
+            c => {
+                return Err(Diagnostic::new(
+                    span(i + 1),
+                    format!("unexpected character '{}'", c),
+                ))
+            }
+        };
+        tokens.push(Token {
+            kind,
+            span: span(i),
+        });
+    }
+    Ok(tokens)
+}
+
+fn single(i: &mut usize, kind: TokenKind) -> TokenKind {
+    *i += 1;
+    kind
+}
+
+fn escape(c: Option<char>) -> Option<char> {
+    match c? {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        'e' => Some('\x1b'),
+        '0' => Some('\0'),
+        c @ ('\\' | '"' | '\'') => Some(c),
+        _ => None,
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '@'
+}
+
+pub fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '@'
+}
+
+/// None if the word is an identifier, Some(Err) for a malformed number
+fn number(word: &str) -> Option<Result<i32, ()>> {
+    let parse = |digits: &str, radix| i32::from_str_radix(digits, radix).map_err(|_| ());
+    let first = word.chars().next()?;
+    if first.is_ascii_digit() {
+        let lower = word.to_ascii_lowercase();
+        return Some(if let Some(hex) = lower.strip_prefix("0x") {
+            parse(hex, 16)
+        } else if let Some(binary) = lower.strip_prefix("0b") {
+            parse(binary, 2)
+        } else {
+            parse(word, 10)
+        });
+    }
+    let hex = word.strip_prefix(['x', 'X'])?;
+    if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Some(parse(hex, 16));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::{tokenize, TokenKind};
+
+    fn kinds(text: &str) -> Vec<TokenKind> {
+        tokenize(text, 0, 1)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize_line() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("LOOP: ADD R1, R1, #-1 ; count down"),
+            vec![
+                Ident("LOOP".to_string()),
+                Colon,
+                Ident("ADD".to_string()),
+                Ident("R1".to_string()),
+                Comma,
+                Ident("R1".to_string()),
+                Comma,
+                Number(-1)
+            ]
+        );
+        assert_eq!(
+            kinds(".orig x3000"),
+            vec![Directive("ORIG".to_string()), Number(0x3000)]
+        );
+        assert_eq!(
+            kinds("LD R0, DATA+2*x10 'a' 0b101 xyz"),
+            vec![
+                Ident("LD".to_string()),
+                Ident("R0".to_string()),
+                Comma,
+                Ident("DATA".to_string()),
+                Plus,
+                Number(2),
+                Star,
+                Number(16),
+                Number(97),
+                Number(5),
+                Ident("xyz".to_string())
+            ]
+        );
+        assert_eq!(
+            kinds("#SIZE-#1"),
+            vec![Ident("SIZE".to_string()), Minus, Number(1)]
+        );
+        assert_eq!(
+            kinds(r#".STRINGZ "hi\n;""#),
+            vec![Directive("STRINGZ".to_string()), Str("hi\n;".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        let error = tokenize("  .STRINGZ \"oops", 0, 3).unwrap_err();
+        assert_eq!((error.span.line, error.span.column), (3, 12));
+        assert_eq!(error.message, "unterminated string");
+        assert!(tokenize("ADD R1, R1, #1x", 0, 1).is_err());
+        assert!(tokenize("ADD R1, R1, $", 0, 1).is_err());
+    }
+}
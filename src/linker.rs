@@ -0,0 +1,322 @@
+use crate::decode_instruction::{OFFSET11, OFFSET6, OFFSET9};
+use crate::loader::Program;
+use crate::object::{Binding, ObjectFile, RelocationKind};
+use crate::opcodes::mask;
+use crate::symbols::SymbolTable;
+use std::collections::HashMap;
+
+/// Where the linker puts sections without a fixed origin
+pub struct Layout {
+    // section name -> address, sections sharing a name are laid out one after another
+    pub placements: HashMap<String, u16>,
+    // first address tried for every other section
+    pub base: u16,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            placements: HashMap::new(),
+            base: 0x3000,
+        }
+    }
+}
+
+/// Linked image, gaps between sections are zero filled
+pub struct Linked {
+    pub program: Program,
+    pub symbols: SymbolTable,
+}
+
+/// Place every section, resolve symbols across objects and patch relocations
+///
+/// Objects are (name, object) pairs, the name is only used in error messages.
+pub fn link(objects: &[(String, ObjectFile)], layout: &Layout) -> Result<Linked, String> {
+    let addresses = place_sections(objects, layout)?;
+
+    let mut exports: HashMap<&str, (u16, &str)> = HashMap::new();
+    for (i, (name, object)) in objects.iter().enumerate() {
+        for symbol in object.exports() {
+            let addr = addresses[i][symbol.section as usize].wrapping_add(symbol.offset);
+            if let Some((_, other)) = exports.insert(&symbol.name, (addr, name)) {
+                return Err(format!(
+                    "{} is defined in both {} and {}",
+                    symbol.name, other, name
+                ));
+            }
+        }
+    }
+
+    let mut symbols = SymbolTable::new();
+    let mut exported: Vec<(&&str, &(u16, &str))> = exports.iter().collect();
+    exported.sort();
+    for (name, (addr, _)) in exported {
+        symbols.insert(name, *addr);
+    }
+
+    let mut image: Vec<(u16, Vec<u16>)> = vec![];
+    for (i, (name, object)) in objects.iter().enumerate() {
+        let mut values = vec![];
+        for symbol in &object.symbols {
+            let value = match symbol.binding {
+                Binding::Import => match exports.get(symbol.name.as_str()) {
+                    Some((addr, _)) => *addr,
+                    None => {
+                        return Err(format!(
+                            "undefined symbol {} referenced in {}",
+                            symbol.name, name
+                        ))
+                    }
+                },
+                _ => addresses[i][symbol.section as usize].wrapping_add(symbol.offset),
+            };
+            if symbol.binding == Binding::Local && symbols.address(&symbol.name).is_none() {
+                symbols.insert(&symbol.name, value);
+            }
+            values.push(value);
+        }
+
+        let mut sections: Vec<Vec<u16>> = object.sections.iter().map(|s| s.words.clone()).collect();
+        for relocation in &object.relocations {
+            let section = relocation.section as usize;
+            let place = addresses[i][section].wrapping_add(relocation.offset);
+            let target = values[relocation.symbol as usize].wrapping_add(relocation.addend as u16);
+            let word = &mut sections[section][relocation.offset as usize];
+            *word = patch(*word, relocation.kind, place, target).ok_or_else(|| {
+                format!(
+                    "{}: {:?} to {} out of range at x{:04X}",
+                    name, relocation.kind, object.symbols[relocation.symbol as usize].name, place
+                )
+            })?;
+        }
+
+        for (section, words) in sections.into_iter().enumerate() {
+            image.push((addresses[i][section], words));
+        }
+    }
+
+    Ok(Linked {
+        program: flatten(image),
+        symbols,
+    })
+}
+
+/// Address of every section, indexed by object then section
+fn place_sections(
+    objects: &[(String, ObjectFile)],
+    layout: &Layout,
+) -> Result<Vec<Vec<u16>>, String> {
+    let mut addresses: Vec<Vec<Option<u16>>> = objects
+        .iter()
+        .map(|(_, object)| object.sections.iter().map(|s| s.origin).collect())
+        .collect();
+    let mut used: Vec<(u32, u32, String)> = vec![];
+    let owner = |i: usize, section: usize| {
+        format!("{}:{}", objects[i].0, objects[i].1.sections[section].name)
+    };
+
+    // fixed origins first, then named placements, then everything else from the base
+    for (i, (_, object)) in objects.iter().enumerate() {
+        for (j, section) in object.sections.iter().enumerate() {
+            if let Some(origin) = section.origin {
+                claim(&mut used, origin, section.words.len(), owner(i, j))?;
+            }
+        }
+    }
+    let mut cursors = layout.placements.clone();
+    for (i, (_, object)) in objects.iter().enumerate() {
+        for (j, section) in object.sections.iter().enumerate() {
+            if addresses[i][j].is_some() {
+                continue;
+            }
+            if let Some(cursor) = cursors.get_mut(&section.name) {
+                claim(&mut used, *cursor, section.words.len(), owner(i, j))?;
+                addresses[i][j] = Some(*cursor);
+                *cursor = cursor.wrapping_add(section.words.len() as u16);
+            }
+        }
+    }
+    let mut cursor = layout.base as u32;
+    for (i, (_, object)) in objects.iter().enumerate() {
+        for (j, section) in object.sections.iter().enumerate() {
+            if addresses[i][j].is_some() {
+                continue;
+            }
+            // skip past whatever is already in the way
+            let len = section.words.len() as u32;
+            while let Some((_, end, _)) = used
+                .iter()
+                .find(|(s, e, _)| cursor < *e && *s < cursor + len.max(1))
+            {
+                cursor = *end;
+            }
+            if cursor > u16::MAX as u32 {
+                return Err(format!("no room left for {}", owner(i, j)));
+            }
+            claim(&mut used, cursor as u16, section.words.len(), owner(i, j))?;
+            addresses[i][j] = Some(cursor as u16);
+            cursor += len;
+        }
+    }
+
+    Ok(addresses
+        .into_iter()
+        .map(|sections| sections.into_iter().map(|addr| addr.unwrap()).collect())
+        .collect())
+}
+
+/// Reserve [start, start + len) for owner, used holds (start, end exclusive, owner)
+fn claim(
+    used: &mut Vec<(u32, u32, String)>,
+    start: u16,
+    len: usize,
+    owner: String,
+) -> Result<(), String> {
+    let (start, end) = (start as u32, start as u32 + len as u32);
+    if end > 0x10000 {
+        return Err(format!("{} does not fit below xFFFF", owner));
+    }
+    if let Some((_, _, other)) = used.iter().find(|(s, e, _)| start < *e && *s < end) {
+        return Err(format!("{} overlaps {}", owner, other));
+    }
+    used.push((start, end, owner));
+    Ok(())
+}
+
+/// Word with the relocated field filled in, None if the value does not fit
+fn patch(word: u16, kind: RelocationKind, place: u16, target: u16) -> Option<u16> {
+    let pc_relative = target.wrapping_sub(place.wrapping_add(1)) as i16;
+    let (value, bits) = match kind {
+        RelocationKind::Offset9 => (pc_relative, OFFSET9),
+        RelocationKind::Offset11 => (pc_relative, OFFSET11),
+        RelocationKind::Offset6 => (target as i16, OFFSET6),
+        RelocationKind::Word => return Some(target),
+    };
+    let limit = 1_i16 << (bits - 1);
+    if value < -limit || value >= limit {
+        return None;
+    }
+    Some(word & !mask(bits) | value as u16 & mask(bits))
+}
+
+/// Single image from the lowest to the highest placed address
+fn flatten(mut sections: Vec<(u16, Vec<u16>)>) -> Program {
+    sections.retain(|(_, words)| !words.is_empty());
+    sections.sort_by_key(|(origin, _)| *origin);
+    let Some(origin) = sections.first().map(|(origin, _)| *origin) else {
+        return Program {
+            origin: 0x3000,
+            words: vec![],
+        };
+    };
+
+    let mut words = vec![];
+    for (start, section) in sections {
+        words.resize((start - origin) as usize, 0);
+        words.extend(section);
+    }
+    Program { origin, words }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::linker::{link, Layout};
+    use crate::object::{Binding, ObjectFile, Relocation, RelocationKind, Section, Symbol};
+
+    fn symbol(name: &str, binding: Binding, offset: u16) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            binding,
+            section: 0,
+            offset,
+        }
+    }
+
+    fn relocation(offset: u16, kind: RelocationKind, symbol: u16) -> Relocation {
+        Relocation {
+            section: 0,
+            offset,
+            kind,
+            symbol,
+            addend: 0,
+        }
+    }
+
+    /// main calls PRINT_NUM from the library and loads a word of its data
+    fn objects() -> Vec<(String, ObjectFile)> {
+        let main = ObjectFile {
+            sections: vec![Section {
+                name: "text".to_string(),
+                origin: None,
+                words: vec![
+                    0x4800, // JSR PRINT_NUM
+                    0x2000, // LD R0, COUNT
+                    0xf025, // HALT
+                    0x0000, // .FILL PRINT_NUM
+                ],
+            }],
+            symbols: vec![
+                symbol("MAIN", Binding::Export, 0),
+                symbol("PRINT_NUM", Binding::Import, 0),
+                symbol("COUNT", Binding::Import, 0),
+            ],
+            relocations: vec![
+                relocation(0, RelocationKind::Offset11, 1),
+                relocation(1, RelocationKind::Offset9, 2),
+                relocation(3, RelocationKind::Word, 1),
+            ],
+        };
+        let library = ObjectFile {
+            sections: vec![Section {
+                name: "text".to_string(),
+                origin: None,
+                words: vec![
+                    0xc1c0, // PRINT_NUM RET
+                    0x0007, // COUNT .FILL 7
+                ],
+            }],
+            symbols: vec![
+                symbol("PRINT_NUM", Binding::Export, 0),
+                symbol("COUNT", Binding::Export, 1),
+            ],
+            relocations: vec![],
+        };
+        vec![
+            ("main.lobj".to_string(), main),
+            ("math.lobj".to_string(), library),
+        ]
+    }
+
+    #[test]
+    fn test_link_resolves_across_objects() {
+        let linked = link(&objects(), &Layout::default()).unwrap();
+        assert_eq!(linked.program.origin, 0x3000);
+        assert_eq!(
+            linked.program.words,
+            vec![0x4803, 0x2003, 0xf025, 0x3004, 0xc1c0, 0x0007]
+        );
+        assert_eq!(linked.symbols.address("PRINT_NUM"), Some(0x3004));
+        assert_eq!(linked.symbols.address("MAIN"), Some(0x3000));
+    }
+
+    #[test]
+    fn test_link_placement_and_errors() {
+        let mut objects = objects();
+        objects[1].1.sections[0].name = "lib".to_string();
+        let mut layout = Layout::default();
+        layout.placements.insert("lib".to_string(), 0x3100);
+        let linked = link(&objects, &layout).unwrap();
+        assert_eq!(linked.program.words.len(), 0x102);
+        assert_eq!(linked.program.words[0x100], 0xc1c0);
+        assert_eq!(linked.symbols.address("COUNT"), Some(0x3101));
+
+        // LD offset9 can not reach x3400
+        layout.placements.insert("lib".to_string(), 0x3400);
+        let error = link(&objects, &layout).err().unwrap();
+        assert_eq!(error, "main.lobj: Offset9 to COUNT out of range at x3001");
+
+        objects.pop();
+        let error = link(&objects, &Layout::default()).err().unwrap();
+        assert_eq!(error, "undefined symbol PRINT_NUM referenced in main.lobj");
+    }
+}
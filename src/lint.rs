@@ -0,0 +1,522 @@
+use crate::assembler::{Analysis, Listed};
+use crate::decode_instruction::{decode_instruction, DecodedInstruction};
+use crate::display::trap_name;
+use crate::expression::Base;
+use crate::source::{Diagnostic, Sources, Span};
+use crate::vm::Opcode;
+use std::collections::{HashMap, HashSet};
+
+// registers a subroutine has to give back unchanged, R0 returns a value,
+// R6 is the stack pointer and R7 the return address
+const CALLEE_SAVED: [u16; 5] = [1, 2, 3, 4, 5];
+const R0: u16 = 0;
+const R7: u16 = 7;
+const HALT: u16 = 0xf025;
+const PUTS: u16 = 0xf022;
+
+/// A word of the program and the statement it came from
+struct Word<'a> {
+    addr: u16,
+    word: u16,
+    listed: &'a Listed,
+}
+
+impl Word<'_> {
+    fn is_code(&self) -> bool {
+        !self.listed.data
+    }
+
+    fn instruction(&self) -> DecodedInstruction {
+        decode_instruction(self.word)
+    }
+
+    /// Address a PC relative branch, call or LEA refers to
+    fn target(&self) -> Option<u16> {
+        let instruction = self.instruction();
+        let relative = match instruction.opcode {
+            Opcode::BR => instruction.nzp != 0,
+            Opcode::JSR => instruction.flag == 1,
+            Opcode::LEA | Opcode::LD | Opcode::LDI | Opcode::ST | Opcode::STI => true,
+            _ => false,
+        };
+        relative.then(|| self.addr.wrapping_add(1).wrapping_add(instruction.offset))
+    }
+}
+
+/// Check an assembled program for the usual LC-3 mistakes
+///
+/// - a conditional BR testing condition codes the instruction before it did not set
+/// - a JSR in a subroutine before R7 was saved
+/// - a subroutine changing one of R1 to R5 without saving it
+/// - a BR without n, z or p, which never branches
+/// - no HALT
+/// - code after an unconditional BR, JMP, RET or HALT that nothing jumps to
+/// - PUTS printing from a label that is not a .STRINGZ
+pub fn lint(analysis: &Analysis, sources: &Sources) -> Vec<Diagnostic> {
+    let mut words: Vec<Word> = analysis
+        .listing
+        .iter()
+        .flat_map(|listed| {
+            (listed.address..)
+                .zip(&listed.words)
+                .map(move |(addr, word)| Word {
+                    addr,
+                    word: *word,
+                    listed,
+                })
+        })
+        .collect();
+    words.sort_by_key(|word| word.addr);
+
+    let mut labels = HashMap::new();
+    for definition in &analysis.definitions {
+        let is_label = definition.value.base != Base::Section
+            && analysis.listing.iter().any(|listed| {
+                listed.line.file == definition.span.file
+                    && listed.line.line == definition.span.line
+                    && listed.address as i32 == definition.value.value
+            });
+        if is_label {
+            labels.insert(definition.value.value as u16, definition.name.as_str());
+        }
+    }
+    // everything that can be jumped to, also through an address in data
+    let mut targets: HashSet<u16> = words.iter().filter_map(|word| word.target()).collect();
+    targets.extend(labels.keys());
+    targets.extend(
+        words
+            .iter()
+            .filter(|word| !word.is_code())
+            .map(|word| word.word),
+    );
+
+    let mut linter = Linter {
+        sources,
+        index: words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| (word.addr, i))
+            .collect(),
+        words: &words,
+        labels,
+        targets,
+        diagnostics: vec![],
+    };
+    linter.stale_condition_codes();
+    linter.subroutines();
+    linter.empty_branches();
+    linter.missing_halt();
+    linter.unreachable_code();
+    linter.puts_without_string();
+    linter.diagnostics
+}
+
+struct Linter<'a> {
+    sources: &'a Sources,
+    words: &'a [Word<'a>],
+    // address -> index into words
+    index: HashMap<u16, usize>,
+    labels: HashMap<u16, &'a str>,
+    targets: HashSet<u16>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Linter<'a> {
+    /// The statement of a word, from its mnemonic to the end of the code
+    fn span(&self, word: &Word) -> Span {
+        let line = word.listed.line;
+        let text = self.sources.line(&line);
+        let code = text.split(';').next().unwrap_or_default();
+        let statement: String = code.chars().skip(word.listed.column - 1).collect();
+        Span {
+            column: word.listed.column,
+            len: statement.trim_end().chars().count(),
+            ..line
+        }
+    }
+
+    fn warn(&mut self, word: &Word, message: String, notes: Vec<(&Word, String)>) {
+        let mut diagnostic = Diagnostic::warning(self.span(word), message);
+        for (word, note) in notes {
+            diagnostic = diagnostic.with_note(self.span(word), note);
+        }
+        if let Some(call) = word.listed.call {
+            diagnostic = diagnostic.with_note(call, "in this macro call");
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// The code word right before this one, if execution can only come from there
+    fn previous(&self, i: usize) -> Option<&'a Word<'a>> {
+        let words = self.words;
+        let word = &words[i];
+        if self.targets.contains(&word.addr) {
+            return None;
+        }
+        let previous = &words[i.checked_sub(1)?];
+        (previous.is_code() && previous.addr.wrapping_add(1) == word.addr).then_some(previous)
+    }
+
+    fn stale_condition_codes(&mut self) {
+        for (i, word) in self.words.iter().enumerate() {
+            let instruction = word.instruction();
+            let conditional = instruction.opcode == Opcode::BR
+                && instruction.nzp != 0
+                && instruction.nzp != 0b111;
+            if !word.is_code() || !conditional {
+                continue;
+            }
+            // BR leaves the condition codes alone, look past a chain of them
+            let mut j = i;
+            let setter = loop {
+                let Some(previous) = self.previous(j) else {
+                    break None;
+                };
+                if previous.instruction().opcode != Opcode::BR {
+                    break Some(previous);
+                }
+                j -= 1;
+            };
+            if let Some(setter) = setter.filter(|setter| !sets_condition_codes(setter.word)) {
+                let message = format!(
+                    "{} tests condition codes that {} does not set",
+                    mnemonic(word.word),
+                    mnemonic(setter.word)
+                );
+                let note = "the condition codes are still the ones from before this".to_string();
+                self.warn(word, message, vec![(setter, note)]);
+            }
+        }
+    }
+
+    /// Code of every JSR target, up to the next subroutine or a label that
+    /// is not branched to from inside once a RET was seen
+    fn subroutines(&mut self) {
+        let mut entries: Vec<u16> = self
+            .words
+            .iter()
+            .filter(|word| word.is_code() && word.instruction().opcode == Opcode::JSR)
+            .filter_map(|word| word.target())
+            .filter(|target| self.index.contains_key(target))
+            .collect();
+        entries.sort();
+        entries.dedup();
+
+        for (n, entry) in entries.iter().enumerate() {
+            let next = entries.get(n + 1).copied();
+            let mut body = vec![];
+            let mut branches = HashSet::new();
+            let mut returned = false;
+            for word in &self.words[self.index[entry]..] {
+                let labeled = self.labels.contains_key(&word.addr) && word.addr != *entry;
+                if Some(word.addr) == next || returned && labeled && !branches.contains(&word.addr)
+                {
+                    break;
+                }
+                if !word.is_code() {
+                    continue;
+                }
+                let instruction = word.instruction();
+                if instruction.opcode == Opcode::BR {
+                    branches.extend(word.target());
+                }
+                returned |= instruction.opcode == Opcode::JMP && instruction.base_r == R7;
+                body.push(word);
+            }
+            self.check_subroutine(*entry, &body);
+        }
+    }
+
+    fn check_subroutine(&mut self, entry: u16, body: &[&Word]) {
+        let name = match self.labels.get(&entry) {
+            Some(name) => name.to_string(),
+            None => format!("x{:04X}", entry),
+        };
+        let entry_word = &self.words[self.index[&entry]];
+        let starts = format!("subroutine {} starts here", name);
+        let entry_note = |word: &Word| match word.addr == entry {
+            true => vec![],
+            false => vec![(entry_word, starts.clone())],
+        };
+
+        // a call overwrites the return address
+        let mut saved = false;
+        for word in body {
+            let instruction = word.instruction();
+            saved |= stored_register(word.word) == Some(R7)
+                || instruction.opcode == Opcode::ADD
+                    && instruction.sr1 == R7
+                    && instruction.flag == 1
+                    && instruction.imm5 == 0;
+            if instruction.opcode == Opcode::JSR && !saved {
+                let message = format!(
+                    "{} overwrites R7 before subroutine {} saved its return address",
+                    mnemonic(word.word),
+                    name
+                );
+                self.warn(word, message, entry_note(word));
+                break;
+            }
+        }
+
+        let stored: HashSet<u16> = body
+            .iter()
+            .filter_map(|word| stored_register(word.word))
+            .collect();
+        let mut reported = HashSet::new();
+        for word in body {
+            let Some(register) = written_register(word.word) else {
+                continue;
+            };
+            if CALLEE_SAVED.contains(&register)
+                && !stored.contains(&register)
+                && reported.insert(register)
+            {
+                let message = format!(
+                    "subroutine {} changes R{} without saving and restoring it",
+                    name, register
+                );
+                self.warn(word, message, entry_note(word));
+            }
+        }
+    }
+
+    fn empty_branches(&mut self) {
+        for word in self.words {
+            let instruction = word.instruction();
+            if word.is_code() && instruction.opcode == Opcode::BR && instruction.nzp == 0 {
+                let message = "BR without n, z or p never branches".to_string();
+                self.warn(word, message, vec![]);
+            }
+        }
+    }
+
+    fn missing_halt(&mut self) {
+        let mut code = self.words.iter().filter(|word| word.is_code());
+        let Some(first) = code.next() else {
+            return;
+        };
+        if first.word != HALT && !code.any(|word| word.word == HALT) {
+            let message = "the program never halts, there is no HALT".to_string();
+            self.warn(first, message, vec![]);
+        }
+    }
+
+    fn unreachable_code(&mut self) {
+        let mut unreachable = false;
+        for (i, word) in self.words.iter().enumerate() {
+            if !word.is_code() || self.targets.contains(&word.addr) {
+                unreachable = false;
+                continue;
+            }
+            let Some(previous) = self.previous(i) else {
+                continue;
+            };
+            let instruction = previous.instruction();
+            let ends_flow = match instruction.opcode {
+                Opcode::BR => instruction.nzp == 0b111,
+                Opcode::JMP => true,
+                _ => previous.word == HALT,
+            };
+            if ends_flow && !unreachable {
+                let message = format!("unreachable code after {}", mnemonic(previous.word));
+                let note = "execution never continues past this".to_string();
+                self.warn(word, message, vec![(previous, note)]);
+            }
+            unreachable = ends_flow || unreachable;
+        }
+    }
+
+    fn puts_without_string(&mut self) {
+        for (i, word) in self.words.iter().enumerate() {
+            if !word.is_code() || word.word != PUTS {
+                continue;
+            }
+            // the instruction that last wrote R0 on the way here
+            let mut j = i;
+            let lea = loop {
+                let Some(previous) = self.previous(j) else {
+                    break None;
+                };
+                let opcode = previous.instruction().opcode;
+                if written_register(previous.word) == Some(R0) {
+                    break (opcode == Opcode::LEA).then_some(previous);
+                }
+                if opcode == Opcode::JSR {
+                    break None;
+                }
+                j -= 1;
+            };
+            let Some(lea) = lea else {
+                continue;
+            };
+            let Some(target) = lea.target() else {
+                continue;
+            };
+            let is_string = self.index.get(&target).is_some_and(|index| {
+                let listed = self.words[*index].listed;
+                listed.op == ".STRINGZ" && listed.address == target
+            });
+            if !is_string {
+                let name = match self.labels.get(&target) {
+                    Some(name) => name.to_string(),
+                    None => format!("x{:04X}", target),
+                };
+                let message = format!("PUTS prints {}, which is not a .STRINGZ", name);
+                let note = "R0 is set here".to_string();
+                self.warn(word, message, vec![(lea, note)]);
+            }
+        }
+    }
+}
+
+fn sets_condition_codes(word: u16) -> bool {
+    let instruction = decode_instruction(word);
+    match instruction.opcode {
+        Opcode::ADD
+        | Opcode::AND
+        | Opcode::NOT
+        | Opcode::LD
+        | Opcode::LDI
+        | Opcode::LDR
+        | Opcode::LEA => true,
+        // GETC and IN set them for the character read
+        Opcode::TRAP => matches!(instruction.trap_code, 0x20 | 0x23),
+        _ => false,
+    }
+}
+
+fn written_register(word: u16) -> Option<u16> {
+    let instruction = decode_instruction(word);
+    match instruction.opcode {
+        Opcode::ADD
+        | Opcode::AND
+        | Opcode::NOT
+        | Opcode::LD
+        | Opcode::LDI
+        | Opcode::LDR
+        | Opcode::LEA => Some(instruction.dr),
+        Opcode::TRAP if matches!(instruction.trap_code, 0x20 | 0x23) => Some(R0),
+        _ => None,
+    }
+}
+
+/// The register an ST, STI or STR writes to memory
+fn stored_register(word: u16) -> Option<u16> {
+    let instruction = decode_instruction(word);
+    match instruction.opcode {
+        Opcode::ST | Opcode::STI | Opcode::STR => Some(instruction.dr),
+        _ => None,
+    }
+}
+
+fn mnemonic(word: u16) -> String {
+    let instruction = decode_instruction(word);
+    match instruction.opcode {
+        Opcode::BR if instruction.nzp == 0b111 => "BR".to_string(),
+        Opcode::BR => {
+            let flags: String = [(4, 'n'), (2, 'z'), (1, 'p')]
+                .iter()
+                .filter(|(bit, _)| instruction.nzp & bit != 0)
+                .map(|(_, flag)| *flag)
+                .collect();
+            format!("BR{}", flags)
+        }
+        Opcode::JSR if instruction.flag == 0 => "JSRR".to_string(),
+        Opcode::JSR => "JSR".to_string(),
+        Opcode::JMP if instruction.base_r == R7 => "RET".to_string(),
+        Opcode::TRAP => trap_name(instruction.trap_code)
+            .unwrap_or("TRAP")
+            .to_string(),
+        opcode => opcode.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{analyze, Options};
+    use crate::lint::lint;
+    use crate::source::Sources;
+    use std::path::Path;
+
+    fn warnings(text: &str) -> Vec<(usize, String)> {
+        let mut sources = Sources::new();
+        let file = sources.add(Path::new("lint.asm"), text.to_string());
+        let analysis = analyze(&mut sources, file, &Options::default());
+        let mut diagnostics = lint(&analysis, &sources);
+        diagnostics.sort_by_key(|d| d.span.line);
+        diagnostics
+            .into_iter()
+            .map(|d| (d.span.line, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_lint_finds_common_mistakes() {
+        let text = "        .ORIG x3000
+        LD R1, COUNT
+        ST R1, SAVE
+        BRz DONE
+        JSR PRINT
+        LEA R0, COUNT
+        PUTS
+DONE    BR DONE
+        ADD R1, R1, #1
+COUNT   .FILL 3
+SAVE    .BLKW 1
+PRINT   ADD R2, R2, #1
+        JSR HELPER
+        RET
+HELPER  ST R3, SAVE3
+        LD R3, SAVE3
+        RET
+SAVE3   .BLKW 1
+        .END";
+        let expected = [
+            (2, "the program never halts, there is no HALT"),
+            (4, "BRz tests condition codes that ST does not set"),
+            (7, "PUTS prints COUNT, which is not a .STRINGZ"),
+            (9, "unreachable code after BR"),
+            (
+                12,
+                "subroutine PRINT changes R2 without saving and restoring it",
+            ),
+            (
+                13,
+                "JSR overwrites R7 before subroutine PRINT saved its return address",
+            ),
+        ];
+        let expected: Vec<(usize, String)> = expected
+            .iter()
+            .map(|(line, message)| (*line, message.to_string()))
+            .collect();
+        assert_eq!(warnings(text), expected);
+    }
+
+    #[test]
+    fn test_lint_accepts_careful_code() {
+        let text = "        .ORIG x3000
+        LEA R0, MSG
+        PUTS
+        GETC
+        BRz SKIP
+        JSR PRINT
+SKIP    HALT
+MSG     .STRINGZ \"hi\"
+PRINT   ST R7, SAVE7
+        ST R1, SAVE1
+        ADD R1, R0, #0
+        BRp POSITIVE
+        BRz POSITIVE
+POSITIVE
+        JSR HELPER
+        LD R1, SAVE1
+        LD R7, SAVE7
+        RET
+HELPER  RET
+SAVE1   .BLKW 1
+SAVE7   .BLKW 1
+        .END";
+        assert_eq!(warnings(text), vec![]);
+    }
+}
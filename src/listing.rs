@@ -0,0 +1,183 @@
+use crate::assembler::{Assembly, Provenance};
+use crate::display::disassemble;
+use crate::expression::Base;
+use crate::source::{Sources, Span};
+use std::collections::HashMap;
+
+/// Listing in the layout of the textbook's lc3as, then a symbol cross reference
+///
+/// ```text
+/// (3000) 5260  0101001001100000 (   3)                 AND R1, R1, #0
+/// (3001) 1261  0001001001100001 (   4) LOOP            ADD R1, R1, #1
+/// ```
+///
+/// Lines without words are listed as written. Pseudo-instructions and macro
+/// calls show their source line, then every instruction they became.
+pub fn listing(assembly: &Assembly, sources: &Sources) -> String {
+    let main = assembly
+        .listing
+        .first()
+        .map_or(0, |listed| listed.line.file);
+    let mut lister = Lister {
+        assembly,
+        sources,
+        text: String::new(),
+        printed: HashMap::new(),
+    };
+    lister.text += "  Addr Hex   Binary             Line  Label           Source\n";
+
+    for listed in &assembly.listing {
+        let line = listed.line.line;
+        let text = sources.line(&listed.line).trim_end();
+        let statement: String = text.chars().skip(listed.column - 1).collect();
+        let words = (listed.address..).zip(&listed.words);
+        match listed.call {
+            // the call line comes first, the body lines are from the definition
+            Some(call) => {
+                lister.fill(call.file, call.line);
+                if listed.provenance == Provenance::Expansion || listed.words.is_empty() {
+                    lister.bare(line, text);
+                }
+            }
+            None if listed.provenance == Provenance::Source && !listed.words.is_empty() => {
+                lister.fill(listed.line.file, line - 1);
+                lister.printed.insert(listed.line.file, line);
+            }
+            None => lister.fill(listed.line.file, line),
+        }
+        match listed.provenance {
+            Provenance::Source => {
+                for (i, (addr, word)) in words.enumerate() {
+                    let source = if i == 0 { statement.as_str() } else { "" };
+                    lister.row(addr, *word, line, source);
+                }
+            }
+            Provenance::Expansion => {
+                for (addr, word) in words {
+                    let instruction = disassemble(addr, *word, &assembly.symbols);
+                    lister.row(addr, *word, line, &format!("  {}", instruction));
+                }
+            }
+            Provenance::Literal => {
+                for (addr, word) in words {
+                    lister.row(addr, *word, line, "  (literal)");
+                }
+            }
+        }
+    }
+    let mut files: Vec<usize> = lister.printed.keys().copied().collect();
+    files.sort();
+    for file in files {
+        lister.fill(file, usize::MAX);
+    }
+
+    let mut text = lister.text;
+    text += "\nSymbol           Value   Defined  Used\n";
+    let location = |span: &Span| match span.file == main {
+        true => span.line.to_string(),
+        false => format!("{}:{}", sources.file(span.file).path.display(), span.line),
+    };
+    for definition in &assembly.definitions {
+        let value = match definition.value.base {
+            Base::Extern(_) => "extern".to_string(),
+            _ => format!("x{:04X}", definition.value.value as u16),
+        };
+        let uses: Vec<String> = definition.uses.iter().map(location).collect();
+        let row = format!(
+            "{:<16} {:<7} {:>7}  {}",
+            definition.name,
+            value,
+            location(&definition.span),
+            uses.join(", ")
+        );
+        text += row.trim_end();
+        text += "\n";
+    }
+    text
+}
+
+struct Lister<'a> {
+    assembly: &'a Assembly,
+    sources: &'a Sources,
+    text: String,
+    // file -> last source line listed
+    printed: HashMap<usize, usize>,
+}
+
+impl Lister<'_> {
+    /// Line with a word, the label is the one at the address
+    fn row(&mut self, addr: u16, word: u16, line: usize, source: &str) {
+        let label = self.assembly.symbols.label(addr).unwrap_or_default();
+        let row = format!(
+            "({:04X}) {:04X}  {:016b} ({:>4}) {:<16}{}",
+            addr, word, word, line, label, source
+        );
+        self.text += row.trim_end();
+        self.text += "\n";
+    }
+
+    /// Line without words, as written
+    fn bare(&mut self, line: usize, text: &str) {
+        let row = format!("{:30}({:>4}) {}", "", line, text);
+        self.text += row.trim_end();
+        self.text += "\n";
+    }
+
+    /// List the lines of a file that were not listed yet, up to and including `line`
+    fn fill(&mut self, file: usize, line: usize) {
+        let printed = self.printed.get(&file).copied().unwrap_or(0);
+        let text = &self.sources.file(file).text;
+        let missing = line.saturating_sub(printed);
+        for (number, text) in text.lines().enumerate().skip(printed).take(missing) {
+            self.bare(number + 1, text);
+        }
+        let last = text.lines().count();
+        self.printed.insert(file, printed.max(line.min(last)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble, Options};
+    use crate::listing::listing;
+    use crate::source::Sources;
+    use std::path::Path;
+
+    #[test]
+    fn test_listing() {
+        let mut sources = Sources::new();
+        let text = "; count to three
+        .ORIG x3000
+        AND R1, R1, #0
+LOOP    PUSH R1         ; save
+        LDIMM R2, #300
+        BR LOOP
+MSG     .STRINGZ \"hi\"
+        .END";
+        let file = sources.add(Path::new("test.asm"), text.to_string());
+        let assembly = assemble(&mut sources, file, &Options::default())
+            .unwrap_or_else(|diagnostics| panic!("{:?}", diagnostics));
+        let expected = [
+            "  Addr Hex   Binary             Line  Label           Source",
+            "                              (   1) ; count to three",
+            "                              (   2)         .ORIG x3000",
+            "(3000) 5260  0101001001100000 (   3)                 AND R1, R1, #0",
+            "                              (   4) LOOP    PUSH R1         ; save",
+            "(3001) 1DBF  0001110110111111 (   4) LOOP              ADD R6 R6 #-1",
+            "(3002) 7380  0111001110000000 (   4)                   STR R1 R6 #0",
+            "                              (   5)         LDIMM R2, #300",
+            "(3003) 2404  0010010000000100 (   5)                   LD R2 x3008",
+            "(3004) 0FFC  0000111111111100 (   6)                 BR LOOP",
+            "(3005) 0068  0000000001101000 (   7) MSG             .STRINGZ \"hi\"",
+            "(3006) 0069  0000000001101001 (   7)",
+            "(3007) 0000  0000000000000000 (   7)",
+            "(3008) 012C  0000000100101100 (   5)                   (literal)",
+            "                              (   8)         .END",
+            "",
+            "Symbol           Value   Defined  Used",
+            "LOOP             x3001         4  6",
+            "MSG              x3005         7",
+        ];
+        assert_eq!(listing(&assembly, &sources), expected.join("\n") + "\n");
+    }
+}
@@ -0,0 +1,287 @@
+use crate::vm::{Register, MEMORY_SIZE, VM};
+use std::io;
+use std::path::Path;
+
+/// An lc3 program image, the words are placed in memory starting at origin
+pub struct Program {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+impl Program {
+    /// Copy the image into memory and point PC at the origin
+    pub fn load(&self, vm: &mut VM) {
+        for (i, word) in self.words.iter().enumerate() {
+            *vm.mem_mut(self.origin.wrapping_add(i as u16)) = *word;
+        }
+        *vm.reg_mut(Register::PC.into()) = self.origin;
+    }
+
+    /// Address of each word in the image
+    pub fn addresses(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.words.len()).map(|i| self.origin.wrapping_add(i as u16))
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        (addr.wrapping_sub(self.origin) as usize) < self.words.len()
+    }
+
+    /// Last address of the image
+    pub fn end(&self) -> u16 {
+        self.origin
+            .wrapping_add(self.words.len().wrapping_sub(1) as u16)
+    }
+}
+
+/// On disk program formats, the first word is always the origin
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // big endian words
+    Obj,
+    // one 4 digit hex word per line (lc3tools)
+    Hex,
+    // one 16 digit binary word per line (lc3tools, LC3Edit)
+    Bin,
+}
+
+impl Format {
+    /// Format named by the file extension
+    pub fn from_extension(path: &str) -> Option<Self> {
+        let extension = Path::new(path).extension()?.to_str()?;
+        match extension.to_ascii_lowercase().as_str() {
+            "obj" => Some(Format::Obj),
+            "hex" => Some(Format::Hex),
+            "bin" => Some(Format::Bin),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the file content, text files
+    /// holding only 0 and 1 digits are .bin, other hex digit files .hex
+    pub fn detect(bytes: &[u8]) -> Self {
+        let text = match std::str::from_utf8(bytes) {
+            Ok(text) if !text.trim().is_empty() => text,
+            _ => return Format::Obj,
+        };
+        let mut words = text
+            .lines()
+            .map(strip_comment)
+            .filter(|line| !line.is_empty());
+        if words
+            .clone()
+            .all(|word| word.len() == 16 && word.chars().all(|c| c == '0' || c == '1'))
+        {
+            Format::Bin
+        } else if words.all(|word| word.len() <= 4 && u16::from_str_radix(word, 16).is_ok()) {
+            Format::Hex
+        } else {
+            Format::Obj
+        }
+    }
+}
+
+/// Read an lc3 program, the format comes from the extension or the content
+pub fn read_program(path: &str) -> io::Result<Program> {
+    let bytes = std::fs::read(path)?;
+    let format = Format::from_extension(path).unwrap_or_else(|| Format::detect(&bytes));
+    parse_program(&bytes, format)
+}
+
+pub fn parse_program(bytes: &[u8], format: Format) -> io::Result<Program> {
+    let mut words: Vec<u16> = match format {
+        Format::Obj => bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect(),
+        Format::Hex => parse_text(bytes, 16)?,
+        Format::Bin => parse_text(bytes, 2)?,
+    };
+    if words.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "program has no origin",
+        ));
+    }
+    let origin = words.remove(0);
+    if words.len() > MEMORY_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("program has {} words, more than fit in memory", words.len()),
+        ));
+    }
+    Ok(Program { origin, words })
+}
+
+/// Write a program in the given format
+pub fn write_program(path: &str, program: &Program, format: Format) -> io::Result<()> {
+    std::fs::write(path, format_program(program, format))
+}
+
+pub fn format_program(program: &Program, format: Format) -> Vec<u8> {
+    let words = std::iter::once(&program.origin).chain(program.words.iter());
+    match format {
+        Format::Obj => words.flat_map(|word| word.to_be_bytes()).collect(),
+        Format::Hex => words
+            .map(|word| format!("{:04X}\n", word))
+            .collect::<String>()
+            .into_bytes(),
+        Format::Bin => words
+            .map(|word| format!("{:016b}\n", word))
+            .collect::<String>()
+            .into_bytes(),
+    }
+}
+
+/// One word per line in the given radix, blank lines and ; comments are skipped
+fn parse_text(bytes: &[u8], radix: u32) -> io::Result<Vec<u16>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let text = std::str::from_utf8(bytes).map_err(|e| invalid(e.to_string()))?;
+    let mut words = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = strip_comment(line);
+        if line.is_empty() {
+            continue;
+        }
+        let word = u16::from_str_radix(line, radix)
+            .map_err(|_| invalid(format!("line {}: invalid word '{}'", number + 1, line)))?;
+        words.push(word);
+    }
+    Ok(words)
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split(';').next().unwrap_or_default().trim()
+}
+
+/// Read several lc3 binaries, each is placed at its own origin
+/// and no two of them may share an address
+pub fn read_programs(paths: &[String]) -> io::Result<Vec<Program>> {
+    let programs = paths
+        .iter()
+        .map(|path| read_program(path))
+        .collect::<io::Result<Vec<Program>>>()?;
+
+    if let Some((a, b)) = find_overlap(&programs) {
+        let message = format!(
+            "{} (x{:04X}-x{:04X}) overlaps {} (x{:04X}-x{:04X})",
+            paths[a],
+            programs[a].origin,
+            programs[a].end(),
+            paths[b],
+            programs[b].origin,
+            programs[b].end()
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    Ok(programs)
+}
+
+/// Indices of the first two programs loaded over each other
+pub fn find_overlap(programs: &[Program]) -> Option<(usize, usize)> {
+    for (a, first) in programs.iter().enumerate() {
+        for (b, second) in programs.iter().enumerate().skip(a + 1) {
+            if first.words.is_empty() || second.words.is_empty() {
+                continue;
+            }
+            if first.contains(second.origin) || second.contains(first.origin) {
+                return Some((a, b));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::{
+        find_overlap, format_program, parse_program, read_programs, Format, Program,
+    };
+
+    fn program(origin: u16, len: usize) -> Program {
+        Program {
+            origin,
+            words: vec![0; len],
+        }
+    }
+
+    #[test]
+    fn test_find_overlap() {
+        let programs = [
+            program(0x3000, 0x10),
+            program(0x4000, 4),
+            program(0x3010, 4),
+        ];
+        assert_eq!(find_overlap(&programs), None);
+
+        let programs = [
+            program(0x3000, 0x10),
+            program(0x4000, 4),
+            program(0x300f, 4),
+        ];
+        assert_eq!(find_overlap(&programs), Some((0, 2)));
+
+        // wrapping around the end of memory
+        let programs = [program(0xfffe, 4), program(0x0001, 1)];
+        assert_eq!(find_overlap(&programs), Some((0, 1)));
+    }
+
+    #[test]
+    fn test_program_filling_memory() {
+        let full = program(0x3000, 0x10000);
+        assert!(full.contains(0x3000));
+        assert!(full.contains(0x2fff));
+        assert_eq!(full.end(), 0x2fff);
+        assert_eq!(full.addresses().count(), 0x10000);
+
+        let mut bytes = vec![0; 2 * (0x10000 + 1)];
+        assert!(parse_program(&bytes, Format::Obj).is_ok());
+        bytes.extend([0, 0]);
+        let error = parse_program(&bytes, Format::Obj).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "program has 65537 words, more than fit in memory"
+        );
+    }
+
+    #[test]
+    fn test_read_overlapping_programs() {
+        let dir = std::env::temp_dir();
+        let write = |name: &str, words: &[u16]| {
+            let path = dir.join(name);
+            let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+            std::fs::write(&path, bytes).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let main = write("loader_test_main.obj", &[0x3000, 0xf025, 0xf025]);
+        let data = write("loader_test_data.obj", &[0x4000, 0x0001]);
+        let clash = write("loader_test_clash.obj", &[0x3001, 0x0001]);
+
+        let programs = read_programs(&[main.clone(), data]).unwrap();
+        assert_eq!(programs[1].origin, 0x4000);
+
+        let error = read_programs(&[main, clash]).err().unwrap();
+        assert!(error.to_string().contains("(x3000-x3001) overlaps"));
+    }
+
+    #[test]
+    fn test_text_formats() {
+        let program = Program {
+            origin: 0x3000,
+            words: vec![0xe002, 0xf025],
+        };
+        for format in [Format::Obj, Format::Hex, Format::Bin] {
+            let bytes = format_program(&program, format);
+            assert_eq!(Format::detect(&bytes), format);
+            let parsed = parse_program(&bytes, format).unwrap();
+            assert_eq!(parsed.origin, 0x3000);
+            assert_eq!(parsed.words, program.words);
+        }
+
+        let hex = parse_program(b"3000 ; origin\n\nf025\n", Format::Hex).unwrap();
+        assert_eq!(hex.words, vec![0xf025]);
+        let error = parse_program(b"3000\nzz\n", Format::Hex).err().unwrap();
+        assert_eq!(error.to_string(), "line 2: invalid word 'zz'");
+        assert_eq!(Format::from_extension("prog.HEX"), Some(Format::Hex));
+        assert_eq!(Format::from_extension("prog.asm"), None);
+    }
+}
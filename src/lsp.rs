@@ -0,0 +1,641 @@
+use crate::assembler::{analyze, Analysis, Definition, Options, MNEMONICS};
+use crate::dap::read_message;
+use crate::decode_instruction::{decode_instruction, IMM5, OFFSET11, OFFSET6, OFFSET9};
+use crate::display::disassemble;
+use crate::lexer::is_ident_char;
+use crate::source::{Diagnostic, Severity, Sources, Span};
+use crate::symbols::SymbolTable;
+use crate::vm::Opcode;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Language Server Protocol server for .asm files
+// see: https://microsoft.github.io/language-server-protocol/specification
+//
+// Documents are synced in full and checked by the assembler on every change.
+// Positions count characters, which matches UTF-16 for the ASCII sources
+// the assembler accepts.
+
+// JSON-RPC error for requests we do not implement
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// lsp enum values
+const SEVERITY_ERROR: i64 = 1;
+const SEVERITY_WARNING: i64 = 2;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_KEYWORD: i64 = 14;
+const COMPLETION_REFERENCE: i64 = 18;
+const COMPLETION_CONSTANT: i64 = 21;
+const SYMBOL_FUNCTION: i64 = 12;
+const SYMBOL_VARIABLE: i64 = 13;
+const SYMBOL_CONSTANT: i64 = 14;
+
+const TRAP_ALIASES: [&str; 6] = ["GETC", "OUT", "PUTS", "IN", "PUTSP", "HALT"];
+const BRANCHES: [&str; 8] = ["BR", "BRn", "BRz", "BRp", "BRnz", "BRnp", "BRzp", "BRnzp"];
+const DIRECTIVES: [&str; 13] = [
+    ".ORIG", ".END", ".FILL", ".BLKW", ".STRINGZ", ".EQU", ".DEFINE", ".MACRO", ".ENDM",
+    ".INCLUDE", ".GLOBAL", ".EXTERN", ".POOL",
+];
+
+pub struct LspServer<W: Write> {
+    output: W,
+    // uri -> text of every open document
+    documents: HashMap<String, String>,
+}
+
+/// A document checked by the assembler
+struct Checked {
+    sources: Sources,
+    file: usize,
+    analysis: Analysis,
+}
+
+/// Serve a single editor session over stdin and stdout
+pub fn serve() -> io::Result<()> {
+    let mut input = io::BufReader::new(io::stdin());
+    let mut server = LspServer::new(io::stdout());
+    while let Some(message) = read_message(&mut input)? {
+        if !server.handle_message(&message)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl<W: Write> LspServer<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Returns false once the editor asked us to exit
+    pub fn handle_message(&mut self, message: &Value) -> io::Result<bool> {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+
+        // notifications
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.to_string(), text.to_string());
+                self.publish_diagnostics(uri)?;
+                return Ok(true);
+            }
+            "textDocument/didChange" => {
+                // full sync, the last change is the whole document
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes
+                    .and_then(|c| c.last())
+                    .and_then(|c| c["text"].as_str())
+                {
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+                self.publish_diagnostics(uri)?;
+                return Ok(true);
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                self.send_notification(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                )?;
+                return Ok(true);
+            }
+            "exit" => return Ok(false),
+            _ => {}
+        }
+        let Some(id) = message.get("id") else {
+            return Ok(true);
+        };
+
+        let position = &params["position"];
+        let result = match method {
+            "initialize" => Some(capabilities()),
+            "shutdown" => Some(Value::Null),
+            "textDocument/definition" => Some(self.definition(uri, position)),
+            "textDocument/references" => {
+                let declaration = params["context"]["includeDeclaration"].as_bool();
+                Some(self.references(uri, position, declaration.unwrap_or(false)))
+            }
+            "textDocument/hover" => Some(self.hover(uri, position)),
+            "textDocument/completion" => Some(self.completion(uri)),
+            "textDocument/documentSymbol" => Some(self.document_symbols(uri)),
+            _ => None,
+        };
+        let response = match result {
+            Some(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            None => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": METHOD_NOT_FOUND, "message": format!("unsupported method {}", method) },
+            }),
+        };
+        self.send(response)?;
+        Ok(true)
+    }
+
+    /// Assemble an open document, includes are read relative to its path
+    fn check(&self, uri: &str) -> Option<Checked> {
+        let text = self.documents.get(uri)?;
+        let mut sources = Sources::new();
+        let file = sources.add(&uri_to_path(uri), text.clone());
+        let analysis = analyze(&mut sources, file, &Options::default());
+        Some(Checked {
+            sources,
+            file,
+            analysis,
+        })
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics: Vec<Value> = match self.check(uri) {
+            Some(checked) => checked
+                .analysis
+                .diagnostics
+                .iter()
+                .filter_map(|diagnostic| lsp_diagnostic(&checked, diagnostic))
+                .collect(),
+            None => vec![],
+        };
+        self.send_notification(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": diagnostics }),
+        )
+    }
+
+    fn definition(&self, uri: &str, position: &Value) -> Value {
+        let Some(checked) = self.check(uri) else {
+            return Value::Null;
+        };
+        match checked.definition_at(position) {
+            Some(definition) => checked.location(&definition.span),
+            None => Value::Null,
+        }
+    }
+
+    fn references(&self, uri: &str, position: &Value, declaration: bool) -> Value {
+        let Some(checked) = self.check(uri) else {
+            return Value::Null;
+        };
+        let Some(definition) = checked.definition_at(position) else {
+            return json!([]);
+        };
+        let declaration = declaration.then_some(&definition.span);
+        let locations: Vec<Value> = declaration
+            .into_iter()
+            .chain(&definition.uses)
+            .map(|span| checked.location(span))
+            .collect();
+        json!(locations)
+    }
+
+    /// A symbol's value, or the words the line under the cursor assembled to
+    fn hover(&self, uri: &str, position: &Value) -> Value {
+        let Some(checked) = self.check(uri) else {
+            return Value::Null;
+        };
+        let text = match checked.definition_at(position) {
+            Some(definition) => {
+                let value = definition.value.value;
+                let place = match definition.span.file == checked.file {
+                    true => format!("line {}", definition.span.line),
+                    false => {
+                        let path = &checked.sources.file(definition.span.file).path;
+                        format!("{}:{}", path.display(), definition.span.line)
+                    }
+                };
+                format!(
+                    "{} = x{:04X} ({})\n\ndefined at {}",
+                    definition.name, value as u16, value, place
+                )
+            }
+            None => checked.encoded_line(line_number(position)),
+        };
+        match text.is_empty() {
+            true => Value::Null,
+            false => json!({ "contents": { "kind": "markdown", "value": text } }),
+        }
+    }
+
+    fn completion(&self, uri: &str) -> Value {
+        let mut items = vec![];
+        let keywords = MNEMONICS
+            .iter()
+            .chain(&TRAP_ALIASES)
+            .chain(&BRANCHES)
+            .chain(&DIRECTIVES);
+        for keyword in keywords {
+            items.push(json!({ "label": keyword, "kind": COMPLETION_KEYWORD }));
+        }
+        for register in 0..8 {
+            let label = format!("R{}", register);
+            items.push(json!({ "label": label, "kind": COMPLETION_VARIABLE }));
+        }
+        if let Some(checked) = self.check(uri) {
+            for definition in checked.visible_definitions() {
+                let kind = match checked.symbol_kind(definition) {
+                    SYMBOL_CONSTANT => COMPLETION_CONSTANT,
+                    _ => COMPLETION_REFERENCE,
+                };
+                let detail = format!("x{:04X}", definition.value.value as u16);
+                items.push(json!({ "label": definition.name, "kind": kind, "detail": detail }));
+            }
+        }
+        json!(items)
+    }
+
+    fn document_symbols(&self, uri: &str) -> Value {
+        let Some(checked) = self.check(uri) else {
+            return Value::Null;
+        };
+        let symbols: Vec<Value> = checked
+            .visible_definitions()
+            .filter(|definition| definition.span.file == checked.file)
+            .map(|definition| {
+                let range = range(&definition.span);
+                json!({
+                    "name": definition.name,
+                    "kind": checked.symbol_kind(definition),
+                    "detail": format!("x{:04X}", definition.value.value as u16),
+                    "range": range,
+                    "selectionRange": range,
+                })
+            })
+            .collect();
+        json!(symbols)
+    }
+
+    fn send_notification(&mut self, method: &str, params: Value) -> io::Result<()> {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    fn send(&mut self, message: Value) -> io::Result<()> {
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+}
+
+impl Checked {
+    /// Labels and constants a user can refer to, macro locals are renamed per expansion
+    fn visible_definitions(&self) -> impl Iterator<Item = &Definition> {
+        self.analysis
+            .definitions
+            .iter()
+            .filter(|definition| !definition.name.starts_with('@'))
+    }
+
+    /// The symbol whose definition or use is under the cursor
+    fn definition_at(&self, position: &Value) -> Option<&Definition> {
+        let line = line_number(position);
+        let column = position["character"].as_u64()? as usize + 1;
+        let span = Span {
+            file: self.file,
+            line,
+            column: 1,
+            len: 0,
+        };
+        let text: Vec<char> = self.sources.line(&span).chars().collect();
+        // the identifier around the cursor, which may sit just after it
+        let is_ident = |i: usize| text.get(i).is_some_and(|c| is_ident_char(*c));
+        let mut start = column - 1;
+        if !is_ident(start) && start > 0 && is_ident(start - 1) {
+            start -= 1;
+        }
+        if !is_ident(start) {
+            return None;
+        }
+        while start > 0 && is_ident(start - 1) {
+            start -= 1;
+        }
+        let mut end = start;
+        while is_ident(end) {
+            end += 1;
+        }
+        let word: String = text[start..end].iter().collect();
+        self.analysis
+            .definitions
+            .iter()
+            .find(|definition| definition.name == word)
+    }
+
+    /// Code labels are functions, data labels variables, the rest constants
+    fn symbol_kind(&self, definition: &Definition) -> i64 {
+        let listed = self.analysis.listing.iter().find(|listed| {
+            listed.line.file == definition.span.file
+                && listed.line.line == definition.span.line
+                && listed.address as i32 == definition.value.value
+        });
+        match listed {
+            Some(listed) if listed.data => SYMBOL_VARIABLE,
+            Some(_) => SYMBOL_FUNCTION,
+            None => SYMBOL_CONSTANT,
+        }
+    }
+
+    /// Address, hex and binary of every word the line assembled to,
+    /// with the range its offset or immediate can reach
+    fn encoded_line(&self, line: usize) -> String {
+        let symbols = SymbolTable::new();
+        let mut rows = vec![];
+        let listed = self.analysis.listing.iter().filter(|listed| {
+            listed.line.file == self.file && listed.line.line == line && listed.call.is_none()
+        });
+        for listed in listed {
+            for (addr, word) in (listed.address..).zip(&listed.words) {
+                let mut row = format!("x{:04X}: x{:04X} {:016b}", addr, word, word);
+                if !listed.data {
+                    row += &format!("  {}", disassemble(addr, *word, &symbols));
+                    if let Some(range) = offset_range(addr, *word) {
+                        row += &format!("  ({})", range);
+                    }
+                }
+                rows.push(row);
+            }
+        }
+        match rows.is_empty() {
+            true => String::new(),
+            false => format!("```\n{}\n```", rows.join("\n")),
+        }
+    }
+
+    fn location(&self, span: &Span) -> Value {
+        let path = &self.sources.file(span.file).path;
+        json!({ "uri": path_to_uri(path), "range": range(span) })
+    }
+}
+
+/// Where a PC relative operand can reach, or the range of an offset or immediate
+fn offset_range(addr: u16, word: u16) -> Option<String> {
+    let instruction = decode_instruction(word);
+    let bits = match instruction.opcode {
+        Opcode::BR | Opcode::LD | Opcode::LDI | Opcode::LEA | Opcode::ST | Opcode::STI => OFFSET9,
+        Opcode::JSR if instruction.flag == 1 => OFFSET11,
+        Opcode::LDR | Opcode::STR => {
+            let limit = 1 << (OFFSET6 - 1);
+            return Some(format!("offset6 {}..{}", -limit, limit - 1));
+        }
+        Opcode::ADD | Opcode::AND if instruction.flag == 1 => {
+            let limit = 1 << (IMM5 - 1);
+            return Some(format!("imm5 {}..{}", -limit, limit - 1));
+        }
+        _ => return None,
+    };
+    let limit = 1u16 << (bits - 1);
+    let next = addr.wrapping_add(1);
+    Some(format!(
+        "offset{} reaches x{:04X}..x{:04X}",
+        bits,
+        next.wrapping_sub(limit),
+        next.wrapping_add(limit - 1)
+    ))
+}
+
+/// The diagnostic in the document, errors in included files are shown at
+/// the line of the document that led there
+fn lsp_diagnostic(checked: &Checked, diagnostic: &Diagnostic) -> Option<Value> {
+    let span = std::iter::once(&diagnostic.span)
+        .chain(diagnostic.notes.iter().map(|(span, _)| span))
+        .find(|span| span.file == checked.file)?;
+    let severity = match diagnostic.severity {
+        Severity::Error => SEVERITY_ERROR,
+        Severity::Warning => SEVERITY_WARNING,
+    };
+    let related: Vec<Value> = std::iter::once((&diagnostic.span, &diagnostic.message))
+        .filter(|(related, _)| *related != span)
+        .chain(diagnostic.notes.iter().map(|(span, note)| (span, note)))
+        .map(|(span, message)| json!({ "location": checked.location(span), "message": message }))
+        .collect();
+    Some(json!({
+        "range": range(span),
+        "severity": severity,
+        "source": "lc3",
+        "message": diagnostic.message,
+        "relatedInformation": related,
+    }))
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            // full document sync
+            "textDocumentSync": 1,
+            "definitionProvider": true,
+            "referencesProvider": true,
+            "hoverProvider": true,
+            "completionProvider": {},
+            "documentSymbolProvider": true,
+        },
+        "serverInfo": { "name": "lc3" },
+    })
+}
+
+/// 1 based line of an lsp position
+fn line_number(position: &Value) -> usize {
+    position["line"].as_u64().unwrap_or(0) as usize + 1
+}
+
+fn range(span: &Span) -> Value {
+    let line = span.line.saturating_sub(1);
+    let start = span.column.saturating_sub(1);
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": start + span.len },
+    })
+}
+
+fn uri_to_path(uri: &str) -> PathBuf {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let bytes = path.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = std::str::from_utf8(bytes.get(i + 1..i + 3).unwrap_or_default())
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = path.canonicalize().unwrap_or(path.to_path_buf());
+    let mut uri = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri += &format!("%{:02X}", byte),
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dap::read_message;
+    use crate::lsp::LspServer;
+    use serde_json::{json, Value};
+    use std::io::BufReader;
+
+    const URI: &str = "file:///tmp/lsp_test.asm";
+    const TEXT: &str = "        .ORIG x3000
+COUNT   .EQU 3
+        LD R1, TIMES
+LOOP    PUTS
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+TIMES   .FILL COUNT
+        .END
+";
+
+    /// Decode everything the server wrote and forget it
+    fn messages(server: &mut LspServer<Vec<u8>>) -> Vec<Value> {
+        let output = std::mem::take(&mut server.output);
+        let mut reader = BufReader::new(output.as_slice());
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn open(text: &str) -> LspServer<Vec<u8>> {
+        let mut server = LspServer::new(vec![]);
+        let document = json!({ "uri": URI, "languageId": "lc3", "version": 1, "text": text });
+        let message = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": document },
+        });
+        server.handle_message(&message).unwrap();
+        server
+    }
+
+    fn request(server: &mut LspServer<Vec<u8>>, method: &str, params: Value) -> Value {
+        let message = json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params });
+        server.handle_message(&message).unwrap();
+        messages(server).remove(0)["result"].clone()
+    }
+
+    fn at(line: u64, character: u64) -> Value {
+        json!({ "textDocument": { "uri": URI }, "position": { "line": line, "character": character } })
+    }
+
+    #[test]
+    fn test_diagnostics_follow_changes() {
+        let mut server = open("        .ORIG x3000\n        ADD R1, R1, #100\n        .END\n");
+        let sent = messages(&mut server);
+        assert_eq!(sent[0]["method"], "textDocument/publishDiagnostics");
+        let diagnostic = &sent[0]["params"]["diagnostics"][0];
+        assert_eq!(diagnostic["severity"], 1);
+        assert_eq!(diagnostic["message"], "100 does not fit in imm5 (-16..15)");
+        assert_eq!(
+            diagnostic["range"]["start"],
+            json!({ "line": 1, "character": 20 })
+        );
+        assert_eq!(
+            diagnostic["range"]["end"],
+            json!({ "line": 1, "character": 24 })
+        );
+
+        let change = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": URI, "version": 2 },
+                "contentChanges": [{ "text": TEXT }],
+            },
+        });
+        server.handle_message(&change).unwrap();
+        let sent = messages(&mut server);
+        assert_eq!(sent[0]["params"]["diagnostics"], json!([]));
+    }
+
+    #[test]
+    fn test_definition_references_and_hover() {
+        let mut server = open(TEXT);
+        messages(&mut server);
+
+        let definition = request(&mut server, "textDocument/definition", at(5, 13));
+        assert!(definition["uri"]
+            .as_str()
+            .unwrap()
+            .ends_with("/lsp_test.asm"));
+        assert_eq!(
+            definition["range"]["start"],
+            json!({ "line": 3, "character": 0 })
+        );
+
+        let mut params = at(3, 2);
+        params["context"] = json!({ "includeDeclaration": true });
+        let references = request(&mut server, "textDocument/references", params);
+        let lines: Vec<&Value> = references
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|location| &location["range"]["start"]["line"])
+            .collect();
+        assert_eq!(lines, [3, 5]);
+
+        let hover = request(&mut server, "textDocument/hover", at(7, 16));
+        let text = hover["contents"]["value"].as_str().unwrap();
+        assert!(text.starts_with("COUNT = x0003 (3)"));
+
+        let hover = request(&mut server, "textDocument/hover", at(2, 9));
+        let text = hover["contents"]["value"].as_str().unwrap();
+        assert!(text.contains("x3000: x2204 0010001000000100  LD R1 x3005"));
+        assert!(text.contains("offset9 reaches x2F01..x3100"));
+    }
+
+    #[test]
+    fn test_completion_and_symbols() {
+        let mut server = open(TEXT);
+        messages(&mut server);
+
+        let completion = request(&mut server, "textDocument/completion", at(4, 8));
+        let labels: Vec<&str> = completion
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap())
+            .collect();
+        for label in ["ADD", "BRnz", "PUTS", ".STRINGZ", "R7", "LOOP", "COUNT"] {
+            assert!(labels.contains(&label), "missing {}", label);
+        }
+
+        let symbols = request(&mut server, "textDocument/documentSymbol", at(0, 0));
+        let symbols: Vec<(&str, i64)> = symbols
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|symbol| {
+                let name = symbol["name"].as_str().unwrap();
+                (name, symbol["kind"].as_i64().unwrap())
+            })
+            .collect();
+        assert_eq!(symbols, [("COUNT", 14), ("LOOP", 12), ("TIMES", 13)]);
+    }
+}
@@ -1,79 +1,622 @@
-use crate::cli::{Cli, Commands};
-use crate::decode_instruction::decode_instruction;
-use crate::vm::{Register, VM};
+use crate::archive::{
+    is_archive, read_archive, read_archive_from, select_members, write_archive, Archive,
+};
+use crate::assembler::{analyze, assemble, Options};
+use crate::callstack::backtrace;
+use crate::cfg::Cfg;
+use crate::cli::{AnalyzeAction, ArAction, Cli, Commands};
+use crate::convention::{ConventionChecker, DEFAULT_CALLEE_SAVED};
+use crate::coverage::Coverage;
+use crate::debuginfo::DebugInfo;
+use crate::formatter::{format_source, macro_names};
+use crate::linker::{link, Layout};
+use crate::lint::lint;
+use crate::listing::listing;
+use crate::loader::{read_program, read_programs, write_program, Format, Program};
+use crate::memcheck::{fill_random, MemoryChecker};
+use crate::object::{read_object, read_object_from, write_object};
+use crate::profiler::Profiler;
+use crate::regions::{disassembly, quote, strings};
+use crate::snapshot::{load_snapshot, save_snapshot};
+use crate::source::{Severity, Sources};
+use crate::stackguard::StackGuard;
+use crate::symbols::{parse_number, SymbolTable};
+use crate::trace::InstructionTrace;
+use crate::vm::{Register, Tracer, VM};
 use clap::Parser;
 use std::fs::File;
-use std::io;
-use std::io::ErrorKind::UnexpectedEof;
-use std::io::{BufReader, Read};
+use std::io::BufWriter;
 use termios::*;
 
+pub mod archive;
+pub mod assembler;
+pub mod callstack;
+pub mod cfg;
 mod cli;
+pub mod console;
+pub mod convention;
+pub mod coverage;
+pub mod dap;
+pub mod debuginfo;
 pub mod decode_instruction;
 mod display;
+pub mod expression;
+pub mod formatter;
+pub mod gdbserver;
+pub mod lexer;
+pub mod linker;
+pub mod lint;
+pub mod listing;
+pub mod loader;
+pub mod lsp;
+pub mod memcheck;
+pub mod object;
 pub mod opcodes;
+pub mod preprocessor;
+pub mod profiler;
+pub mod regions;
+pub mod snapshot;
+pub mod source;
+pub mod stackguard;
+pub mod symbols;
+pub mod trace;
+pub mod tui;
 pub mod vm;
 
 fn main() {
     let cli = Cli::parse();
 
-    let (path, execute) = match &cli.command {
-        Commands::Execute { path } => (path, true),
-        Commands::Disassemble { path } => (path, false),
+    match &cli.command {
+        Commands::Execute {
+            paths,
+            entry,
+            save_state_on_halt,
+            profile,
+            coverage,
+            trace,
+            symbols,
+            check_calls,
+            callee_saved,
+            uninitialized,
+            random_memory,
+            stack,
+        } => {
+            let mut vm = VM::init();
+            if let Some(seed) = random_memory {
+                fill_random(&mut vm, random_seed(*seed));
+            }
+            let symbols = load_symbols(paths, symbols.as_deref());
+            let programs = load_programs(&mut vm, paths, entry.as_deref(), &symbols);
+            let debug = load_debug_info(paths);
+
+            let mut profiler = profile.as_ref().map(|_| Profiler::new());
+            let mut coverage_tracer = coverage.as_ref().map(|_| Coverage::new());
+            let mut trace = trace.as_ref().map(|trace_path| {
+                let file = File::create(trace_path).expect("failed to create trace");
+                InstructionTrace::new(BufWriter::new(file), &symbols, &debug)
+            });
+            let mut checker = check_calls.then(|| {
+                let callee_saved = parse_registers(callee_saved);
+                ConventionChecker::new(std::io::stderr(), &symbols, &callee_saved)
+            });
+            let mut memory_checker = uninitialized.as_ref().map(|mode| {
+                let mut checker = MemoryChecker::new(std::io::stderr(), &symbols, mode == "break");
+                checker.mark_loaded(&programs);
+                checker
+            });
+            let mut stack_guard = stack_region(stack.as_deref(), &symbols)
+                .map(|(limit, top)| StackGuard::new(std::io::stderr(), &symbols, limit, top));
+            let mut tracers: Vec<&mut dyn Tracer> = vec![];
+            if let Some(trace) = trace.as_mut() {
+                tracers.push(trace);
+            }
+            if let Some(checker) = checker.as_mut() {
+                tracers.push(checker);
+            }
+            if let Some(memory_checker) = memory_checker.as_mut() {
+                tracers.push(memory_checker);
+            }
+            if let Some(stack_guard) = stack_guard.as_mut() {
+                tracers.push(stack_guard);
+            }
+            if let Some(profiler) = profiler.as_mut() {
+                tracers.push(profiler);
+            }
+            if let Some(coverage_tracer) = coverage_tracer.as_mut() {
+                tracers.push(coverage_tracer);
+            }
+            let faulted = run(
+                &mut vm,
+                save_state_on_halt.as_deref(),
+                &mut tracers,
+                &symbols,
+            );
+
+            if let (Some(report), Some(profiler)) = (profile, profiler) {
+                write_profile(&mut vm, &profiler, &symbols, &debug, report);
+            }
+            if let (Some(report), Some(coverage_tracer)) = (coverage, coverage_tracer) {
+                write_coverage(&coverage_tracer, &programs, &symbols, &debug, paths, report);
+            }
+            let stopped = faulted
+                || (uninitialized.as_deref() == Some("break")
+                    && memory_checker.is_some_and(|checker| checker.violations() > 0))
+                || stack_guard.is_some_and(|guard| guard.violation());
+            if stopped {
+                std::process::exit(1);
+            }
+        }
+        Commands::Disassemble { path, symbols } => {
+            let mut vm = VM::init();
+            let symbols = load_symbols(std::slice::from_ref(path), symbols.as_deref());
+            load_program(&mut vm, path, Some(&symbols));
+        }
+        Commands::Strings { path, symbols } => {
+            let program = read_program(path).expect("failed to read program");
+            let symbols = load_symbols(std::slice::from_ref(path), symbols.as_deref());
+            for (addr, text, packed) in strings(&program, &symbols) {
+                let packed = if packed { " (PUTSP)" } else { "" };
+                println!("x{:04X}  {}{}", addr, quote(&text), packed);
+            }
+        }
+        Commands::Analyze { action } => run_analyze(action),
+        Commands::Convert { input, output } => {
+            let format = Format::from_extension(output).unwrap_or_else(|| {
+                eprintln!("unknown output format, use .obj, .hex or .bin");
+                std::process::exit(1);
+            });
+            let program = read_program(input).expect("failed to read program");
+            write_program(output, &program, format).expect("failed to write program");
+        }
+        Commands::Assemble {
+            source,
+            output,
+            relocatable,
+            listing: listing_path,
+        } => {
+            let mut sources = Sources::new();
+            let file = sources
+                .load(std::path::Path::new(source))
+                .expect("failed to read source");
+            let options = Options {
+                relocatable: *relocatable,
+            };
+            let assembly = assemble(&mut sources, file, &options).unwrap_or_else(|diagnostics| {
+                for diagnostic in &diagnostics {
+                    eprint!("{}", diagnostic.render(&sources));
+                }
+                let errors = diagnostics
+                    .iter()
+                    .filter(|d| d.severity == Severity::Error)
+                    .count();
+                eprintln!("{} error(s), nothing written", errors);
+                std::process::exit(1);
+            });
+            for warning in &assembly.warnings {
+                eprint!("{}", warning.render(&sources));
+            }
+            if let Some(path) = listing_path {
+                let text = listing(&assembly, &sources);
+                std::fs::write(path, text).expect("failed to write listing");
+            }
+            let extension = if *relocatable { "lobj" } else { "obj" };
+            let output = output.clone().unwrap_or_else(|| {
+                let path = std::path::Path::new(source).with_extension(extension);
+                path.to_string_lossy().into_owned()
+            });
+            if *relocatable {
+                write_object(&output, &assembly.object).expect("failed to write object");
+            } else {
+                let format = Format::from_extension(&output).unwrap_or(Format::Obj);
+                write_program(&output, &assembly.program, format).expect("failed to write program");
+                let sym_path = std::path::Path::new(&output).with_extension("sym");
+                std::fs::write(&sym_path, assembly.symbols.to_sym())
+                    .expect("failed to write symbols");
+                let dbg_path = std::path::Path::new(&output).with_extension("dbg");
+                let debug = DebugInfo::from_assembly(&assembly, &sources);
+                std::fs::write(&dbg_path, debug.to_text()).expect("failed to write debug info");
+            }
+        }
+        Commands::Fmt { sources, check } => {
+            let mut unformatted = 0;
+            for source in sources {
+                let path = std::path::Path::new(source);
+                let mut files = Sources::new();
+                let file = files.load(path).expect("failed to read source");
+                let text = files.file(file).text.clone();
+                let formatted =
+                    format_source(&text, file, &macro_names(path)).unwrap_or_else(|diagnostic| {
+                        eprint!("{}", diagnostic.render(&files));
+                        std::process::exit(1);
+                    });
+                if formatted == text {
+                    continue;
+                }
+                if *check {
+                    println!("{} is not formatted", source);
+                    unformatted += 1;
+                } else {
+                    std::fs::write(path, formatted).expect("failed to write source");
+                }
+            }
+            if unformatted > 0 {
+                std::process::exit(1);
+            }
+        }
+        Commands::Lint { sources } => {
+            let mut problems = 0;
+            for source in sources {
+                let mut files = Sources::new();
+                let file = files
+                    .load(std::path::Path::new(source))
+                    .expect("failed to read source");
+                let analysis = analyze(&mut files, file, &Options::default());
+                let mut diagnostics = analysis.diagnostics.clone();
+                if !diagnostics.iter().any(|d| d.severity == Severity::Error) {
+                    diagnostics.extend(lint(&analysis, &files));
+                    diagnostics.sort_by_key(|d| (d.span.file, d.span.line, d.span.column));
+                }
+                for diagnostic in &diagnostics {
+                    eprint!("{}", diagnostic.render(&files));
+                }
+                problems += diagnostics.len();
+            }
+            if problems > 0 {
+                eprintln!("{} problem(s) found", problems);
+                std::process::exit(1);
+            }
+        }
+        Commands::Link {
+            objects,
+            output,
+            place,
+            base,
+        } => {
+            let mut archives = vec![];
+            let mut inputs = vec![];
+            for path in objects {
+                let bytes = std::fs::read(path).expect("failed to read object");
+                if is_archive(&bytes) {
+                    let archive = read_archive_from(&mut bytes.as_slice());
+                    archives.push((path.clone(), archive.expect("failed to read archive")));
+                } else {
+                    let object = read_object_from(&mut bytes.as_slice());
+                    inputs.push((path.clone(), object.expect("failed to read object")));
+                }
+            }
+            let members = select_members(&inputs, &archives);
+            inputs.extend(members);
+            let mut layout = Layout::default();
+            if let Some(base) = base {
+                layout.base = parse_number(base).expect("invalid base address");
+            }
+            for placement in place {
+                let (name, addr) = placement
+                    .split_once('=')
+                    .and_then(|(name, addr)| Some((name, parse_number(addr)?)))
+                    .expect("placements look like SECTION=ADDRESS");
+                layout.placements.insert(name.to_string(), addr);
+            }
+
+            let linked = link(&inputs, &layout).unwrap_or_else(|e| {
+                eprintln!("link failed: {}", e);
+                std::process::exit(1);
+            });
+            let format = Format::from_extension(output).unwrap_or(Format::Obj);
+            write_program(output, &linked.program, format).expect("failed to write program");
+            let sym_path = std::path::Path::new(output).with_extension("sym");
+            std::fs::write(&sym_path, linked.symbols.to_sym()).expect("failed to write symbols");
+        }
+        Commands::Ar { action } => run_ar(action),
+        Commands::Gdbserver {
+            paths,
+            entry,
+            listen,
+        } => {
+            let mut vm = VM::init();
+            let symbols = load_symbols(paths, None);
+            load_programs(&mut vm, paths, entry.as_deref(), &symbols);
+            with_raw_terminal(|| gdbserver::serve(&mut vm, listen).expect("gdbserver failed"));
+        }
+        Commands::Dap => dap::serve().expect("dap server failed"),
+        Commands::Lsp => lsp::serve().expect("language server failed"),
+        Commands::Tui {
+            paths,
+            entry,
+            symbols,
+        } => {
+            let mut vm = Box::new(VM::init());
+            let symbols = load_symbols(paths, symbols.as_deref());
+            load_programs(&mut vm, paths, entry.as_deref(), &symbols);
+            tui::run(vm, symbols, load_debug_info(paths)).expect("tui failed");
+        }
+        Commands::Resume {
+            snapshot,
+            save_state_on_halt,
+        } => {
+            let mut vm = load_snapshot(snapshot).expect("failed to load snapshot");
+            let symbols = SymbolTable::new();
+            if run(&mut vm, save_state_on_halt.as_deref(), &mut [], &symbols) {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Symbols from an explicit .sym file, or the ones next to the programs
+fn load_symbols(program_paths: &[String], symbols_path: Option<&str>) -> SymbolTable {
+    match symbols_path {
+        Some(path) => SymbolTable::load(path).expect("failed to load symbols"),
+        None => {
+            let mut symbols = SymbolTable::new();
+            for path in program_paths {
+                symbols.extend(SymbolTable::for_program(path));
+            }
+            symbols
+        }
+    }
+}
+
+/// Register numbers from names like R1, the default callee saved registers if there are none
+fn parse_registers(names: &[String]) -> Vec<u16> {
+    if names.is_empty() {
+        return DEFAULT_CALLEE_SAVED.to_vec();
+    }
+    names
+        .iter()
+        .map(|name| match name.trim().as_bytes() {
+            [b'r' | b'R', digit @ b'0'..=b'7'] => u16::from(digit - b'0'),
+            _ => {
+                eprintln!("invalid register '{}', expected R0 to R7", name);
+                std::process::exit(1);
+            }
+        })
+        .collect()
+}
+
+/// The stack given as LIMIT:TOP, or by the STACK_LIMIT and STACK_TOP labels
+fn stack_region(stack: Option<&str>, symbols: &SymbolTable) -> Option<(u16, u16)> {
+    let Some(stack) = stack else {
+        return symbols
+            .address("STACK_LIMIT")
+            .zip(symbols.address("STACK_TOP"));
+    };
+    let region = stack.split_once(':').and_then(|(limit, top)| {
+        let limit = symbols.resolve(limit.trim())?;
+        let top = symbols.resolve(top.trim())?;
+        (limit < top).then_some((limit, top))
+    });
+    if region.is_none() {
+        eprintln!(
+            "invalid stack '{}', expected LIMIT:TOP with LIMIT below TOP",
+            stack
+        );
+        std::process::exit(1);
+    }
+    region
+}
+
+/// The seed for --random-memory, 0 picks one from the current time
+fn random_seed(seed: u64) -> u64 {
+    if seed != 0 {
+        return seed;
+    }
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+    let seed = now.map_or(1, |now| now.as_nanos() as u64);
+    eprintln!("random memory seed: {}", seed);
+    seed
+}
+
+/// Debug info from the .dbg files next to the programs
+fn load_debug_info(program_paths: &[String]) -> DebugInfo {
+    let mut debug = DebugInfo::new();
+    for path in program_paths {
+        debug.extend(DebugInfo::for_program(path));
+    }
+    debug
+}
+
+/// Load every binary at its own origin, PC starts at `entry`
+/// or the origin of the first binary
+fn load_programs(
+    vm: &mut VM,
+    paths: &[String],
+    entry: Option<&str>,
+    symbols: &SymbolTable,
+) -> Vec<Program> {
+    let programs = read_programs(paths).unwrap_or_else(|e| {
+        eprintln!("failed to load programs: {}", e);
+        std::process::exit(1);
+    });
+    for program in &programs {
+        program.load(vm);
+    }
+
+    let pc = match entry {
+        Some(entry) => symbols.resolve(entry).unwrap_or_else(|| {
+            eprintln!("unknown entry point '{}'", entry);
+            std::process::exit(1);
+        }),
+        None => programs[0].origin,
     };
+    *vm.reg_mut(Register::PC.into()) = pc;
+    println!("program loaded successfully!");
+    programs
+}
 
-    // Some tricks to make the VM's terminal be interactive
-    let stdin = 0;
-    let termios = Termios::from_fd(stdin).unwrap();
+/// Load an lc3 binary into memory, the first word is the origin
+/// and becomes the initial PC, disassembles it when given symbols
+fn load_program(vm: &mut VM, path: &str, disassemble_with: Option<&SymbolTable>) -> Program {
+    let program = read_program(path).unwrap();
 
-    // make a mutable copy of termios
-    // that we will modify
-    let mut new_termios = termios.clone();
-    new_termios.c_iflag &= IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON;
-    new_termios.c_lflag &= !(ICANON | ECHO); // no echo and canonical mode
+    if let Some(symbols) = disassemble_with {
+        for (addr, line) in disassembly(&program, symbols) {
+            println!(
+                "x{:04X}  {:<12} {}",
+                addr,
+                symbols.label(addr).unwrap_or_default(),
+                line
+            );
+        }
+    }
 
-    tcsetattr(stdin, TCSANOW, &mut new_termios).unwrap();
+    program.load(vm);
+    println!("program loaded successfully!");
+    program
+}
 
-    let f = File::open(path).unwrap();
-    let mut f = BufReader::new(f);
+/// Run to the end, returns true if the program was stopped by an error
+/// after printing the error and a backtrace
+fn run(
+    vm: &mut VM,
+    save_state_on_halt: Option<&str>,
+    tracers: &mut [&mut dyn Tracer],
+    symbols: &SymbolTable,
+) -> bool {
+    with_raw_terminal(|| vm.run_traced(tracers));
 
-    let mut address = read_u16(&mut f).unwrap();
+    if let Some(path) = save_state_on_halt {
+        save_snapshot(vm, path).expect("failed to save snapshot");
+        println!("\nstate saved to {}", path);
+    }
+    if let Some(error) = vm.error() {
+        let pc = vm.reg(Register::PC.into());
+        eprintln!("\n{}", error);
+        eprint!("{}", backtrace(vm.call_stack().frames(), pc, symbols));
+        return true;
+    }
+    false
+}
 
-    let mut vm = VM::init();
-    *vm.reg_mut(Register::PC.into()) = address;
+fn write_profile(
+    vm: &mut VM,
+    profiler: &Profiler,
+    symbols: &SymbolTable,
+    debug: &DebugInfo,
+    path: &str,
+) {
+    std::fs::write(path, profiler.report(vm, symbols, debug)).expect("failed to write profile");
+    let folded_path = format!("{}.folded", path);
+    std::fs::write(&folded_path, profiler.folded_stacks(symbols)).expect("failed to write profile");
+    println!("\nprofile written to {} and {}", path, folded_path);
+}
 
-    loop {
-        match read_u16(&mut f) {
-            Ok(instruction) => {
-                if !execute {
-                    // disassemble
-                    let decoded = decode_instruction(instruction);
-                    println!("{}", decoded);
-                }
+fn write_coverage(
+    coverage: &Coverage,
+    programs: &[Program],
+    symbols: &SymbolTable,
+    debug: &DebugInfo,
+    program_paths: &[String],
+    path: &str,
+) {
+    let mut listing = String::new();
+    let mut lcov = String::new();
+    for (program, program_path) in programs.iter().zip(program_paths) {
+        if programs.len() > 1 {
+            listing += &format!("{}\n", program_path);
+        }
+        listing += &coverage.listing(program, symbols, debug);
+        // source lines with debug info, otherwise the words of the image
+        let source_lcov = coverage.lcov_for_source(program, debug);
+        if source_lcov.is_empty() {
+            lcov += &coverage.lcov(program, program_path);
+        } else {
+            lcov += &source_lcov;
+        }
+    }
+    std::fs::write(path, listing).expect("failed to write coverage");
+    let lcov_path = format!("{}.info", path);
+    std::fs::write(&lcov_path, lcov).expect("failed to write coverage");
+    println!("\ncoverage written to {} and {}", path, lcov_path);
+}
+
+fn run_analyze(action: &AnalyzeAction) {
+    match action {
+        AnalyzeAction::Cfg {
+            path,
+            symbols,
+            json,
+            output,
+        } => {
+            let program = read_program(path).expect("failed to read program");
+            let symbols = load_symbols(std::slice::from_ref(path), symbols.as_deref());
+            let cfg = Cfg::recover(&program);
+            let text = if *json {
+                format!("{:#}\n", cfg.to_json(&symbols))
+            } else {
+                cfg.to_dot(&program, &symbols)
+            };
+            match output {
+                Some(output) => std::fs::write(output, text).expect("failed to write graph"),
+                None => print!("{}", text),
+            }
+        }
+    }
+}
 
-                *vm.mem_mut(address) = instruction;
-                address += 1;
+fn run_ar(action: &ArAction) {
+    match action {
+        ArAction::Add { archive, objects } => {
+            let mut library = if std::path::Path::new(archive).exists() {
+                read_archive(archive).expect("failed to read archive")
+            } else {
+                Archive::new()
+            };
+            for path in objects {
+                let name = std::path::Path::new(path).file_name().unwrap();
+                let object = read_object(path).expect("failed to read object");
+                library.insert(&name.to_string_lossy(), object);
+            }
+            write_archive(archive, &library).expect("failed to write archive");
+        }
+        ArAction::List { archive } => {
+            let library = read_archive(archive).expect("failed to read archive");
+            for (name, object) in &library.members {
+                let exports: Vec<&str> = object.exports().map(|s| s.name.as_str()).collect();
+                println!("{}: {}", name, exports.join(" "));
             }
-            Err(e) => {
-                if e.kind() == UnexpectedEof {
-                    println!("program loaded successfully!");
+        }
+        ArAction::Extract { archive, members } => {
+            let library = read_archive(archive).expect("failed to read archive");
+            for member in members {
+                if !library.members.iter().any(|(name, _)| name == member) {
+                    eprintln!("{} has no member {}", archive, member);
+                    std::process::exit(1);
+                }
+            }
+            for (name, object) in &library.members {
+                if members.is_empty() || members.contains(name) {
+                    // never outside the current directory, whatever the archive says
+                    let Some(file_name) = std::path::Path::new(name).file_name() else {
+                        eprintln!("{} has a bad member name {:?}", archive, name);
+                        std::process::exit(1);
+                    };
+                    write_object(&file_name.to_string_lossy(), object)
+                        .expect("failed to write object");
                 }
-                break;
             }
         }
     }
+}
 
-    if execute {
-        vm.run();
-    }
+/// Run `f` with the terminal in non canonical, no echo mode
+/// so the lc3 program receives key presses as they happen
+fn with_raw_terminal<F: FnOnce()>(f: F) {
+    // Some tricks to make the VM's terminal be interactive
+    let stdin = 0;
+    let termios = Termios::from_fd(stdin).unwrap();
+
+    // make a mutable copy of termios
+    // that we will modify
+    let mut new_termios = termios;
+    new_termios.c_iflag &= IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON;
+    new_termios.c_lflag &= !(ICANON | ECHO); // no echo and canonical mode
+
+    tcsetattr(stdin, TCSANOW, &new_termios).unwrap();
+
+    f();
 
     // reset the stdin to
     // original termios data
     tcsetattr(stdin, TCSANOW, &termios).unwrap();
 }
-
-fn read_u16(f: &mut BufReader<File>) -> io::Result<u16> {
-    let mut buffer = [0_u8; 2];
-    f.read_exact(&mut buffer)?;
-    Ok(u16::from_be_bytes(buffer))
-}
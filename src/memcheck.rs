@@ -0,0 +1,191 @@
+use crate::decode_instruction::DecodedInstruction;
+use crate::display::address;
+use crate::loader::Program;
+use crate::symbols::SymbolTable;
+use crate::vm::{report, Opcode, Tracer, MEMORY_SIZE, VM};
+use std::io::Write;
+
+// the device registers are always readable
+const DEVICE_REGISTERS: u16 = 0xFE00;
+
+/// Shadow "initialized" bit for every word of memory, set for loaded
+/// programs and on every store, reads of words never written are reported
+///
+/// LD, LDR, LDI (the pointer and the word it points to), the pointer of
+/// STI and instruction fetch are checked. Every unwritten word is reported
+/// once, or the program stops before the read when `stop` is set.
+pub struct MemoryChecker<'a, W: Write> {
+    output: W,
+    symbols: &'a SymbolTable,
+    initialized: Vec<bool>,
+    reported: Vec<bool>,
+    stop: bool,
+    // address the instruction about to run stores to
+    store: Option<u16>,
+    violations: usize,
+}
+
+impl<'a, W: Write> MemoryChecker<'a, W> {
+    pub fn new(output: W, symbols: &'a SymbolTable, stop: bool) -> Self {
+        let mut initialized = vec![false; MEMORY_SIZE];
+        initialized[DEVICE_REGISTERS as usize..].fill(true);
+        Self {
+            output,
+            symbols,
+            initialized,
+            reported: vec![false; MEMORY_SIZE],
+            stop,
+            store: None,
+            violations: 0,
+        }
+    }
+
+    /// Mark the words of the loaded programs as written
+    pub fn mark_loaded(&mut self, programs: &[Program]) {
+        for program in programs {
+            for addr in program.addresses() {
+                self.initialized[addr as usize] = true;
+            }
+        }
+    }
+
+    pub fn is_initialized(&self, addr: u16) -> bool {
+        self.initialized[addr as usize]
+    }
+
+    pub fn violations(&self) -> usize {
+        self.violations
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    fn check(&mut self, vm: &mut VM, pc: u16, addr: u16, what: &str) {
+        if self.initialized[addr as usize] || self.reported[addr as usize] && !self.stop {
+            return;
+        }
+        self.reported[addr as usize] = true;
+        self.violations += 1;
+        report(
+            &mut self.output,
+            format_args!(
+                "uninitialized memory: {} {} {}, which was never written\n",
+                address(pc, self.symbols),
+                what,
+                address(addr, self.symbols)
+            ),
+        );
+        if self.stop {
+            vm.halt();
+        }
+    }
+}
+
+impl<W: Write> Tracer for MemoryChecker<'_, W> {
+    fn before_step(&mut self, vm: &mut VM, pc: u16, instruction: &DecodedInstruction) {
+        let next = pc.wrapping_add(1);
+        let pc_relative = next.wrapping_add(instruction.offset);
+        let base_relative = vm.reg(instruction.base_r).wrapping_add(instruction.offset);
+
+        self.check(vm, pc, pc, "executes");
+        match instruction.opcode {
+            Opcode::LD => self.check(vm, pc, pc_relative, "LD reads"),
+            Opcode::LDR => self.check(vm, pc, base_relative, "LDR reads"),
+            Opcode::LDI => {
+                self.check(vm, pc, pc_relative, "LDI reads the pointer at");
+                let target = *vm.mem_mut(pc_relative);
+                self.check(vm, pc, target, "LDI reads");
+            }
+            Opcode::STI => self.check(vm, pc, pc_relative, "STI reads the pointer at"),
+            _ => {}
+        }
+
+        self.store = match instruction.opcode {
+            Opcode::ST => Some(pc_relative),
+            Opcode::STR => Some(base_relative),
+            Opcode::STI => Some(*vm.mem_mut(pc_relative)),
+            _ => None,
+        };
+    }
+
+    fn after_step(&mut self, _vm: &mut VM, _pc: u16, _instruction: &DecodedInstruction) {
+        if let Some(addr) = self.store.take() {
+            self.initialized[addr as usize] = true;
+        }
+    }
+}
+
+/// Fill all memory with pseudo random words, so programs relying on
+/// memory being zero behave differently
+pub fn fill_random(vm: &mut VM, seed: u64) {
+    // xorshift64, the state must not be zero
+    let mut state = seed.max(1);
+    for addr in 0..MEMORY_SIZE {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        *vm.mem_mut(addr as u16) = (state >> 32) as u16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::Program;
+    use crate::memcheck::{fill_random, MemoryChecker};
+    use crate::symbols::SymbolTable;
+    use crate::vm::{Register, VM};
+
+    fn program() -> Program {
+        Program {
+            origin: 0x3000,
+            words: vec![
+                0x2204, // x3000 LD R1, COUNT
+                0x3404, // x3001 ST R2, TEMP
+                0x2603, // x3002 LD R3, TEMP
+                0x2803, // x3003 LD R4, UNSET
+                0xf025, // x3004 HALT
+                0x0005, // x3005 COUNT .FILL 5
+            ],
+        }
+    }
+
+    #[test]
+    fn test_reports_reads_of_unwritten_words() {
+        let mut vm = VM::init();
+        let program = program();
+        program.load(&mut vm);
+        let mut symbols = SymbolTable::new();
+        symbols.insert("TEMP", 0x3006);
+        symbols.insert("UNSET", 0x3007);
+
+        let mut checker = MemoryChecker::new(vec![], &symbols, false);
+        checker.mark_loaded(std::slice::from_ref(&program));
+        vm.run_traced(&mut [&mut checker]);
+
+        assert!(checker.is_initialized(0x3006));
+        assert_eq!(checker.violations(), 1);
+        let output = String::from_utf8(checker.into_inner()).unwrap();
+        assert_eq!(
+            output,
+            "uninitialized memory: x3003 LD reads x3007 (UNSET), which was never written\n"
+        );
+    }
+
+    #[test]
+    fn test_stops_before_the_read() {
+        let mut vm = VM::init();
+        fill_random(&mut vm, 42);
+        let program = program();
+        program.load(&mut vm);
+        let symbols = SymbolTable::new();
+
+        let mut checker = MemoryChecker::new(vec![], &symbols, true);
+        checker.mark_loaded(std::slice::from_ref(&program));
+        vm.run_traced(&mut [&mut checker]);
+
+        assert_eq!(vm.reg(Register::PC.into()), 0x3003);
+        assert_eq!(vm.reg(Register::R4.into()), 0);
+        assert_eq!(checker.violations(), 1);
+    }
+}
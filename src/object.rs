@@ -0,0 +1,312 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// Relocatable object file layout (all words big endian)
+///   - magic "LC3R" (4 bytes)
+///   - format version (u16)
+///   - section count, then per section:
+///     name, fixed flag (u16, 0 or 1), origin (u16), word count, words
+///   - symbol count, then per symbol:
+///     name, binding (u16), section index (u16), offset in the section (u16)
+///   - relocation count, then per relocation:
+///     section index, offset of the patched word, kind (u16), symbol index, addend (i16)
+///
+/// Names are a u16 byte length followed by the UTF-8 bytes.
+pub const OBJECT_MAGIC: &[u8; 4] = b"LC3R";
+pub const OBJECT_VERSION: u16 = 1;
+
+/// Block of words placed as a unit by the linker
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    // absolute sections keep their .ORIG, others are placed by the linker
+    pub origin: Option<u16>,
+    pub words: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    // only visible inside the object
+    Local,
+    // visible to every object being linked
+    Export,
+    // defined by another object, section and offset are unused
+    Import,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub binding: Binding,
+    pub section: u16,
+    pub offset: u16,
+}
+
+/// How the address of a symbol is patched into a word
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocationKind {
+    // PC relative, bits [8:0] (BR, LD, LDI, LEA, ST, STI)
+    Offset9,
+    // PC relative, bits [10:0] (JSR)
+    Offset11,
+    // the symbol value itself, bits [5:0] (LDR, STR)
+    Offset6,
+    // the whole word (.FILL LABEL)
+    Word,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub section: u16,
+    pub offset: u16,
+    pub kind: RelocationKind,
+    pub symbol: u16,
+    pub addend: i16,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ObjectFile {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl ObjectFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Symbols other objects can link against
+    pub fn exports(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.binding == Binding::Export)
+    }
+
+    /// Symbols this object expects another object to define
+    pub fn imports(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.binding == Binding::Import)
+    }
+}
+
+pub fn read_object(path: &str) -> io::Result<ObjectFile> {
+    let mut f = BufReader::new(File::open(path)?);
+    read_object_from(&mut f)
+}
+
+pub fn write_object(path: &str, object: &ObjectFile) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    write_object_to(object, &mut f)?;
+    f.flush()
+}
+
+/// True if the bytes start like a relocatable object
+pub fn is_object(bytes: &[u8]) -> bool {
+    bytes.starts_with(OBJECT_MAGIC)
+}
+
+pub fn write_object_to<W: Write>(object: &ObjectFile, w: &mut W) -> io::Result<()> {
+    w.write_all(OBJECT_MAGIC)?;
+    write_u16(w, OBJECT_VERSION)?;
+
+    write_u16(w, object.sections.len() as u16)?;
+    for section in &object.sections {
+        write_name(w, &section.name)?;
+        write_u16(w, section.origin.is_some() as u16)?;
+        write_u16(w, section.origin.unwrap_or_default())?;
+        write_u16(w, section.words.len() as u16)?;
+        for word in &section.words {
+            write_u16(w, *word)?;
+        }
+    }
+
+    write_u16(w, object.symbols.len() as u16)?;
+    for symbol in &object.symbols {
+        write_name(w, &symbol.name)?;
+        let binding = match symbol.binding {
+            Binding::Local => 0,
+            Binding::Export => 1,
+            Binding::Import => 2,
+        };
+        write_u16(w, binding)?;
+        write_u16(w, symbol.section)?;
+        write_u16(w, symbol.offset)?;
+    }
+
+    write_u16(w, object.relocations.len() as u16)?;
+    for relocation in &object.relocations {
+        let kind = match relocation.kind {
+            RelocationKind::Offset9 => 0,
+            RelocationKind::Offset11 => 1,
+            RelocationKind::Offset6 => 2,
+            RelocationKind::Word => 3,
+        };
+        write_u16(w, relocation.section)?;
+        write_u16(w, relocation.offset)?;
+        write_u16(w, kind)?;
+        write_u16(w, relocation.symbol)?;
+        write_u16(w, relocation.addend as u16)?;
+    }
+    Ok(())
+}
+
+pub fn read_object_from<R: Read>(r: &mut R) -> io::Result<ObjectFile> {
+    let mut magic = [0_u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != OBJECT_MAGIC {
+        return Err(invalid_data("not a relocatable lc3 object"));
+    }
+    if read_u16(r)? != OBJECT_VERSION {
+        return Err(invalid_data("unsupported object version"));
+    }
+
+    let mut object = ObjectFile::new();
+    for _ in 0..read_u16(r)? {
+        let name = read_name(r)?;
+        let fixed = read_u16(r)? != 0;
+        let origin = read_u16(r)?;
+        let mut words = vec![];
+        for _ in 0..read_u16(r)? {
+            words.push(read_u16(r)?);
+        }
+        object.sections.push(Section {
+            name,
+            origin: fixed.then_some(origin),
+            words,
+        });
+    }
+
+    for _ in 0..read_u16(r)? {
+        let name = read_name(r)?;
+        let binding = match read_u16(r)? {
+            0 => Binding::Local,
+            1 => Binding::Export,
+            2 => Binding::Import,
+            _ => return Err(invalid_data("invalid symbol binding")),
+        };
+        let section = read_u16(r)?;
+        let offset = read_u16(r)?;
+        if binding != Binding::Import && section as usize >= object.sections.len() {
+            return Err(invalid_data("symbol in a missing section"));
+        }
+        object.symbols.push(Symbol {
+            name,
+            binding,
+            section,
+            offset,
+        });
+    }
+
+    for _ in 0..read_u16(r)? {
+        let section = read_u16(r)?;
+        let offset = read_u16(r)?;
+        let kind = match read_u16(r)? {
+            0 => RelocationKind::Offset9,
+            1 => RelocationKind::Offset11,
+            2 => RelocationKind::Offset6,
+            3 => RelocationKind::Word,
+            _ => return Err(invalid_data("invalid relocation kind")),
+        };
+        let symbol = read_u16(r)?;
+        let addend = read_u16(r)? as i16;
+        let in_section = object
+            .sections
+            .get(section as usize)
+            .is_some_and(|s| (offset as usize) < s.words.len());
+        if !in_section || symbol as usize >= object.symbols.len() {
+            return Err(invalid_data("relocation out of bounds"));
+        }
+        object.relocations.push(Relocation {
+            section,
+            offset,
+            kind,
+            symbol,
+            addend,
+        });
+    }
+    Ok(object)
+}
+
+fn write_name<W: Write>(w: &mut W, name: &str) -> io::Result<()> {
+    write_u16(w, name.len() as u16)?;
+    w.write_all(name.as_bytes())
+}
+
+fn read_name<R: Read>(r: &mut R) -> io::Result<String> {
+    let mut bytes = vec![0_u8; read_u16(r)? as usize];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("name is not UTF-8"))
+}
+
+fn write_u16<W: Write>(w: &mut W, value: u16) -> io::Result<()> {
+    w.write_all(&value.to_be_bytes())
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut buffer = [0_u8; 2];
+    r.read_exact(&mut buffer)?;
+    Ok(u16::from_be_bytes(buffer))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::{
+        read_object_from, write_object_to, Binding, ObjectFile, Relocation, RelocationKind,
+        Section, Symbol,
+    };
+
+    #[test]
+    fn test_object_round_trip() {
+        let object = ObjectFile {
+            sections: vec![
+                Section {
+                    name: "text".to_string(),
+                    origin: None,
+                    words: vec![0x4800, 0xc1c0],
+                },
+                Section {
+                    name: "vectors".to_string(),
+                    origin: Some(0x0100),
+                    words: vec![0x0000],
+                },
+            ],
+            symbols: vec![
+                Symbol {
+                    name: "MAIN".to_string(),
+                    binding: Binding::Export,
+                    section: 0,
+                    offset: 0,
+                },
+                Symbol {
+                    name: "PRINT_NUM".to_string(),
+                    binding: Binding::Import,
+                    section: 0,
+                    offset: 0,
+                },
+            ],
+            relocations: vec![Relocation {
+                section: 0,
+                offset: 0,
+                kind: RelocationKind::Offset11,
+                symbol: 1,
+                addend: -1,
+            }],
+        };
+
+        let mut buffer = vec![];
+        write_object_to(&object, &mut buffer).unwrap();
+        assert_eq!(read_object_from(&mut buffer.as_slice()).unwrap(), object);
+
+        // relocation pointing past the end of its section
+        let last = buffer.len() - 10;
+        buffer[last..last + 2].copy_from_slice(&5_u16.to_be_bytes());
+        assert!(read_object_from(&mut buffer.as_slice()).is_err());
+    }
+}
@@ -1,9 +1,8 @@
 use crate::decode_instruction::DecodedInstruction;
-use crate::vm::{update_flags, Register, MEMORY_SIZE, VM};
-use std::io::{Read, Write};
+use crate::vm::{update_flags, Register, VM};
 
-/// For complete opcode specification
-/// see: https://icourse.club/uploads/files/a9710bf2454961912f79d89b25ba33c4841f6c24.pdf
+// For complete opcode specification
+// see: https://icourse.club/uploads/files/a9710bf2454961912f79d89b25ba33c4841f6c24.pdf
 
 pub fn add_opcode(vm: &mut VM, instruction: DecodedInstruction) {
     if instruction.flag == 1 {
@@ -83,15 +82,16 @@ pub fn jmp_opcode(vm: &mut VM, instruction: DecodedInstruction) {
 }
 
 pub fn jsr_opcode(vm: &mut VM, instruction: DecodedInstruction) {
+    // read the base register first, JSRR R7 jumps to the old R7
+    let base = vm.reg(instruction.base_r);
     *vm.reg_mut(Register::R7.into()) = vm.reg(Register::PC.into());
     if instruction.flag == 1 {
         // JSR
         *vm.reg_mut(Register::PC.into()) =
             vm.reg(Register::PC.into()).wrapping_add(instruction.offset);
     } else {
-        // JSSR
-        *vm.reg_mut(Register::PC.into()) =
-            vm.reg(Register::PC.into()).wrapping_add(instruction.base_r);
+        // JSRR
+        *vm.reg_mut(Register::PC.into()) = base;
     }
 }
 
@@ -102,64 +102,82 @@ pub fn trap_opcode(vm: &mut VM, instruction: DecodedInstruction) {
         0x22 => trap_puts(vm),
         0x23 => trap_in(vm),
         0x24 => trap_putsp(vm),
-        0x25 => trap_halt(),
-        _ => unreachable!(),
+        0x25 => trap_halt(vm),
+        code => {
+            let pc = vm.reg(Register::PC.into()).wrapping_sub(1);
+            vm.fault(format!("illegal trap x{:02X} at x{:04X}", code, pc));
+        }
     }
 }
 
 /// Get character from the keyboard and store into R0
 fn trap_get_c(vm: &mut VM) {
-    let mut buffer = [0, 1];
-    std::io::stdin().read_exact(&mut buffer).unwrap();
-    *vm.reg_mut(Register::R0.into()) = buffer[0] as u16;
-    update_flags(vm, Register::R0.into());
+    match vm.console().read_key() {
+        Some(key) => {
+            vm.input_received();
+            *vm.reg_mut(Register::R0.into()) = key as u16;
+            update_flags(vm, Register::R0.into());
+        }
+        None => vm.wait_for_input(),
+    }
 }
 
 /// Outputs a character
 fn trap_out(vm: &mut VM) {
-    print!("{}", vm.reg(Register::R0.into()) as u8 as char);
+    let c = vm.reg(Register::R0.into()) as u8 as char;
+    vm.console().write(&c.to_string());
 }
 
 /// Starting from mem_addr = R0, print each cell as a character
 /// until last memory cell is reached or 0 is encountered
 fn trap_puts(vm: &mut VM) {
     let mut mem_addr = vm.reg(Register::R0.into());
+    let mut output = String::new();
     let mut data = vm.mem(mem_addr);
     while data != 0 {
-        print!("{}", data as u8 as char);
-        mem_addr += 1;
+        output.push(data as u8 as char);
+        mem_addr = mem_addr.wrapping_add(1);
         data = vm.mem(mem_addr);
     }
-    std::io::stdout().flush().unwrap();
+    vm.console().write(&output);
 }
 
 fn trap_in(vm: &mut VM) {
-    print!("Enter a character: ");
-    std::io::stdout().flush().unwrap();
-    *vm.reg_mut(Register::R0.into()) = std::io::stdin()
-        .bytes()
-        .next()
-        .and_then(|result| result.ok())
-        .map(|byte| byte as u16)
-        .unwrap();
+    // the prompt was already shown if we are retrying after missing input
+    if !vm.is_awaiting_input() {
+        vm.console().write("Enter a character: ");
+    }
+    match vm.console().read_key() {
+        Some(key) => {
+            vm.input_received();
+            *vm.reg_mut(Register::R0.into()) = key as u16;
+            update_flags(vm, Register::R0.into());
+        }
+        None => vm.wait_for_input(),
+    }
 }
 
 /// Same as trap_puts but assumes two characters per word
 fn trap_putsp(vm: &mut VM) {
     let mut mem_addr = vm.reg(Register::R0.into());
-    while mem_addr < MEMORY_SIZE as u16 {
-        let data = vm.mem(mem_addr);
+    let mut output = String::new();
+    let mut data = vm.mem(mem_addr);
+    while data != 0 {
         let first_half = data & mask(8);
         let second_half = data >> 8;
 
-        print!("{}{}", first_half as u8 as char, second_half as u8 as char);
-        mem_addr += 1;
+        output.push(first_half as u8 as char);
+        if second_half != 0 {
+            output.push(second_half as u8 as char);
+        }
+        mem_addr = mem_addr.wrapping_add(1);
+        data = vm.mem(mem_addr);
     }
-    std::io::stdout().flush().unwrap();
+    vm.console().write(&output);
 }
 
-fn trap_halt() {
-    std::process::exit(0);
+fn trap_halt(vm: &mut VM) {
+    vm.halt();
 }
 
 pub const fn mask(n: u8) -> u16 {
@@ -167,9 +185,10 @@ pub const fn mask(n: u8) -> u16 {
 }
 
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
 mod tests {
     use crate::decode_instruction::decode_instruction;
-    use crate::opcodes::{add_opcode, ldi_opcode, mask};
+    use crate::opcodes::{add_opcode, jsr_opcode, ldi_opcode, mask};
     use crate::vm::{Opcode, Register, VM};
 
     // (instr_value, instr_bit_count)
@@ -277,6 +296,30 @@ mod tests {
         assert_eq!(vm.reg(Register::R2.into()), 42);
     }
 
+    #[test]
+    fn test_jsr_opcode() {
+        let mut vm = VM::init();
+        *vm.reg_mut(Register::PC.into()) = 0x3001;
+        *vm.reg_mut(Register::R2.into()) = 0x4000;
+
+        // JSR #16
+        let jsr = encode_instruction(vec![encode_opcode(Opcode::JSR), (1, 1), (16, 11)]);
+        jsr_opcode(&mut vm, decode_instruction(jsr));
+        assert_eq!(vm.reg(Register::PC.into()), 0x3011);
+        assert_eq!(vm.reg(Register::R7.into()), 0x3001);
+
+        // JSRR R2
+        let jsrr = encode_instruction(vec![
+            encode_opcode(Opcode::JSR),
+            (0, 3),
+            encode_register(Register::R2),
+            (0, 6),
+        ]);
+        jsr_opcode(&mut vm, decode_instruction(jsrr));
+        assert_eq!(vm.reg(Register::PC.into()), 0x4000);
+        assert_eq!(vm.reg(Register::R7.into()), 0x3011);
+    }
+
     #[test]
     fn test_neg_addition() {
         // add two numbers, one is negative
@@ -0,0 +1,417 @@
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::source::{Diagnostic, Severity, Sources, Span};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// nested macro expansions deeper than this are assumed to be recursive
+const MAX_EXPANSION_DEPTH: usize = 32;
+
+/// A source line after includes, defines and macros were expanded
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub tokens: Vec<Token>,
+    // the whole line in the file it was written in
+    pub span: Span,
+    // (call site, macro name) for every macro this line came from, outermost first
+    pub expansions: Vec<(Span, String)>,
+}
+
+impl Line {
+    /// Warning at span, with the same notes as an error
+    pub fn warning(&self, span: Span, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..self.error(span, message)
+        }
+    }
+
+    /// Error at span, with a note for every macro expansion that produced the line
+    pub fn error(&self, span: Span, message: impl Into<String>) -> Diagnostic {
+        let mut diagnostic = Diagnostic::new(span, message);
+        // the error is in an argument, also show the line of the macro using it
+        if let Some((_, name)) = self.expansions.last() {
+            if (span.file, span.line) != (self.span.file, self.span.line) {
+                let note = format!("in this line of macro {}", name);
+                diagnostic = diagnostic.with_note(self.span, note);
+            }
+        }
+        for (call, name) in self.expansions.iter().rev() {
+            diagnostic = diagnostic.with_note(*call, format!("in expansion of macro {}", name));
+        }
+        diagnostic
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Line>,
+    span: Span,
+}
+
+/// Expand `.INCLUDE`, `.DEFINE` and `.MACRO` / `.ENDM`
+///
+///     .MACRO PUSH reg          ; parameters are used by name
+///     ADD R6, R6, #-1
+///     STR reg, R6, #0
+///     .ENDM
+///
+/// Labels starting with @ are local to one expansion of a macro.
+pub fn preprocess(sources: &mut Sources, file: usize) -> Result<Vec<Line>, Diagnostic> {
+    let mut preprocessor = Preprocessor {
+        macros: HashMap::new(),
+        defines: HashMap::new(),
+        include_stack: vec![],
+        expansion_count: 0,
+        output: vec![],
+    };
+    preprocessor.process_file(sources, file)?;
+    Ok(preprocessor.output)
+}
+
+struct Preprocessor {
+    // upper cased name -> macro
+    macros: HashMap<String, Macro>,
+    defines: HashMap<String, Vec<Token>>,
+    include_stack: Vec<PathBuf>,
+    // numbers the local labels of every expansion
+    expansion_count: usize,
+    output: Vec<Line>,
+}
+
+impl Preprocessor {
+    fn process_file(&mut self, sources: &mut Sources, file: usize) -> Result<(), Diagnostic> {
+        let path = sources.file(file).path.clone();
+        self.include_stack.push(path.canonicalize().unwrap_or(path));
+
+        let text = sources.file(file).text.clone();
+        // (name, definition) of the macro being recorded
+        let mut recording: Option<(String, Macro)> = None;
+        for (number, text) in text.lines().enumerate() {
+            let tokens = tokenize(text, file, number + 1)?;
+            let line = Line {
+                tokens,
+                span: Span {
+                    file,
+                    line: number + 1,
+                    column: 1,
+                    len: text.chars().count(),
+                },
+                expansions: vec![],
+            };
+
+            if recording.is_some() {
+                match directive(&line) {
+                    Some("ENDM") => {
+                        let (name, definition) = recording.take().unwrap();
+                        self.macros.insert(name, definition);
+                    }
+                    Some("MACRO") => {
+                        return Err(line.error(line.tokens[0].span, "nested .MACRO definition"))
+                    }
+                    _ => recording.as_mut().unwrap().1.body.push(line),
+                }
+                continue;
+            }
+
+            match directive(&line) {
+                Some("MACRO") => recording = Some(self.macro_header(&line)?),
+                Some("ENDM") => return Err(line.error(line.tokens[0].span, ".ENDM without .MACRO")),
+                Some("DEFINE") => self.define(&line)?,
+                Some("INCLUDE") => self.include(sources, &line)?,
+                _ => {
+                    let line = self.substitute_defines(line);
+                    self.emit(line, 0)?;
+                }
+            }
+        }
+
+        if let Some((name, definition)) = recording {
+            return Err(Diagnostic::new(
+                definition.span,
+                format!("missing .ENDM for macro {}", name),
+            ));
+        }
+        self.include_stack.pop();
+        Ok(())
+    }
+
+    /// `.MACRO NAME a, b`
+    fn macro_header(&self, line: &Line) -> Result<(String, Macro), Diagnostic> {
+        let Some(name) = line.tokens.get(1).and_then(|token| token.ident()) else {
+            return Err(line.error(line.tokens[0].span, "expected a macro name after .MACRO"));
+        };
+        let mut params = vec![];
+        for (i, token) in line.tokens[2..].iter().enumerate() {
+            match (&token.kind, i % 2) {
+                (TokenKind::Ident(param), 0) => params.push(param.to_ascii_uppercase()),
+                (TokenKind::Comma, 1) => {}
+                _ => return Err(line.error(token.span, "expected a parameter name")),
+            }
+        }
+        let definition = Macro {
+            params,
+            body: vec![],
+            span: line.tokens[1].span,
+        };
+        Ok((name.to_ascii_uppercase(), definition))
+    }
+
+    /// `.DEFINE NAME replacement tokens`
+    fn define(&mut self, line: &Line) -> Result<(), Diagnostic> {
+        let Some(name) = line.tokens.get(1).and_then(|token| token.ident()) else {
+            return Err(line.error(line.tokens[0].span, "expected a name after .DEFINE"));
+        };
+        let replacement = self.substitute_defines(Line {
+            tokens: line.tokens[2..].to_vec(),
+            ..line.clone()
+        });
+        self.defines.insert(name.to_string(), replacement.tokens);
+        Ok(())
+    }
+
+    /// `.INCLUDE "file"`, relative to the including file
+    fn include(&mut self, sources: &mut Sources, line: &Line) -> Result<(), Diagnostic> {
+        let (Some(TokenKind::Str(name)), 2) =
+            (line.tokens.get(1).map(|t| &t.kind), line.tokens.len())
+        else {
+            return Err(line.error(
+                line.tokens[0].span,
+                "expected a quoted file name after .INCLUDE",
+            ));
+        };
+        let span = line.tokens[1].span;
+        let including = &sources.file(line.span.file).path;
+        let path = including.parent().unwrap_or(including).join(name);
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if self.include_stack.contains(&canonical) {
+            return Err(line.error(span, format!("{} includes itself", name)));
+        }
+        let file = sources
+            .load(&path)
+            .map_err(|e| line.error(span, format!("can not include {}: {}", name, e)))?;
+        self.process_file(sources, file)
+    }
+
+    fn substitute_defines(&self, mut line: Line) -> Line {
+        if self.defines.is_empty() {
+            return line;
+        }
+        let mut tokens = vec![];
+        for token in line.tokens {
+            match token.ident().and_then(|name| self.defines.get(name)) {
+                // replacements point at the use so errors show where the name was written
+                Some(replacement) => tokens.extend(replacement.iter().map(|t| Token {
+                    kind: t.kind.clone(),
+                    span: token.span,
+                })),
+                None => tokens.push(token),
+            }
+        }
+        line.tokens = tokens;
+        line
+    }
+
+    /// Output a line, expanding it if it calls a macro
+    fn emit(&mut self, line: Line, depth: usize) -> Result<(), Diagnostic> {
+        let Some((label_len, name)) = self.macro_call(&line) else {
+            self.output.push(line);
+            return Ok(());
+        };
+        let call = line.tokens[label_len].span;
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(line.error(call, format!("macro {} expands too deeply", name)));
+        }
+
+        if label_len > 0 {
+            self.output.push(Line {
+                tokens: line.tokens[..label_len].to_vec(),
+                ..line.clone()
+            });
+        }
+
+        self.expansion_count += 1;
+        let id = self.expansion_count;
+        let args = split_arguments(&line.tokens[label_len + 1..]);
+        let definition = &self.macros[&name];
+        if args.len() != definition.params.len() {
+            return Err(line
+                .error(
+                    call,
+                    format!(
+                        "macro {} takes {} arguments, {} given",
+                        name,
+                        definition.params.len(),
+                        args.len()
+                    ),
+                )
+                .with_note(definition.span, "macro defined here"));
+        }
+
+        let mut expansions = line.expansions.clone();
+        expansions.push((call, name.clone()));
+        let mut body = vec![];
+        for body_line in &definition.body {
+            let mut tokens = vec![];
+            for token in &body_line.tokens {
+                let param = token.ident().and_then(|ident| {
+                    definition
+                        .params
+                        .iter()
+                        .position(|param| param.eq_ignore_ascii_case(ident))
+                });
+                match (param, token.ident()) {
+                    (Some(param), _) => tokens.extend(args[param].iter().cloned()),
+                    (None, Some(local)) if local.starts_with('@') => tokens.push(Token {
+                        kind: TokenKind::Ident(format!("{}.{}", local, id)),
+                        span: token.span,
+                    }),
+                    _ => tokens.push(token.clone()),
+                }
+            }
+            body.push(Line {
+                tokens,
+                span: body_line.span,
+                expansions: expansions.clone(),
+            });
+        }
+
+        for body_line in body {
+            let body_line = self.substitute_defines(body_line);
+            self.emit(body_line, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    /// (number of label tokens before the name, macro name) if the line calls a macro
+    fn macro_call(&self, line: &Line) -> Option<(usize, String)> {
+        let is_macro = |i: usize| {
+            let name = line.tokens.get(i)?.ident()?.to_ascii_uppercase();
+            self.macros.contains_key(&name).then_some(name)
+        };
+        if let Some(name) = is_macro(0) {
+            return Some((0, name));
+        }
+        line.tokens.first()?.ident()?;
+        let label_len = match line.tokens.get(1).map(|token| &token.kind) {
+            Some(TokenKind::Colon) => 2,
+            _ => 1,
+        };
+        is_macro(label_len).map(|name| (label_len, name))
+    }
+}
+
+/// Upper cased directive the line starts with
+fn directive(line: &Line) -> Option<&str> {
+    match &line.tokens.first()?.kind {
+        TokenKind::Directive(name) => Some(name),
+        _ => None,
+    }
+}
+
+/// Split macro arguments at commas outside parentheses
+fn split_arguments(tokens: &[Token]) -> Vec<Vec<Token>> {
+    if tokens.is_empty() {
+        return vec![];
+    }
+    let mut args = vec![vec![]];
+    let mut depth = 0;
+    for token in tokens {
+        match token.kind {
+            TokenKind::Comma if depth == 0 => {
+                args.push(vec![]);
+                continue;
+            }
+            TokenKind::LParen => depth += 1,
+            TokenKind::RParen => depth -= 1,
+            _ => {}
+        }
+        args.last_mut().unwrap().push(token.clone());
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::preprocessor::preprocess;
+    use crate::source::Sources;
+    use std::path::Path;
+
+    fn expand(text: &str) -> Vec<String> {
+        let mut sources = Sources::new();
+        let file = sources.add(Path::new("test.asm"), text.to_string());
+        preprocess(&mut sources, file)
+            .unwrap()
+            .iter()
+            .map(|line| {
+                let tokens: Vec<String> = line
+                    .tokens
+                    .iter()
+                    .map(|token| format!("{:?}", token.kind))
+                    .collect();
+                tokens.join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_macro_expansion() {
+        let lines = expand(
+            ".MACRO COUNTDOWN reg, n
+             ADD reg, reg, n
+@LOOP        ADD reg, reg, #-1
+             BRp @LOOP
+             .ENDM
+.DEFINE STEP #2
+START        COUNTDOWN R1, STEP
+             COUNTDOWN R2, #3",
+        );
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0], "Ident(\"START\")");
+        assert_eq!(
+            lines[1],
+            "Ident(\"ADD\") Ident(\"R1\") Comma Ident(\"R1\") Comma Number(2)"
+        );
+        assert!(lines[2].starts_with("Ident(\"@LOOP.1\")"));
+        assert!(lines[6].ends_with("Ident(\"@LOOP.2\")"));
+    }
+
+    #[test]
+    fn test_macro_errors_point_at_definition_and_call() {
+        let mut sources = Sources::new();
+        let text = ".MACRO TWICE a\nTWICE a\n.ENDM\nTWICE R1";
+        let file = sources.add(Path::new("test.asm"), text.to_string());
+        let error = preprocess(&mut sources, file).unwrap_err();
+        assert_eq!(error.message, "macro TWICE expands too deeply");
+        assert_eq!(error.span.line, 2);
+        assert_eq!(error.notes.last().unwrap().0.line, 4);
+
+        let mut sources = Sources::new();
+        let text = ".MACRO ONE a\n.ENDM\nONE";
+        let file = sources.add(Path::new("test.asm"), text.to_string());
+        let error = preprocess(&mut sources, file).unwrap_err();
+        assert_eq!(error.message, "macro ONE takes 1 arguments, 0 given");
+        assert_eq!(error.notes[0].0.line, 1);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join("lc3_include_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.asm"), ".MACRO HALT2\nHALT\n.ENDM\n").unwrap();
+        let main = dir.join("main.asm");
+        std::fs::write(&main, ".INCLUDE \"lib.asm\"\nHALT2\n").unwrap();
+        std::fs::write(dir.join("loop.asm"), ".INCLUDE \"loop.asm\"\n").unwrap();
+
+        let mut sources = Sources::new();
+        let file = sources.load(&main).unwrap();
+        let lines = preprocess(&mut sources, file).unwrap();
+        assert_eq!(lines.len(), 1);
+        // the expanded line lives in lib.asm
+        assert_eq!(lines[0].span.file, 1);
+
+        let mut sources = Sources::new();
+        let file = sources.load(&dir.join("loop.asm")).unwrap();
+        let error = preprocess(&mut sources, file).unwrap_err();
+        assert_eq!(error.message, "loop.asm includes itself");
+    }
+}
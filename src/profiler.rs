@@ -0,0 +1,335 @@
+use crate::debuginfo::DebugInfo;
+use crate::decode_instruction::DecodedInstruction;
+use crate::display::{source_or_disassembly, trap_name};
+use crate::symbols::SymbolTable;
+use crate::vm::{Opcode, Tracer, MEMORY_SIZE, VM};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// number of addresses listed in the hotspot report
+const HOTSPOT_COUNT: usize = 25;
+
+/// Counts executed instructions per address, opcode, trap and subroutine
+///
+/// Subroutines are tracked with the shadow call stack of the VM (JSR / JSRR entry, RET exit).
+/// Every distinct stack is interned once, each instruction then only bumps
+/// the counter of the current stack.
+pub struct Profiler {
+    total: u64,
+    address_counts: Vec<u64>,
+    opcode_counts: [u64; 16],
+    trap_counts: BTreeMap<u16, u64>,
+    calls: HashMap<u16, u64>,
+    // depth of the VM call stack the current stack was interned at
+    depth: usize,
+    // entry address of the code running outside any subroutine
+    root: Option<u16>,
+    stack_ids: HashMap<Vec<u16>, usize>,
+    // (subroutine entries from the root down, instructions executed there)
+    stacks: Vec<(Vec<u16>, u64)>,
+    current_stack: usize,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            total: 0,
+            address_counts: vec![0; MEMORY_SIZE],
+            opcode_counts: [0; 16],
+            trap_counts: BTreeMap::new(),
+            calls: HashMap::new(),
+            depth: 0,
+            root: None,
+            stack_ids: HashMap::new(),
+            stacks: vec![],
+            current_stack: 0,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn address_count(&self, addr: u16) -> u64 {
+        self.address_counts[addr as usize]
+    }
+
+    pub fn opcode_count(&self, opcode: Opcode) -> u64 {
+        self.opcode_counts[u16::from(opcode) as usize]
+    }
+
+    fn intern_current_stack(&mut self, vm: &VM) {
+        let frames = vm.call_stack().frames();
+        self.depth = frames.len();
+        let mut stack: Vec<u16> = self.root.into_iter().collect();
+        stack.extend(frames.iter().map(|frame| frame.entry));
+
+        self.current_stack = match self.stack_ids.get(&stack) {
+            Some(id) => *id,
+            None => {
+                let id = self.stacks.len();
+                self.stack_ids.insert(stack.clone(), id);
+                self.stacks.push((stack, 0));
+                id
+            }
+        };
+    }
+
+    /// (entry, calls, self count, total count) sorted by total count
+    fn subroutines(&self) -> Vec<(u16, u64, u64, u64)> {
+        let mut self_counts: HashMap<u16, u64> = HashMap::new();
+        let mut total_counts: HashMap<u16, u64> = HashMap::new();
+        for (stack, count) in &self.stacks {
+            if let Some(entry) = stack.last() {
+                *self_counts.entry(*entry).or_default() += count;
+            }
+            // recursive calls count once towards the total
+            let unique: HashSet<&u16> = stack.iter().collect();
+            for entry in unique {
+                *total_counts.entry(*entry).or_default() += count;
+            }
+        }
+
+        let mut subroutines: Vec<(u16, u64, u64, u64)> = total_counts
+            .into_iter()
+            .map(|(entry, total)| {
+                let calls = self.calls.get(&entry).copied().unwrap_or_default();
+                let self_count = self_counts.get(&entry).copied().unwrap_or_default();
+                (entry, calls, self_count, total)
+            })
+            .collect();
+        subroutines.sort_by(|a, b| b.3.cmp(&a.3).then(a.0.cmp(&b.0)));
+        subroutines
+    }
+
+    /// Human readable report, hottest entries first
+    pub fn report(&self, vm: &mut VM, symbols: &SymbolTable, debug: &DebugInfo) -> String {
+        let mut report = format!("instructions executed: {}\n", self.total);
+
+        report += "\nhotspots\n";
+        report += &format!(
+            "{:>10} {:>7}  {:<16} {}\n",
+            "count", "%", "address", "instruction"
+        );
+        let mut addresses: Vec<usize> = (0..MEMORY_SIZE)
+            .filter(|addr| self.address_counts[*addr] > 0)
+            .collect();
+        addresses.sort_by(|a, b| {
+            self.address_counts[*b]
+                .cmp(&self.address_counts[*a])
+                .then(a.cmp(b))
+        });
+        for addr in addresses.into_iter().take(HOTSPOT_COUNT) {
+            let count = self.address_counts[addr];
+            let addr = addr as u16;
+            let word = *vm.mem_mut(addr);
+            report += &format!(
+                "{:>10} {:>6.1}%  {:<16} {}\n",
+                count,
+                self.percent(count),
+                symbols.describe(addr),
+                source_or_disassembly(addr, word, symbols, debug)
+            );
+        }
+
+        report += "\nopcodes\n";
+        let mut opcodes: Vec<(Opcode, u64)> = (0..16)
+            .map(|code| {
+                (
+                    Opcode::try_from(code).unwrap(),
+                    self.opcode_counts[code as usize],
+                )
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        opcodes.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        for (opcode, count) in opcodes {
+            let name = match opcode {
+                // JSR displays as the JS prefix shared with JSRR
+                Opcode::JSR => "JSR".to_string(),
+                _ => opcode.to_string(),
+            };
+            report += &format!(
+                "  {:<6} {:>10} {:>6.1}%\n",
+                name,
+                count,
+                self.percent(count)
+            );
+        }
+
+        if !self.trap_counts.is_empty() {
+            report += "\ntraps\n";
+            let mut traps: Vec<(&u16, &u64)> = self.trap_counts.iter().collect();
+            traps.sort_by(|a, b| b.1.cmp(a.1));
+            for (trap_code, count) in traps {
+                let name = trap_name(*trap_code).unwrap_or("?");
+                report += &format!("  x{:02X} {:<6} {:>10}\n", trap_code, name, count);
+            }
+        }
+
+        report += "\nsubroutines\n";
+        report += &format!(
+            "{:>10} {:>10} {:>7} {:>10} {:>7}  {}\n",
+            "calls", "self", "%", "total", "%", "subroutine"
+        );
+        for (entry, calls, self_count, total) in self.subroutines() {
+            report += &format!(
+                "{:>10} {:>10} {:>6.1}% {:>10} {:>6.1}%  {}\n",
+                calls,
+                self_count,
+                self.percent(self_count),
+                total,
+                self.percent(total),
+                symbols.describe(entry)
+            );
+        }
+        report
+    }
+
+    /// One line per call stack: `root;caller;callee count`
+    /// (the input format of flamegraph.pl and inferno)
+    pub fn folded_stacks(&self, symbols: &SymbolTable) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(stack, count)| {
+                let names: Vec<String> =
+                    stack.iter().map(|entry| symbols.describe(*entry)).collect();
+                format!("{} {}", names.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n") + "\n"
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.total as f64
+        }
+    }
+}
+
+impl Tracer for Profiler {
+    fn after_step(&mut self, vm: &mut VM, pc: u16, instruction: &DecodedInstruction) {
+        if self.root.is_none() {
+            self.root = Some(pc);
+            self.intern_current_stack(vm);
+        }
+
+        self.total += 1;
+        self.address_counts[pc as usize] += 1;
+        self.opcode_counts[u16::from(instruction.opcode) as usize] += 1;
+        if instruction.opcode == Opcode::TRAP {
+            *self.trap_counts.entry(instruction.trap_code).or_default() += 1;
+        }
+        // the instruction belongs to the subroutine it was fetched in
+        self.stacks[self.current_stack].1 += 1;
+
+        // a call pushes one frame, a return pops one
+        let frames = vm.call_stack().frames();
+        if frames.len() != self.depth {
+            if let Some(frame) = frames.last().filter(|_| frames.len() > self.depth) {
+                *self.calls.entry(frame.entry).or_default() += 1;
+            }
+            self.intern_current_stack(vm);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debuginfo::DebugInfo;
+    use crate::profiler::Profiler;
+    use crate::symbols::SymbolTable;
+    use crate::vm::{Opcode, Register, VM};
+
+    /// main calls MUL twice, MUL loops R2 times
+    fn program() -> VM {
+        let mut vm = VM::init();
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        let words = [
+            0x5020, // x3000 AND R0, R0, #0
+            0x54a0, // x3001 AND R2, R2, #0
+            0x14a3, // x3002 ADD R2, R2, #3
+            0x4803, // x3003 JSR MUL (x3007)
+            0x4802, // x3004 JSR MUL (x3007)
+            0xf021, // x3005 OUT
+            0xf025, // x3006 HALT
+            0x56e0, // x3007 MUL AND R3, R3, #0
+            0x16c2, // x3008 ADD R3, R3, R2
+            0x1021, // x3009 LOOP ADD R0, R0, #1
+            0x16ff, // x300A ADD R3, R3, #-1
+            0x03fd, // x300B BRp LOOP
+            0xc1c0, // x300C RET
+        ];
+        for (i, word) in words.iter().enumerate() {
+            *vm.mem_mut(0x3000 + i as u16) = *word;
+        }
+        vm
+    }
+
+    #[test]
+    fn test_profile_counts() {
+        let mut vm = program();
+        let mut profiler = Profiler::new();
+        vm.run_traced(&mut [&mut profiler]);
+
+        // 7 in main + 2 * (2 + 3 * 3 + 1) in MUL
+        assert_eq!(profiler.total(), 7 + 2 * 12);
+        assert_eq!(profiler.address_count(0x3009), 6);
+        assert_eq!(profiler.opcode_count(Opcode::JSR), 2);
+        assert_eq!(profiler.opcode_count(Opcode::TRAP), 2);
+
+        let subroutines = profiler.subroutines();
+        // root first (it includes everything), then MUL
+        assert_eq!(subroutines[0], (0x3000, 0, 7, 31));
+        assert_eq!(subroutines[1], (0x3007, 2, 24, 24));
+    }
+
+    #[test]
+    fn test_reports() {
+        let mut vm = program();
+        let mut profiler = Profiler::new();
+        vm.run_traced(&mut [&mut profiler]);
+
+        let symbols = SymbolTable::new();
+        assert_eq!(
+            profiler.folded_stacks(&symbols),
+            "x3000 7\nx3000;x3007 24\n"
+        );
+
+        let report = profiler.report(&mut vm, &symbols, &DebugInfo::new());
+        assert!(report.starts_with("instructions executed: 31\n"));
+        let hottest = report.lines().nth(4).unwrap();
+        assert!(hottest.contains("x3009"));
+        assert!(report.contains("x21 OUT"));
+    }
+
+    #[test]
+    fn test_reports_with_symbols() {
+        let mut vm = program();
+        let mut profiler = Profiler::new();
+        vm.run_traced(&mut [&mut profiler]);
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("MAIN", 0x3000);
+        symbols.insert("MUL", 0x3007);
+        symbols.insert("LOOP", 0x3009);
+        assert_eq!(profiler.folded_stacks(&symbols), "MAIN 7\nMAIN;MUL 24\n");
+
+        let report = profiler.report(&mut vm, &symbols, &DebugInfo::new());
+        let hottest = report.lines().nth(4).unwrap();
+        assert!(hottest.contains("LOOP "));
+        assert!(report.contains("MUL+1"));
+        assert!(report.contains("BRp LOOP"));
+        assert!(report.contains("JSR MUL"));
+    }
+}
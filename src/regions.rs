@@ -0,0 +1,266 @@
+use crate::cfg::Cfg;
+use crate::decode_instruction::decode_instruction;
+use crate::display::disassemble;
+use crate::loader::Program;
+use crate::symbols::SymbolTable;
+use crate::vm::Opcode;
+use std::collections::BTreeSet;
+
+// characters an unreferenced run needs to be taken as a string
+const MIN_STRING: usize = 3;
+
+/// A run of words disassembled as one line, or one line per word for packed strings
+#[derive(Debug, PartialEq)]
+pub struct Region {
+    pub addr: u16,
+    pub len: usize,
+    pub kind: RegionKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RegionKind {
+    Instruction,
+    // null terminated ASCII, one character per word
+    Stringz(String),
+    // two characters per word for PUTSP, low byte first, null terminated
+    Packed(String),
+    // zeros
+    Blkw,
+    Fill,
+}
+
+/// Split a program into code and data
+///
+/// Words reached by the control flow from the origin are code. Of the
+/// rest, null terminated ASCII runs are strings, zeros are .BLKW and words
+/// loaded, stored or taken the address of are .FILL, as are the words
+/// following data. Words nothing refers to stay instructions, they may
+/// be reached through JSRR or JMP.
+pub fn regions(program: &Program, symbols: &SymbolTable) -> Vec<Region> {
+    let cfg = Cfg::recover(program);
+    let mut referenced = BTreeSet::new();
+    let mut strings = BTreeSet::new();
+    for addr in program.addresses().filter(|addr| cfg.is_code(*addr)) {
+        let instruction = decode_instruction(word(program, addr));
+        let target = addr.wrapping_add(1).wrapping_add(instruction.offset);
+        match instruction.opcode {
+            Opcode::LEA => {
+                referenced.insert(target);
+                strings.insert(target);
+            }
+            Opcode::LD | Opcode::LDI | Opcode::ST | Opcode::STI => {
+                referenced.insert(target);
+            }
+            _ => {}
+        }
+    }
+
+    let mut regions = vec![];
+    let mut in_data = false;
+    let mut i = 0;
+    while i < program.words.len() {
+        let addr = program.origin.wrapping_add(i as u16);
+        // a region ends where code, a label or a reference starts
+        let limit = (i + 1..program.words.len())
+            .find(|j| {
+                let addr = program.origin.wrapping_add(*j as u16);
+                cfg.is_code(addr) || referenced.contains(&addr) || symbols.label(addr).is_some()
+            })
+            .unwrap_or(program.words.len());
+        let words = &program.words[i..limit];
+        let min = if strings.contains(&addr) {
+            1
+        } else {
+            MIN_STRING
+        };
+
+        let (len, kind) = if cfg.is_code(addr) {
+            (1, RegionKind::Instruction)
+        } else if let Some((len, text)) = stringz(words).filter(|(_, text)| text.len() >= min) {
+            (len, RegionKind::Stringz(text))
+        } else if let Some((len, text)) = packed(words).filter(|(_, text)| text.len() >= min) {
+            (len, RegionKind::Packed(text))
+        } else if words[0] == 0 {
+            let len = words.iter().take_while(|word| **word == 0).count();
+            (len, RegionKind::Blkw)
+        } else if in_data || referenced.contains(&addr) {
+            (1, RegionKind::Fill)
+        } else {
+            (1, RegionKind::Instruction)
+        };
+        in_data = kind != RegionKind::Instruction;
+        regions.push(Region { addr, len, kind });
+        i += len;
+    }
+    regions
+}
+
+/// Disassembly with the data regions written as directives
+pub fn disassembly(program: &Program, symbols: &SymbolTable) -> Vec<(u16, String)> {
+    let mut lines = vec![];
+    for region in regions(program, symbols) {
+        let addr = region.addr;
+        match region.kind {
+            RegionKind::Instruction => {
+                lines.push((addr, disassemble(addr, word(program, addr), symbols)));
+            }
+            RegionKind::Stringz(text) => lines.push((addr, format!(".STRINGZ {}", quote(&text)))),
+            RegionKind::Packed(text) => {
+                for (i, addr) in (addr..).take(region.len).enumerate() {
+                    let mut line = format!(".FILL x{:04X}", word(program, addr));
+                    if i == 0 {
+                        line += &format!(" ; PUTSP {}", quote(&text));
+                    }
+                    lines.push((addr, line));
+                }
+            }
+            RegionKind::Blkw if region.len == 1 => lines.push((addr, ".FILL x0000".to_string())),
+            RegionKind::Blkw => lines.push((addr, format!(".BLKW {}", region.len))),
+            RegionKind::Fill => lines.push((addr, format!(".FILL x{:04X}", word(program, addr)))),
+        }
+    }
+    lines
+}
+
+/// Text found in the program, with the address it starts at and whether it is packed
+pub fn strings(program: &Program, symbols: &SymbolTable) -> Vec<(u16, String, bool)> {
+    regions(program, symbols)
+        .into_iter()
+        .filter_map(|region| match region.kind {
+            RegionKind::Stringz(text) => Some((region.addr, text, false)),
+            RegionKind::Packed(text) => Some((region.addr, text, true)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A string in quotes, with the escapes the assembler reads
+pub fn quote(text: &str) -> String {
+    let mut quoted = "\"".to_string();
+    for c in text.chars() {
+        match c {
+            '\n' => quoted += "\\n",
+            '\t' => quoted += "\\t",
+            '\r' => quoted += "\\r",
+            '\x1b' => quoted += "\\e",
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted + "\""
+}
+
+/// Characters up to a null word, and the words they take with the null
+fn stringz(words: &[u16]) -> Option<(usize, String)> {
+    let len = words.iter().take_while(|word| is_text(**word)).count();
+    if words.get(len) != Some(&0) {
+        return None;
+    }
+    let text = words[..len]
+        .iter()
+        .map(|word| *word as u8 as char)
+        .collect();
+    Some((len + 1, text))
+}
+
+/// Two characters per word up to a null word, the last word may hold one
+fn packed(words: &[u16]) -> Option<(usize, String)> {
+    let mut text = String::new();
+    for (i, word) in words.iter().enumerate() {
+        let (low, high) = (word & 0xFF, word >> 8);
+        if *word == 0 {
+            return Some((i + 1, text));
+        }
+        if !is_text(low) || high != 0 && !is_text(high) {
+            return None;
+        }
+        text.push(low as u8 as char);
+        if high == 0 {
+            // an odd length string ends here
+            return (words.get(i + 1) == Some(&0)).then_some((i + 2, text));
+        }
+        text.push(high as u8 as char);
+    }
+    None
+}
+
+/// Printable ASCII or a control character strings commonly hold
+fn is_text(word: u16) -> bool {
+    matches!(word, 0x20..=0x7E | 0x09 | 0x0A | 0x0D | 0x1B)
+}
+
+fn word(program: &Program, addr: u16) -> u16 {
+    program.words[addr.wrapping_sub(program.origin) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::Program;
+    use crate::regions::{disassembly, regions, strings, RegionKind};
+    use crate::symbols::SymbolTable;
+
+    #[test]
+    fn test_data_regions() {
+        let mut words = vec![
+            0xe004, // x3000 LEA R0, MSG
+            0xf022, // x3001 PUTS
+            0x220c, // x3002 LD R1, COUNT
+            0xf025, // x3003 HALT
+            0x0000, // x3004 .BLKW 1
+        ];
+        // x3005 MSG .STRINGZ "Hi\n"
+        words.extend([0x48, 0x69, 0x0a, 0]);
+        // x3009 .BLKW 2
+        words.extend([0, 0]);
+        // x300B a PUTSP string nothing refers to, "Hello"
+        words.extend([0x6548, 0x6c6c, 0x006f, 0]);
+        // x300F .FILL 7 loaded by LD, x3010 a word after it
+        words.extend([0x0007, 0x1234]);
+        // x3011 follows data, so is data too
+        words.push(0x1021);
+        let program = Program {
+            origin: 0x3000,
+            words,
+        };
+        let mut symbols = SymbolTable::new();
+        symbols.insert("BUFFER", 0x3009);
+
+        let kinds: Vec<(u16, usize, RegionKind)> = regions(&program, &symbols)
+            .into_iter()
+            .map(|region| (region.addr, region.len, region.kind))
+            .collect();
+        assert_eq!(kinds[4], (0x3004, 1, RegionKind::Blkw));
+        assert_eq!(
+            kinds[5],
+            (0x3005, 4, RegionKind::Stringz("Hi\n".to_string()))
+        );
+        assert_eq!(kinds[6], (0x3009, 2, RegionKind::Blkw));
+        assert_eq!(
+            kinds[7],
+            (0x300B, 4, RegionKind::Packed("Hello".to_string()))
+        );
+        assert_eq!(kinds[8], (0x300F, 1, RegionKind::Fill));
+        assert_eq!(kinds[9], (0x3010, 1, RegionKind::Fill));
+        assert_eq!(kinds[10], (0x3011, 1, RegionKind::Fill));
+
+        let lines = disassembly(&program, &symbols);
+        assert_eq!(lines[0], (0x3000, "LEA R0 x3005".to_string()));
+        assert_eq!(lines[5], (0x3005, ".STRINGZ \"Hi\\n\"".to_string()));
+        assert_eq!(lines[6], (0x3009, ".BLKW 2".to_string()));
+        assert_eq!(
+            lines[7],
+            (0x300B, ".FILL x6548 ; PUTSP \"Hello\"".to_string())
+        );
+
+        assert_eq!(
+            strings(&program, &symbols),
+            vec![
+                (0x3005, "Hi\n".to_string(), false),
+                (0x300B, "Hello".to_string(), true)
+            ]
+        );
+    }
+}
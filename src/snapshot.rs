@@ -0,0 +1,199 @@
+use crate::callstack::{CallStack, Frame};
+use crate::vm::{MEMORY_SIZE, REGISTER_COUNT, VM};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// Snapshot file layout (all words big endian, same as .obj files)
+///   - magic "LC3S" (4 bytes)
+///   - format version (u16)
+///   - running state (u16, 0 or 1)
+///   - registers (REGISTER_COUNT words, R0 - R7, PC, COND)
+///   - memory (MEMORY_SIZE words)
+///
+/// Version 2 adds:
+///   - waiting for input (u16, 0 or 1)
+///   - error (u16 byte length, UTF-8 bytes), empty if there is none
+///   - call stack depth (u16), then per frame from the outermost:
+///     entry, call site, return address
+///
+/// Device state lives in the memory mapped registers (KBSR, KBDR)
+/// so it is captured along with the rest of memory.
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"LC3S";
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+/// Serialize the complete VM state to a snapshot file
+pub fn save_snapshot(vm: &mut VM, path: &str) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(path)?);
+    write_snapshot(vm, &mut f)?;
+    f.flush()
+}
+
+/// Restore a VM from a snapshot file
+pub fn load_snapshot(path: &str) -> io::Result<VM> {
+    let mut f = BufReader::new(File::open(path)?);
+    read_snapshot(&mut f)
+}
+
+pub fn write_snapshot<W: Write>(vm: &mut VM, w: &mut W) -> io::Result<()> {
+    w.write_all(SNAPSHOT_MAGIC)?;
+    write_u16(w, SNAPSHOT_VERSION)?;
+    write_u16(w, vm.is_running() as u16)?;
+    for addr in 0..REGISTER_COUNT {
+        write_u16(w, vm.reg(addr as u16))?;
+    }
+    // read through mem_mut so the keyboard device is not polled
+    for addr in 0..MEMORY_SIZE {
+        write_u16(w, *vm.mem_mut(addr as u16))?;
+    }
+
+    write_u16(w, vm.is_awaiting_input() as u16)?;
+    let error = vm.error().unwrap_or_default();
+    write_u16(w, error.len() as u16)?;
+    w.write_all(error.as_bytes())?;
+    let frames = vm.call_stack().frames();
+    write_u16(w, frames.len() as u16)?;
+    for frame in frames {
+        write_u16(w, frame.entry)?;
+        write_u16(w, frame.call_site)?;
+        write_u16(w, frame.return_addr)?;
+    }
+    Ok(())
+}
+
+pub fn read_snapshot<R: Read>(r: &mut R) -> io::Result<VM> {
+    let mut magic = [0_u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(invalid_data("not an lc3 snapshot"));
+    }
+
+    // version 1 ends after memory
+    let version = read_u16(r)?;
+    if version != 1 && version != SNAPSHOT_VERSION {
+        return Err(invalid_data("unsupported snapshot version"));
+    }
+
+    let mut vm = VM::init();
+    vm.set_running(read_u16(r)? != 0);
+    for addr in 0..REGISTER_COUNT {
+        *vm.reg_mut(addr as u16) = read_u16(r)?;
+    }
+    for addr in 0..MEMORY_SIZE {
+        *vm.mem_mut(addr as u16) = read_u16(r)?;
+    }
+    if version == 1 {
+        return Ok(vm);
+    }
+
+    vm.set_awaiting_input(read_u16(r)? != 0);
+    let mut error = vec![0_u8; read_u16(r)? as usize];
+    r.read_exact(&mut error)?;
+    let error = String::from_utf8(error).map_err(|_| invalid_data("error is not UTF-8"))?;
+    vm.set_error((!error.is_empty()).then_some(error));
+    let mut call_stack = CallStack::new();
+    for _ in 0..read_u16(r)? {
+        call_stack.push(Frame {
+            entry: read_u16(r)?,
+            call_site: read_u16(r)?,
+            return_addr: read_u16(r)?,
+        });
+    }
+    vm.set_call_stack(call_stack);
+    Ok(vm)
+}
+
+fn write_u16<W: Write>(w: &mut W, value: u16) -> io::Result<()> {
+    w.write_all(&value.to_be_bytes())
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut buffer = [0_u8; 2];
+    r.read_exact(&mut buffer)?;
+    Ok(u16::from_be_bytes(buffer))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::snapshot::{read_snapshot, write_snapshot, SNAPSHOT_VERSION};
+    use crate::vm::{Register, MEMORY_SIZE, REGISTER_COUNT, VM};
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut vm = VM::init();
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        *vm.reg_mut(Register::R6.into()) = 0xfdff;
+        *vm.mem_mut(0x3000) = 0xf025;
+        *vm.mem_mut(0xffff) = 42;
+        // JSR x3010 at x3001, the subroutine is about to run
+        *vm.mem_mut(0x3001) = 0x480e;
+        *vm.reg_mut(Register::PC.into()) = 0x3001;
+        vm.step();
+        vm.set_running(true);
+
+        let mut buffer = vec![];
+        write_snapshot(&mut vm, &mut buffer).unwrap();
+
+        let mut restored = read_snapshot(&mut buffer.as_slice()).unwrap();
+        assert!(restored.is_running());
+        assert!(!restored.is_awaiting_input());
+        assert_eq!(restored.error(), None);
+        assert_eq!(restored.call_stack().frames(), vm.call_stack().frames());
+        assert_eq!(restored.call_stack().frames()[0].call_site, 0x3001);
+        assert_eq!(restored.reg(Register::PC.into()), 0x3010);
+        assert_eq!(restored.reg(Register::R6.into()), 0xfdff);
+        assert_eq!(*restored.mem_mut(0x3000), 0xf025);
+        assert_eq!(*restored.mem_mut(0xffff), 42);
+    }
+
+    #[test]
+    fn test_snapshot_rejects_bad_header() {
+        let mut vm = VM::init();
+        let mut buffer = vec![];
+        write_snapshot(&mut vm, &mut buffer).unwrap();
+
+        let mut bad_magic = buffer.clone();
+        bad_magic[0] = b'X';
+        assert!(read_snapshot(&mut bad_magic.as_slice()).is_err());
+
+        let mut bad_version = buffer.clone();
+        bad_version[4..6].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_be_bytes());
+        assert!(read_snapshot(&mut bad_version.as_slice()).is_err());
+
+        assert!(read_snapshot(&mut &buffer[..100]).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_keeps_the_error() {
+        let mut vm = VM::init();
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        *vm.mem_mut(0x3000) = 0xf030; // TRAP x30
+        vm.set_running(true);
+        vm.step();
+
+        let mut buffer = vec![];
+        write_snapshot(&mut vm, &mut buffer).unwrap();
+        let restored = read_snapshot(&mut buffer.as_slice()).unwrap();
+        assert_eq!(restored.error(), Some("illegal trap x30 at x3000"));
+        assert_eq!(restored.reg(Register::PC.into()), 0x3000);
+    }
+
+    #[test]
+    fn test_snapshot_reads_version_1() {
+        let mut vm = VM::init();
+        *vm.mem_mut(0x3000) = 0xf025;
+        let mut buffer = vec![];
+        write_snapshot(&mut vm, &mut buffer).unwrap();
+        // a version 1 snapshot ends after memory
+        buffer.truncate(4 + 2 + 2 + 2 * (REGISTER_COUNT + MEMORY_SIZE));
+        buffer[4..6].copy_from_slice(&1_u16.to_be_bytes());
+
+        let mut restored = read_snapshot(&mut buffer.as_slice()).unwrap();
+        assert_eq!(*restored.mem_mut(0x3000), 0xf025);
+        assert_eq!(restored.call_stack().depth(), 0);
+    }
+}
@@ -0,0 +1,161 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Source files of one assembly, spans refer to them by index
+#[derive(Default)]
+pub struct Sources {
+    files: Vec<SourceFile>,
+}
+
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub text: String,
+}
+
+impl Sources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(&mut self, path: &Path) -> io::Result<usize> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(self.add(path, text))
+    }
+
+    /// Add a file that is already in memory, returns its index
+    pub fn add(&mut self, path: &Path, text: String) -> usize {
+        self.files.push(SourceFile {
+            path: path.to_path_buf(),
+            text,
+        });
+        self.files.len() - 1
+    }
+
+    pub fn file(&self, file: usize) -> &SourceFile {
+        &self.files[file]
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    /// Text of a line, 1 based
+    pub fn line(&self, span: &Span) -> &str {
+        let Some(index) = span.line.checked_sub(1) else {
+            return "";
+        };
+        self.files[span.file]
+            .text
+            .lines()
+            .nth(index)
+            .unwrap_or_default()
+    }
+}
+
+/// Location in a source file, line and column are 1 based
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+impl Span {
+    /// Span covering self through other, both on the same line
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            len: (other.column + other.len).saturating_sub(self.column),
+            ..*self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// An error or warning with the place it was found and the places that led there
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Span,
+    pub message: String,
+    pub notes: Vec<(Span, String)>,
+}
+
+impl Diagnostic {
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            span,
+            message: message.into(),
+            notes: vec![],
+        }
+    }
+
+    pub fn warning(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::new(span, message)
+        }
+    }
+
+    pub fn with_note(mut self, span: Span, note: impl Into<String>) -> Self {
+        self.notes.push((span, note.into()));
+        self
+    }
+
+    /// `file:line:column: error: message` and the line with the span
+    /// underlined, then the same for every note
+    ///
+    /// ```text
+    /// test.asm:6:13: error: 100 does not fit in imm5 (-16..15)
+    ///   6 |     ADD R1, #100
+    ///     |             ^^^^
+    /// ```
+    pub fn render(&self, sources: &Sources) -> String {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let mut text = snippet(sources, &self.span, severity, &self.message);
+        for (span, note) in &self.notes {
+            text += &snippet(sources, span, "note", note);
+        }
+        text
+    }
+}
+
+fn snippet(sources: &Sources, span: &Span, label: &str, message: &str) -> String {
+    let mut text = format!(
+        "{}:{}:{}: {}: {}\n",
+        sources.file(span.file).path.display(),
+        span.line,
+        span.column,
+        label,
+        message
+    );
+    let line = sources.line(span);
+    if span.line == 0 || line.trim().is_empty() {
+        return text;
+    }
+    let gutter = span.line.to_string().len();
+    // tabs are copied so the caret lines up however wide they are shown
+    let indent: String = line
+        .chars()
+        .take(span.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    text += &format!("{:>w$} | {}\n", span.line, line, w = gutter + 1);
+    text += &format!(
+        "{:>w$} | {}{}\n",
+        "",
+        indent,
+        "^".repeat(span.len.max(1)),
+        w = gutter + 1
+    );
+    text
+}
@@ -0,0 +1,154 @@
+use crate::callstack::backtrace;
+use crate::decode_instruction::DecodedInstruction;
+use crate::display::address;
+use crate::symbols::SymbolTable;
+use crate::vm::{report, Opcode, Register, Tracer, VM};
+use std::io::Write;
+
+/// Stops the program when R6 leaves the stack or a store through R6
+/// lands outside it, below the stack is an overflow and above an underflow
+///
+/// The stack grows down from `top`, which is one past its highest word,
+/// to `limit`, its lowest word. R6 may be anywhere from limit to top.
+pub struct StackGuard<'a, W: Write> {
+    output: W,
+    symbols: &'a SymbolTable,
+    limit: u16,
+    top: u16,
+    violation: bool,
+}
+
+impl<'a, W: Write> StackGuard<'a, W> {
+    pub fn new(output: W, symbols: &'a SymbolTable, limit: u16, top: u16) -> Self {
+        Self {
+            output,
+            symbols,
+            limit,
+            top,
+            violation: false,
+        }
+    }
+
+    /// Whether the program was stopped
+    pub fn violation(&self) -> bool {
+        self.violation
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    /// Report and halt, addr is where R6 went or the store went
+    fn stop(&mut self, vm: &mut VM, pc: u16, addr: u16, problem: &str) {
+        self.violation = true;
+        let kind = if addr < self.limit {
+            "overflow"
+        } else {
+            "underflow"
+        };
+        report(
+            &mut self.output,
+            format_args!(
+                "stack {}: {} {}, the stack is x{:04X}-x{:04X}\n{}",
+                kind,
+                address(pc, self.symbols),
+                problem,
+                self.limit,
+                self.top.wrapping_sub(1),
+                backtrace(vm.call_stack().frames(), pc, self.symbols)
+            ),
+        );
+        vm.halt();
+    }
+}
+
+impl<W: Write> Tracer for StackGuard<'_, W> {
+    fn before_step(&mut self, vm: &mut VM, pc: u16, instruction: &DecodedInstruction) {
+        let r6 = u16::from(Register::R6);
+        if instruction.opcode == Opcode::STR && instruction.base_r == r6 {
+            let addr = vm.reg(r6).wrapping_add(instruction.offset);
+            if addr < self.limit || addr >= self.top {
+                let problem = format!("stores through R6 to x{:04X}", addr);
+                self.stop(vm, pc, addr, &problem);
+            }
+        }
+    }
+
+    fn after_step(&mut self, vm: &mut VM, pc: u16, instruction: &DecodedInstruction) {
+        let writes_r6 = matches!(
+            instruction.opcode,
+            Opcode::ADD
+                | Opcode::AND
+                | Opcode::NOT
+                | Opcode::LD
+                | Opcode::LDR
+                | Opcode::LDI
+                | Opcode::LEA
+        ) && instruction.dr == u16::from(Register::R6);
+        let r6 = vm.reg(Register::R6.into());
+        if writes_r6 && (r6 < self.limit || r6 > self.top) {
+            let problem = format!("moves R6 to x{:04X}", r6);
+            self.stop(vm, pc, r6, &problem);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stackguard::StackGuard;
+    use crate::symbols::SymbolTable;
+    use crate::vm::{Register, VM};
+
+    fn run(program: &[u16]) -> (VM, String) {
+        let mut vm = VM::init();
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        for (i, word) in program.iter().enumerate() {
+            *vm.mem_mut(0x3000 + i as u16) = *word;
+        }
+        let mut symbols = SymbolTable::new();
+        symbols.insert("MAIN", 0x3000);
+        symbols.insert("PUSH", 0x3003);
+        let mut guard = StackGuard::new(vec![], &symbols, 0x4000, 0x4002);
+        vm.run_traced(&mut [&mut guard]);
+        assert!(guard.violation());
+        (vm, String::from_utf8(guard.into_inner()).unwrap())
+    }
+
+    #[test]
+    fn test_stops_a_store_past_the_limit() {
+        let program = [
+            0x2c05, // x3000 LD R6, TOP
+            0x4801, // x3001 JSR PUSH
+            0xf025, // x3002 HALT
+            0x7fbf, // x3003 PUSH STR R7, R6, #-1
+            0x1dbf, // x3004 ADD R6, R6, #-1
+            0x0ffd, // x3005 BR PUSH
+            0x4002, // x3006 TOP
+        ];
+        let (vm, output) = run(&program);
+
+        // the third push would write x3FFF
+        assert_eq!(vm.reg(Register::PC.into()), 0x3003);
+        assert_eq!(vm.reg(Register::R6.into()), 0x4000);
+        assert_eq!(
+            output,
+            "stack overflow: x3003 (PUSH) stores through R6 to x3FFF, the stack is x4000-x4001
+  #0 x3003 (PUSH)
+  #1 x3001 (MAIN+1) calls PUSH
+"
+        );
+    }
+
+    #[test]
+    fn test_stops_when_r6_leaves_the_stack() {
+        let program = [
+            0x2c02, // x3000 LD R6, TOP
+            0x1da1, // x3001 ADD R6, R6, #1
+            0xf025, // x3002 HALT
+            0x4002, // x3003 TOP
+        ];
+        let (vm, output) = run(&program);
+        assert_eq!(vm.reg(Register::PC.into()), 0x3002);
+        assert!(output.starts_with("stack underflow: x3001 (MAIN+1) moves R6 to x4003"));
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::io;
+
+/// Label <-> address mapping read from the .sym file
+/// lc3 assemblers write next to each .obj
+///
+/// // Symbol table
+/// // Scope level 0:
+/// //    Symbol Name       Page Address
+/// //    ----------------  ------------
+/// //    START             3000
+#[derive(Default)]
+pub struct SymbolTable {
+    // ordered so lookups and merges give the same result on every run
+    by_name: BTreeMap<String, u16>,
+    by_addr: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// Symbols from the .sym file next to a program, empty if there is none
+    pub fn for_program(path: &str) -> Self {
+        let sym_path = std::path::Path::new(path).with_extension("sym");
+        Self::load(&sym_path.to_string_lossy()).unwrap_or_default()
+    }
+
+    /// Every line of the form `name address` is a symbol, comment
+    /// markers and the header lines are ignored
+    pub fn parse(text: &str) -> Self {
+        let mut table = Self::new();
+        for line in text.lines() {
+            let line = line.trim_start_matches(['/', ' ', '\t']);
+            let mut parts = line.split_whitespace();
+            let (Some(name), Some(addr), None) = (parts.next(), parts.next(), parts.next()) else {
+                continue;
+            };
+            let addr = addr.trim_start_matches(['x', 'X']);
+            if let Ok(addr) = u16::from_str_radix(addr, 16) {
+                table.insert(name, addr);
+            }
+        }
+        table
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        self.by_name.insert(name.to_string(), addr);
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    /// Add every symbol of another table, existing names are kept
+    pub fn extend(&mut self, other: SymbolTable) {
+        for (name, addr) in other.by_name {
+            if !self.by_name.contains_key(&name) {
+                self.insert(&name, addr);
+            }
+        }
+    }
+
+    /// Render in the .sym format written by lc3as, ordered by address
+    pub fn to_sym(&self) -> String {
+        let mut symbols: Vec<(&u16, &String)> = self
+            .by_name
+            .iter()
+            .map(|(name, addr)| (addr, name))
+            .collect();
+        symbols.sort();
+        let mut text = "// Symbol table\n// Scope level 0:\n".to_string();
+        text += "//\tSymbol Name       Page Address\n";
+        text += "//\t----------------  ------------\n";
+        for (addr, name) in symbols {
+            text += &format!("//\t{:<16}  {:04X}\n", name, addr);
+        }
+        text
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Address of a label, an exact match wins over one that
+    /// only differs in case
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied().or_else(|| {
+            self.by_name
+                .iter()
+                .find(|(label, _)| label.eq_ignore_ascii_case(name))
+                .map(|(_, addr)| *addr)
+        })
+    }
+
+    /// Label placed exactly at addr
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(|name| name.as_str())
+    }
+
+    /// Closest label at or before addr and its address
+    pub fn containing(&self, addr: u16) -> Option<(&str, u16)> {
+        self.by_addr
+            .range(..=addr)
+            .next_back()
+            .map(|(addr, name)| (name.as_str(), *addr))
+    }
+
+    /// Render an address as LABEL or LABEL+n, falling back to hex
+    pub fn describe(&self, addr: u16) -> String {
+        match self.containing(addr) {
+            Some((name, base)) if base == addr => name.to_string(),
+            Some((name, base)) => format!("{}+{}", name, addr - base),
+            None => format!("x{:04X}", addr),
+        }
+    }
+
+    /// Resolve a label or a number (x3000, 0x3000, #12288, 12288)
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        parse_number(text).or_else(|| self.address(text))
+    }
+}
+
+/// Parse an lc3 style number literal
+pub fn parse_number(text: &str) -> Option<u16> {
+    let text = text.trim();
+    if let Some(hex) = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('x'))
+        .or_else(|| text.strip_prefix('X'))
+    {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    let decimal = text.strip_prefix('#').unwrap_or(text);
+    match decimal.parse::<i32>() {
+        Ok(value) if (-32768..=65535).contains(&value) => Some(value as u16),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::{parse_number, SymbolTable};
+
+    const SYM_FILE: &str = "// Symbol table
+// Scope level 0:
+//\tSymbol Name       Page Address
+//\t----------------  ------------
+//\tSTART             3000
+//\tPRINT_NUM         3010
+//\tDATA              3020
+";
+
+    #[test]
+    fn test_parse_sym_file() {
+        let symbols = SymbolTable::parse(SYM_FILE);
+        assert_eq!(symbols.address("START"), Some(0x3000));
+        assert_eq!(symbols.address("print_num"), Some(0x3010));
+        assert_eq!(symbols.address("Symbol"), None);
+        assert_eq!(symbols.label(0x3020), Some("DATA"));
+
+        let written = SymbolTable::parse(&symbols.to_sym());
+        assert_eq!(written.address("PRINT_NUM"), Some(0x3010));
+        assert_eq!(
+            symbols.to_sym().lines().nth(4),
+            Some("//\tSTART             3000")
+        );
+    }
+
+    #[test]
+    fn test_describe_address() {
+        let symbols = SymbolTable::parse(SYM_FILE);
+        assert_eq!(symbols.describe(0x3010), "PRINT_NUM");
+        assert_eq!(symbols.describe(0x3012), "PRINT_NUM+2");
+        assert_eq!(symbols.describe(0x2000), "x2000");
+    }
+
+    #[test]
+    fn test_resolve() {
+        let symbols = SymbolTable::parse(SYM_FILE);
+        assert_eq!(symbols.resolve("x3001"), Some(0x3001));
+        assert_eq!(symbols.resolve("0x3001"), Some(0x3001));
+        assert_eq!(symbols.resolve("#10"), Some(10));
+        assert_eq!(symbols.resolve("#-1"), Some(0xffff));
+        assert_eq!(symbols.resolve("DATA"), Some(0x3020));
+        assert_eq!(parse_number("LOOP"), None);
+    }
+
+    #[test]
+    fn test_label_case_and_merge_order() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("loop", 0x3005);
+        symbols.insert("Loop", 0x3001);
+        let mut other = SymbolTable::new();
+        other.insert("LOOP", 0x4000);
+        other.insert("AGAIN", 0x4000);
+        other.insert("loop", 0x5000);
+        symbols.extend(other);
+
+        assert_eq!(symbols.address("Loop"), Some(0x3001));
+        assert_eq!(symbols.address("loop"), Some(0x3005));
+        assert_eq!(symbols.address("LOOP"), Some(0x4000));
+        assert_eq!(symbols.address("lOOP"), Some(0x4000));
+        assert_eq!(symbols.label(0x4000), Some("AGAIN"));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::debuginfo::DebugInfo;
+use crate::decode_instruction::DecodedInstruction;
+use crate::display::{cond_flags, source_or_disassembly};
+use crate::symbols::SymbolTable;
+use crate::vm::{report, Register, Tracer, VM};
+use std::io::Write;
+
+/// Logs every executed instruction with its symbolic address
+/// and the registers after it ran
+pub struct InstructionTrace<'a, W: Write> {
+    output: W,
+    symbols: &'a SymbolTable,
+    debug: &'a DebugInfo,
+}
+
+impl<'a, W: Write> InstructionTrace<'a, W> {
+    pub fn new(output: W, symbols: &'a SymbolTable, debug: &'a DebugInfo) -> Self {
+        Self {
+            output,
+            symbols,
+            debug,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+impl<W: Write> Tracer for InstructionTrace<'_, W> {
+    fn after_step(&mut self, vm: &mut VM, pc: u16, _instruction: &DecodedInstruction) {
+        let word = *vm.mem_mut(pc);
+        let registers: Vec<String> = (0..8)
+            .map(|reg| format!("R{}={:04X}", reg, vm.reg(reg)))
+            .collect();
+        report(
+            &mut self.output,
+            format_args!(
+                "x{:04X} {:<16} {:<20} {} {}\n",
+                pc,
+                self.symbols.describe(pc),
+                source_or_disassembly(pc, word, self.symbols, self.debug),
+                registers.join(" "),
+                cond_flags(vm.reg(Register::COND.into()))
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debuginfo::DebugInfo;
+    use crate::symbols::SymbolTable;
+    use crate::trace::InstructionTrace;
+    use crate::vm::{Register, VM};
+
+    #[test]
+    fn test_trace_uses_labels() {
+        let mut vm = VM::init();
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        *vm.mem_mut(0x3000) = 0x4801; // JSR DONE
+        *vm.mem_mut(0x3002) = 0xf025; // DONE HALT
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("MAIN", 0x3000);
+        symbols.insert("DONE", 0x3002);
+        let debug = DebugInfo::new();
+        let mut trace = InstructionTrace::new(vec![], &symbols, &debug);
+        vm.run_traced(&mut [&mut trace]);
+
+        let output = String::from_utf8(trace.into_inner()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("x3000 MAIN             JSR DONE"));
+        assert!(lines[0].contains("R7=3001"));
+        assert!(lines[1].starts_with("x3002 DONE             TRAP HALT"));
+    }
+}
@@ -0,0 +1,802 @@
+use crate::callstack::{backtrace, frame_link_backtrace};
+use crate::console::BufferConsole;
+use crate::debuginfo::DebugInfo;
+use crate::display::{cond_flags, source_or_disassembly};
+use crate::snapshot::save_snapshot;
+use crate::symbols::SymbolTable;
+use crate::vm::{Register, REGISTER_COUNT, VM};
+use std::collections::HashSet;
+use std::io;
+use std::io::{Read, Write};
+use std::time::Duration;
+use termios::*;
+
+// Terminal debugger, panes are drawn with plain ANSI escape sequences
+//
+// keys while stopped:
+//   s step, c continue, b toggle breakpoint at the cursor
+//   up/down (k/j) move the cursor, [ ] scroll memory, i send next key to the program
+//   : command, q quit
+// commands, locations are labels or numbers:
+//   b <loc> toggle breakpoint, g <loc> move the cursor, x/<n> <loc> examine memory
+//   bt backtrace of the JSR / JSRR calls, bt r5 backtrace from the R5 frame links
+// keys while running:
+//   esc pause, anything else is keyboard input for the program
+
+// instructions executed between redraws while running
+const SLICE_SIZE: usize = 5_000;
+// delay between polls for key presses while stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(16);
+
+const ESC: u8 = 0x1b;
+// frames followed by bt r5
+const MAX_FRAMES: usize = 64;
+
+const MEMORY_COLUMNS: u16 = 8;
+const REGISTERS_WIDTH: usize = 24;
+const BOTTOM_HEIGHT: usize = 10;
+// program output kept for the output pane, older output is dropped
+const OUTPUT_LINES: usize = BOTTOM_HEIGHT - 2;
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Style {
+    Normal,
+    // register changed by the last step
+    Changed,
+    // line at PC
+    Current,
+    Breakpoint,
+}
+
+impl Style {
+    fn escape(&self) -> &'static str {
+        match self {
+            Style::Normal => "",
+            Style::Changed => "\x1b[1;33m",
+            Style::Current => "\x1b[7m",
+            Style::Breakpoint => "\x1b[31m",
+        }
+    }
+}
+
+type Line = (String, Style);
+
+enum Mode {
+    Stopped,
+    Running,
+    // the next key press is sent to the program
+    Input,
+    // typing a command after :
+    Command,
+}
+
+pub struct Tui {
+    vm: Box<VM>,
+    console: BufferConsole,
+    symbols: SymbolTable,
+    debug: DebugInfo,
+    output: String,
+    breakpoints: HashSet<u16>,
+    // registers before the last step, for change highlighting
+    previous_registers: [u16; REGISTER_COUNT],
+    // selected address in the disassembly pane
+    cursor: u16,
+    // first address of the memory pane
+    memory_start: u16,
+    mode: Mode,
+    // ignore a breakpoint at PC when continuing from it
+    skip_breakpoint: bool,
+    status: String,
+    command: String,
+    // terminal size of the last frame, the screen is only cleared on resize
+    screen_size: (usize, usize),
+}
+
+/// Run the terminal debugger until the user quits
+pub fn run(vm: Box<VM>, symbols: SymbolTable, debug: DebugInfo) -> io::Result<()> {
+    let mut tui = Tui::new(vm, symbols, debug);
+
+    let stdin = 0;
+    let termios = Termios::from_fd(stdin)?;
+    let mut raw = termios;
+    raw.c_lflag &= !(ICANON | ECHO);
+    // reads return immediately so we can poll while the program runs
+    raw.c_cc[VMIN] = 0;
+    raw.c_cc[VTIME] = 0;
+    tcsetattr(stdin, TCSANOW, &raw)?;
+
+    // alternate screen, hidden cursor
+    print!("\x1b[?1049h\x1b[?25l");
+    let _guard = TerminalGuard { termios };
+    tui.event_loop()
+}
+
+/// Puts the terminal back the way it was when dropped, also on a panic
+struct TerminalGuard {
+    termios: Termios,
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = tcsetattr(0, TCSANOW, &self.termios);
+    }
+}
+
+impl Tui {
+    pub fn new(mut vm: Box<VM>, symbols: SymbolTable, debug: DebugInfo) -> Self {
+        let console = BufferConsole::new();
+        vm.set_console(Box::new(console.clone()));
+        vm.set_running(true);
+
+        let pc = vm.reg(Register::PC.into());
+        let previous_registers = std::array::from_fn(|i| vm.reg(i as u16));
+        Self {
+            vm,
+            console,
+            symbols,
+            debug,
+            output: String::new(),
+            breakpoints: HashSet::new(),
+            previous_registers,
+            cursor: pc,
+            memory_start: pc,
+            mode: Mode::Stopped,
+            skip_breakpoint: false,
+            status: "stopped".to_string(),
+            command: String::new(),
+            screen_size: (0, 0),
+        }
+    }
+
+    fn event_loop(&mut self) -> io::Result<()> {
+        loop {
+            let keys = read_keys()?;
+            for key in keys {
+                if !self.handle_key(&key) {
+                    return Ok(());
+                }
+            }
+
+            let blocked = self.vm.is_awaiting_input() && !self.console.has_input();
+            if matches!(self.mode, Mode::Running) && !blocked {
+                self.continue_execution(SLICE_SIZE);
+            } else {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+
+            let (width, height) = terminal_size();
+            print!("{}", self.render(width, height));
+            io::stdout().flush()?;
+        }
+    }
+
+    /// Returns false when the user quits
+    fn handle_key(&mut self, key: &[u8]) -> bool {
+        match self.mode {
+            Mode::Running => {
+                if key == [ESC] {
+                    self.mode = Mode::Stopped;
+                    self.status = "paused".to_string();
+                } else {
+                    self.console.push_input(&String::from_utf8_lossy(key));
+                }
+            }
+            Mode::Input => {
+                self.console.push_input(&String::from_utf8_lossy(key));
+                self.mode = Mode::Stopped;
+                self.status = "input queued".to_string();
+            }
+            Mode::Command => match key {
+                [ESC] => self.mode = Mode::Stopped,
+                b"\r" | b"\n" => {
+                    self.mode = Mode::Stopped;
+                    let command = std::mem::take(&mut self.command);
+                    self.status = self.run_command(command.trim());
+                }
+                [0x7f] | [0x08] => {
+                    self.command.pop();
+                }
+                _ => self.command.push_str(&String::from_utf8_lossy(key)),
+            },
+            Mode::Stopped => match key {
+                b"q" => return false,
+                b":" => {
+                    self.mode = Mode::Command;
+                    self.command.clear();
+                }
+                b"s" => self.step(),
+                b"c" if self.vm.is_running() => {
+                    self.mode = Mode::Running;
+                    self.skip_breakpoint = true;
+                    self.status = "running (esc to pause)".to_string();
+                    self.previous_registers = self.registers();
+                }
+                b"b" => self.toggle_breakpoint(self.cursor),
+                b"i" => {
+                    self.mode = Mode::Input;
+                    self.status = "press a key to send to the program".to_string();
+                }
+                b"k" | b"\x1b[A" => self.cursor = self.cursor.wrapping_sub(1),
+                b"j" | b"\x1b[B" => self.cursor = self.cursor.wrapping_add(1),
+                b"[" => self.memory_start = self.memory_start.wrapping_sub(MEMORY_COLUMNS * 8),
+                b"]" => self.memory_start = self.memory_start.wrapping_add(MEMORY_COLUMNS * 8),
+                _ => {}
+            },
+        }
+        true
+    }
+
+    /// Run a : command, returns the status line
+    fn run_command(&mut self, command: &str) -> String {
+        let (name, location) = command.split_once(' ').unwrap_or((command, ""));
+        if name == "bt" {
+            return match location.trim() {
+                "" => self.backtrace(false),
+                "r5" | "R5" => self.backtrace(true),
+                other => format!("unknown backtrace '{}', use bt or bt r5", other),
+            };
+        }
+        if name == "save" {
+            let path = location.trim();
+            if path.is_empty() {
+                return "save needs a snapshot path".to_string();
+            }
+            return match save_snapshot(&mut self.vm, path) {
+                Ok(()) => format!("state saved to {}, continue with lc3 resume {}", path, path),
+                Err(e) => format!("failed to save {}: {}", path, e),
+            };
+        }
+        if !matches!(name, "b" | "g") && !name.starts_with('x') {
+            return format!("unknown command '{}'", name);
+        }
+        let location = location.trim();
+        let addr = self.debug.resolve(location);
+        let Some(addr) = addr.or_else(|| self.symbols.resolve(location)) else {
+            return format!("unknown location '{}'", location);
+        };
+
+        match name {
+            "b" => {
+                self.toggle_breakpoint(addr);
+                let state = if self.breakpoints.contains(&addr) {
+                    "set"
+                } else {
+                    "cleared"
+                };
+                format!("breakpoint {} at {}", state, self.symbols.describe(addr))
+            }
+            "g" => {
+                self.cursor = addr;
+                format!("at {}", self.symbols.describe(addr))
+            }
+            _ => {
+                let count = match name.strip_prefix("x/") {
+                    Some(count) => match count.parse::<u16>() {
+                        Ok(count) => count,
+                        Err(_) => return format!("invalid count '{}'", count),
+                    },
+                    None if name == "x" => 1,
+                    None => return format!("unknown command '{}'", name),
+                };
+                self.memory_start = addr;
+                let values: Vec<String> = (0..count)
+                    .map(|i| format!("x{:04X}", *self.vm.mem_mut(addr.wrapping_add(i))))
+                    .collect();
+                format!("{}: {}", self.symbols.describe(addr), values.join(" "))
+            }
+        }
+    }
+
+    /// Backtrace on one line, the innermost frame first
+    fn backtrace(&mut self, frame_links: bool) -> String {
+        let pc = self.vm.reg(Register::PC.into());
+        let text = if frame_links {
+            frame_link_backtrace(&mut self.vm, &self.symbols, MAX_FRAMES)
+        } else {
+            backtrace(self.vm.call_stack().frames(), pc, &self.symbols)
+        };
+        let frames: Vec<&str> = text.lines().map(str::trim).collect();
+        frames.join("  ")
+    }
+
+    fn registers(&self) -> [u16; REGISTER_COUNT] {
+        std::array::from_fn(|i| self.vm.reg(i as u16))
+    }
+
+    fn step(&mut self) {
+        if !self.vm.is_running() {
+            return;
+        }
+        self.previous_registers = self.registers();
+        self.vm.step();
+        self.after_execution();
+        if self.vm.is_running() {
+            self.status = if self.vm.is_awaiting_input() {
+                "waiting for input (press i)".to_string()
+            } else {
+                "stopped".to_string()
+            };
+        }
+    }
+
+    fn toggle_breakpoint(&mut self, addr: u16) {
+        if !self.breakpoints.remove(&addr) {
+            self.breakpoints.insert(addr);
+        }
+    }
+
+    /// Run up to `count` instructions, stopping at breakpoints
+    fn continue_execution(&mut self, count: usize) {
+        for _ in 0..count {
+            if !self.vm.is_running() {
+                break;
+            }
+            // keep running but let the event loop collect key presses
+            if self.vm.is_awaiting_input() && !self.console.has_input() {
+                break;
+            }
+            let pc = self.vm.reg(Register::PC.into());
+            if self.breakpoints.contains(&pc) && !self.skip_breakpoint {
+                self.mode = Mode::Stopped;
+                self.status = format!("breakpoint at {}", self.symbols.describe(pc));
+                break;
+            }
+            self.skip_breakpoint = false;
+            self.vm.step();
+        }
+        self.after_execution();
+    }
+
+    fn after_execution(&mut self) {
+        self.output.push_str(&self.console.take_output());
+        trim_output(&mut self.output);
+        self.cursor = self.vm.reg(Register::PC.into());
+        if !self.vm.is_running() {
+            self.mode = Mode::Stopped;
+            self.status = match self.vm.error().map(str::to_string) {
+                Some(error) => format!("{}: {}", error, self.backtrace(false)),
+                None => "halted (q to quit)".to_string(),
+            };
+        }
+    }
+
+    /// Draw every pane into a single string of escape sequences
+    pub fn render(&mut self, width: usize, height: usize) -> String {
+        let width = width.max(60);
+        let height = height.max(20);
+        let left_width = width - REGISTERS_WIDTH;
+        let top_height = height - BOTTOM_HEIGHT - 2;
+        let stack_height = top_height.saturating_sub(REGISTER_COUNT + 2);
+        let memory_width = left_width.min(6 + MEMORY_COLUMNS as usize * 5 + 2);
+
+        // panes overwrite every cell they own, so clearing is only needed on resize
+        let mut screen = String::new();
+        if self.screen_size != (width, height) {
+            self.screen_size = (width, height);
+            screen += "\x1b[2J";
+        }
+        screen += &format!(
+            "\x1b[1;1H\x1b[7m{:<width$}\x1b[0m",
+            self.title_bar(),
+            width = width
+        );
+
+        let disassembly = self.disassembly_lines(top_height - 2);
+        draw_pane(
+            &mut screen,
+            "Disassembly",
+            &disassembly,
+            2,
+            1,
+            left_width,
+            top_height,
+        );
+        let registers = self.register_lines();
+        draw_pane(
+            &mut screen,
+            "Registers",
+            &registers,
+            2,
+            left_width + 1,
+            REGISTERS_WIDTH,
+            REGISTER_COUNT + 2,
+        );
+        let stack = self.stack_lines(stack_height.saturating_sub(2));
+        draw_pane(
+            &mut screen,
+            "Stack (R6)",
+            &stack,
+            2 + REGISTER_COUNT + 2,
+            left_width + 1,
+            REGISTERS_WIDTH,
+            stack_height,
+        );
+
+        let bottom = 2 + top_height;
+        let memory = self.memory_lines(BOTTOM_HEIGHT - 2);
+        draw_pane(
+            &mut screen,
+            "Memory",
+            &memory,
+            bottom,
+            1,
+            memory_width,
+            BOTTOM_HEIGHT,
+        );
+        let output = output_lines(&self.output, width - memory_width - 2, OUTPUT_LINES);
+        draw_pane(
+            &mut screen,
+            "Console",
+            &output,
+            bottom,
+            memory_width + 1,
+            width - memory_width,
+            BOTTOM_HEIGHT,
+        );
+
+        let help = match self.mode {
+            Mode::Command => format!(":{}", self.command),
+            _ => "s step  c continue  esc pause  b breakpoint  j/k cursor  [/] memory  i input  : command  q quit"
+                .to_string(),
+        };
+        screen += &format!("\x1b[{};1H{}\x1b[K", height, truncate(&help, width));
+        screen
+    }
+
+    fn title_bar(&self) -> String {
+        let pc = self.vm.reg(Register::PC.into());
+        format!(" lc3 | PC {} | {}", self.symbols.describe(pc), self.status)
+    }
+
+    /// Instructions around the cursor, PC highlighted
+    fn disassembly_lines(&mut self, count: usize) -> Vec<Line> {
+        let pc = self.vm.reg(Register::PC.into());
+        let start = self.cursor.wrapping_sub(count as u16 / 3);
+        (0..count as u16)
+            .map(|i| {
+                let addr = start.wrapping_add(i);
+                let word = *self.vm.mem_mut(addr);
+                let marker = match (addr == self.cursor, self.breakpoints.contains(&addr)) {
+                    (true, true) => ">*",
+                    (true, false) => "> ",
+                    (false, true) => " *",
+                    (false, false) => "  ",
+                };
+                let label = self.symbols.label(addr).unwrap_or_default();
+                let text = format!(
+                    "{}x{:04X} {:04X} {:<10} {}",
+                    marker,
+                    addr,
+                    word,
+                    label,
+                    source_or_disassembly(addr, word, &self.symbols, &self.debug)
+                );
+                let style = if addr == pc {
+                    Style::Current
+                } else if self.breakpoints.contains(&addr) {
+                    Style::Breakpoint
+                } else {
+                    Style::Normal
+                };
+                (text, style)
+            })
+            .collect()
+    }
+
+    fn register_lines(&self) -> Vec<Line> {
+        (0..REGISTER_COUNT)
+            .map(|i| {
+                let value = self.vm.reg(i as u16);
+                let name = Register::try_from(i as u16).unwrap().to_string();
+                let text = if i == Register::COND as usize {
+                    format!("{:<4} {}", name, cond_flags(value))
+                } else {
+                    format!("{:<4} x{:04X} {:>6}", name, value, value as i16)
+                };
+                let style = if value != self.previous_registers[i] {
+                    Style::Changed
+                } else {
+                    Style::Normal
+                };
+                (text, style)
+            })
+            .collect()
+    }
+
+    fn stack_lines(&mut self, count: usize) -> Vec<Line> {
+        let stack_pointer = self.vm.reg(Register::R6.into());
+        (0..count as u16)
+            .map(|i| {
+                let addr = stack_pointer.wrapping_add(i);
+                let marker = if i == 0 { ">" } else { " " };
+                let text = format!("{}x{:04X} x{:04X}", marker, addr, *self.vm.mem_mut(addr));
+                (text, Style::Normal)
+            })
+            .collect()
+    }
+
+    fn memory_lines(&mut self, count: usize) -> Vec<Line> {
+        (0..count as u16)
+            .map(|row| {
+                let addr = self.memory_start.wrapping_add(row * MEMORY_COLUMNS);
+                let mut text = format!("x{:04X}:", addr);
+                for column in 0..MEMORY_COLUMNS {
+                    text += &format!(" {:04X}", *self.vm.mem_mut(addr.wrapping_add(column)));
+                }
+                (text, Style::Normal)
+            })
+            .collect()
+    }
+}
+
+/// Draw a bordered pane at (row, column), both 1 based
+fn draw_pane(
+    screen: &mut String,
+    title: &str,
+    lines: &[Line],
+    row: usize,
+    column: usize,
+    width: usize,
+    height: usize,
+) {
+    if width < 4 || height < 2 {
+        return;
+    }
+    let inner = width - 2;
+    let top = format!("┌{:─<inner$}┐", truncate(title, inner), inner = inner);
+    *screen += &format!("\x1b[{};{}H{}", row, column, top);
+    for i in 0..height - 2 {
+        let (text, style) = lines
+            .get(i)
+            .cloned()
+            .unwrap_or((String::new(), Style::Normal));
+        *screen += &format!(
+            "\x1b[{};{}H│{}{:<inner$}\x1b[0m│",
+            row + 1 + i,
+            column,
+            style.escape(),
+            truncate(&text, inner),
+            inner = inner
+        );
+    }
+    let bottom = format!("└{:─<inner$}┘", "", inner = inner);
+    *screen += &format!("\x1b[{};{}H{}", row + height - 1, column, bottom);
+}
+
+/// Last `count` lines of program output, wrapped to the pane width
+fn output_lines(output: &str, width: usize, count: usize) -> Vec<Line> {
+    let width = width.max(1);
+    let mut lines = vec![];
+    for line in output.split('\n') {
+        let chars: Vec<char> = line.chars().filter(|c| !c.is_control()).collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        for chunk in chars.chunks(width) {
+            lines.push(chunk.iter().collect());
+        }
+    }
+    let skip = lines.len().saturating_sub(count);
+    lines
+        .into_iter()
+        .skip(skip)
+        .map(|line| (line, Style::Normal))
+        .collect()
+}
+
+/// Keep the output the pane can show: the last lines, and no more than
+/// MAX_OUTPUT_BYTES for a program that never prints a newline
+fn trim_output(output: &mut String) {
+    if let Some((start, _)) = output.match_indices('\n').rev().nth(OUTPUT_LINES - 1) {
+        output.drain(..=start);
+    }
+    if output.len() > MAX_OUTPUT_BYTES {
+        let mut start = output.len() - MAX_OUTPUT_BYTES;
+        while !output.is_char_boundary(start) {
+            start += 1;
+        }
+        output.drain(..start);
+    }
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
+/// Key presses available on stdin, escape sequences are kept together
+fn read_keys() -> io::Result<Vec<Vec<u8>>> {
+    let mut buffer = [0_u8; 64];
+    let count = io::stdin().read(&mut buffer)?;
+    let mut keys = vec![];
+    let mut i = 0;
+    while i < count {
+        // arrow keys arrive as ESC [ X
+        if buffer[i] == ESC && i + 2 < count && buffer[i + 1] == b'[' {
+            keys.push(buffer[i..i + 3].to_vec());
+            i += 3;
+        } else {
+            keys.push(vec![buffer[i]]);
+            i += 1;
+        }
+    }
+    Ok(keys)
+}
+
+fn terminal_size() -> (usize, usize) {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let result = unsafe { libc::ioctl(1, libc::TIOCGWINSZ, &mut size) };
+    if result == 0 && size.ws_col > 0 {
+        (size.ws_col as usize, size.ws_row as usize)
+    } else {
+        (100, 32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debuginfo::DebugInfo;
+    use crate::snapshot::load_snapshot;
+    use crate::symbols::SymbolTable;
+    use crate::tui::{output_lines, trim_output, Style, Tui, MAX_OUTPUT_BYTES, OUTPUT_LINES};
+    use crate::vm::{Register, VM};
+
+    fn tui() -> Tui {
+        let mut vm = Box::new(VM::init());
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        // ADD R1, R1, #1
+        *vm.mem_mut(0x3000) = 0x1261;
+        *vm.mem_mut(0x3001) = 0x1261;
+        // OUT
+        *vm.mem_mut(0x3002) = 0xf021;
+        // HALT
+        *vm.mem_mut(0x3003) = 0xf025;
+        Tui::new(vm, SymbolTable::new(), DebugInfo::new())
+    }
+
+    #[test]
+    fn test_step_highlights_changes() {
+        let mut tui = tui();
+        tui.handle_key(b"s");
+
+        let registers = tui.register_lines();
+        assert_eq!(registers[1].1, Style::Changed);
+        assert_eq!(registers[2].1, Style::Normal);
+        assert_eq!(registers[8].0, "PC   x3001  12289");
+
+        let disassembly = tui.disassembly_lines(6);
+        let current = disassembly
+            .iter()
+            .find(|(_, style)| *style == Style::Current)
+            .unwrap();
+        assert!(current.0.contains("x3001 1261"));
+    }
+
+    #[test]
+    fn test_continue_stops_at_breakpoint() {
+        let mut tui = tui();
+        tui.handle_key(b"j");
+        tui.handle_key(b"j");
+        tui.handle_key(b"b");
+        tui.handle_key(b"c");
+        tui.continue_execution(100);
+        assert_eq!(tui.vm.reg(Register::PC.into()), 0x3002);
+
+        tui.handle_key(b"c");
+        tui.continue_execution(100);
+        assert!(!tui.vm.is_running());
+
+        let screen = tui.render(100, 30);
+        assert!(screen.contains("halted"));
+    }
+
+    #[test]
+    fn test_commands_accept_labels() {
+        let mut tui = tui();
+        tui.symbols.insert("PRINT", 0x3002);
+        for key in [":", "b", " ", "P", "R", "I", "N", "T", "\r"] {
+            tui.handle_key(key.as_bytes());
+        }
+        assert!(tui.breakpoints.contains(&0x3002));
+        assert_eq!(tui.status, "breakpoint set at PRINT");
+
+        assert_eq!(tui.run_command("x/2 PRINT"), "PRINT: xF021 xF025");
+        assert_eq!(tui.memory_start, 0x3002);
+        assert_eq!(tui.run_command("g x3001"), "at x3001");
+        assert_eq!(tui.cursor, 0x3001);
+        assert_eq!(tui.run_command("b NOWHERE"), "unknown location 'NOWHERE'");
+    }
+
+    #[test]
+    fn test_breakpoint_at_source_line() {
+        let mut tui = tui();
+        let text = "file 0 /nowhere/count.asm\nx3000 0 3\nx3001 0 3\nx3002 0 5\n";
+        tui.debug = DebugInfo::parse(text, std::path::Path::new(""));
+        assert_eq!(tui.run_command("b count.asm:4"), "breakpoint set at x3002");
+        assert!(tui.breakpoints.contains(&0x3002));
+        assert_eq!(
+            tui.run_command("b other.asm:4"),
+            "unknown location 'other.asm:4'"
+        );
+    }
+
+    #[test]
+    fn test_backtrace_on_illegal_trap() {
+        let mut vm = Box::new(VM::init());
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        // JSR BAD
+        *vm.mem_mut(0x3000) = 0x4801;
+        // BAD TRAP x30
+        *vm.mem_mut(0x3002) = 0xf030;
+        let mut symbols = SymbolTable::new();
+        symbols.insert("MAIN", 0x3000);
+        symbols.insert("BAD", 0x3002);
+        let mut tui = Tui::new(vm, symbols, DebugInfo::new());
+
+        tui.handle_key(b"s");
+        assert_eq!(
+            tui.run_command("bt"),
+            "#0 x3002 (BAD)  #1 x3000 (MAIN) calls BAD"
+        );
+        tui.handle_key(b"s");
+        assert!(!tui.vm.is_running());
+        assert_eq!(tui.vm.reg(Register::PC.into()), 0x3002);
+        assert_eq!(
+            tui.status,
+            "illegal trap x30 at x3002: #0 x3002 (BAD)  #1 x3000 (MAIN) calls BAD"
+        );
+    }
+
+    #[test]
+    fn test_save_snapshot_mid_program() {
+        let mut vm = Box::new(VM::init());
+        *vm.reg_mut(Register::PC.into()) = 0x3000;
+        // ADD R1, R1, #1 twice
+        *vm.mem_mut(0x3000) = 0x1261;
+        *vm.mem_mut(0x3001) = 0x1261;
+        let mut tui = Tui::new(vm, SymbolTable::new(), DebugInfo::new());
+        tui.handle_key(b"s");
+
+        let path = std::env::temp_dir().join("tui_test.snapshot");
+        let path = path.to_string_lossy();
+        assert!(tui
+            .run_command(&format!("save {}", path))
+            .starts_with("state saved"));
+        let restored = load_snapshot(&path).unwrap();
+        assert!(restored.is_running());
+        assert_eq!(restored.reg(Register::PC.into()), 0x3001);
+        assert_eq!(restored.reg(Register::R1.into()), 1);
+        assert_eq!(tui.run_command("save"), "save needs a snapshot path");
+    }
+
+    #[test]
+    fn test_output_wrapping() {
+        let lines = output_lines("hello world\nbye", 5, 3);
+        let lines: Vec<&str> = lines.iter().map(|(line, _)| line.as_str()).collect();
+        assert_eq!(lines, vec![" worl", "d", "bye"]);
+    }
+
+    #[test]
+    fn test_output_is_trimmed() {
+        let mut output: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        output += "last";
+        trim_output(&mut output);
+        assert_eq!(output.lines().count(), OUTPUT_LINES);
+        assert!(output.starts_with("line 93\n"));
+        assert_eq!(
+            output_lines(&output, 80, OUTPUT_LINES).last().unwrap().0,
+            "last"
+        );
+
+        let mut output = "é".repeat(MAX_OUTPUT_BYTES);
+        trim_output(&mut output);
+        assert_eq!(output.len(), MAX_OUTPUT_BYTES);
+    }
+}
@@ -1,15 +1,18 @@
-use crate::decode_instruction::decode_instruction;
+use crate::callstack::CallStack;
+use crate::console::{Console, StdConsole};
+use crate::decode_instruction::{decode_instruction, DecodedInstruction};
 use crate::opcodes::{
     add_opcode, and_opcode, br_opcode, jmp_opcode, jsr_opcode, ld_opcode, ldi_opcode, ldr_opcode,
     lea_opcode, not_opcode, st_opcode, sti_opcode, str_opcode, trap_opcode,
 };
-use std::io::Read;
+use std::fmt;
+use std::io::Write;
 
 #[repr(u16)]
 /// Register Enum for readable reference
 /// 10 registers in total
 /// 8 general purpose registers (R0 - R7)
-///   - the general purpose registers can be addressed with 3 bits (log_2(8))
+///     - the general purpose registers can be addressed with 3 bits (log_2(8))
 /// 1 program counter (PC)
 /// 1 condition flag (COND)
 pub enum Register {
@@ -34,10 +37,10 @@ impl From<Register> for u16 {
 impl TryFrom<u16> for Register {
     type Error = &'static str;
     fn try_from(value: u16) -> Result<Self, Self::Error> {
-        if value <= 15 {
-            Ok(unsafe { std::mem::transmute(value) })
+        if value < REGISTER_COUNT as u16 {
+            Ok(unsafe { std::mem::transmute::<u16, Register>(value) })
         } else {
-            Err("invalid opcode")
+            Err("invalid register")
         }
     }
 }
@@ -94,7 +97,7 @@ impl TryFrom<u16> for Opcode {
     type Error = &'static str;
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         if value <= 15 {
-            Ok(unsafe { std::mem::transmute(value) })
+            Ok(unsafe { std::mem::transmute::<u16, Opcode>(value) })
         } else {
             Err("invalid opcode")
         }
@@ -102,6 +105,7 @@ impl TryFrom<u16> for Opcode {
 }
 
 /// Conditional Flags
+#[allow(clippy::upper_case_acronyms)]
 enum Flags {
     POSITIVE = 1 << 0,
     ZERO = 1 << 1,
@@ -125,6 +129,13 @@ pub struct VM {
     memory: [u16; MEMORY_SIZE],
     registers: [u16; REGISTER_COUNT],
     running: bool,
+    console: Box<dyn Console>,
+    // set when an input trap found no key press and will be retried
+    awaiting_input: bool,
+    // why the program was stopped by an illegal instruction or trap
+    error: Option<String>,
+    // JSR / JSRR calls that have not returned yet
+    call_stack: CallStack,
 }
 
 impl VM {
@@ -133,9 +144,39 @@ impl VM {
             memory: [0; MEMORY_SIZE],
             registers: [0; REGISTER_COUNT],
             running: false,
+            console: Box::new(StdConsole),
+            awaiting_input: false,
+            error: None,
+            call_stack: CallStack::new(),
         }
     }
 
+    pub fn set_console(&mut self, console: Box<dyn Console>) {
+        self.console = console;
+    }
+
+    pub fn console(&mut self) -> &mut dyn Console {
+        self.console.as_mut()
+    }
+
+    pub fn is_awaiting_input(&self) -> bool {
+        self.awaiting_input
+    }
+
+    /// Rewind PC so the current input trap runs again once a key is available
+    pub fn wait_for_input(&mut self) {
+        *self.reg_mut(Register::PC.into()) = self.reg(Register::PC.into()).wrapping_sub(1);
+        self.awaiting_input = true;
+    }
+
+    pub fn input_received(&mut self) {
+        self.awaiting_input = false;
+    }
+
+    pub fn set_awaiting_input(&mut self, awaiting_input: bool) {
+        self.awaiting_input = awaiting_input;
+    }
+
     pub fn reg(&self, addr: u16) -> u16 {
         self.registers[addr as usize]
     }
@@ -146,14 +187,12 @@ impl VM {
 
     pub fn mem(&mut self, addr: u16) -> u16 {
         if addr == MR_KBSR as u16 {
-            let mut buffer = [0; 1];
-            std::io::stdin().read_exact(&mut buffer).unwrap();
-
-            if buffer[0] != 0 {
-                self.memory[MR_KBSR] = 1 << 15;
-                self.memory[MR_KBDR] = buffer[0] as u16;
-            } else {
-                self.memory[MR_KBSR] = 0;
+            match self.console.read_key() {
+                Some(key) if key != 0 => {
+                    self.memory[MR_KBSR] = 1 << 15;
+                    self.memory[MR_KBDR] = key as u16;
+                }
+                _ => self.memory[MR_KBSR] = 0,
             }
         }
         self.memory[addr as usize]
@@ -163,42 +202,129 @@ impl VM {
         &mut self.memory[addr as usize]
     }
 
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn set_running(&mut self, running: bool) {
+        self.running = running;
+    }
+
+    /// Stop the fetch-decode-execute loop after the current instruction
+    pub fn halt(&mut self) {
+        self.running = false;
+    }
+
+    /// Stop on an instruction that cannot run, PC is left at it
+    pub fn fault(&mut self, message: String) {
+        *self.reg_mut(Register::PC.into()) = self.reg(Register::PC.into()).wrapping_sub(1);
+        self.error = Some(message);
+        self.running = false;
+    }
+
+    /// Why the program stopped, if it was an error
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn set_error(&mut self, error: Option<String>) {
+        self.error = error;
+    }
+
+    /// Shadow call stack of every instruction executed so far
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    pub fn set_call_stack(&mut self, call_stack: CallStack) {
+        self.call_stack = call_stack;
+    }
+
     pub fn run(&mut self) {
         self.running = true;
 
         while self.running {
-            // fetch instruction
-            let instruction = *self.mem_mut(self.reg(Register::PC.into()));
-
-            // decode instruction
-            let decoded_instruction = decode_instruction(instruction);
-
-            // update pc
-            *self.reg_mut(Register::PC.into()) += 1;
-
-            // execute
-            match decoded_instruction.opcode {
-                Opcode::BR => br_opcode(self, decoded_instruction),
-                Opcode::ADD => add_opcode(self, decoded_instruction),
-                Opcode::LD => ld_opcode(self, decoded_instruction),
-                Opcode::ST => st_opcode(self, decoded_instruction),
-                Opcode::JSR => jsr_opcode(self, decoded_instruction),
-                Opcode::AND => and_opcode(self, decoded_instruction),
-                Opcode::LDR => ldr_opcode(self, decoded_instruction),
-                Opcode::STR => str_opcode(self, decoded_instruction),
-                Opcode::RTI => panic!("unused"),
-                Opcode::NOT => not_opcode(self, decoded_instruction),
-                Opcode::LDI => ldi_opcode(self, decoded_instruction),
-                Opcode::STI => sti_opcode(self, decoded_instruction),
-                Opcode::JMP => jmp_opcode(self, decoded_instruction),
-                Opcode::RES => panic!("unused"),
-                Opcode::LEA => lea_opcode(self, decoded_instruction),
-                Opcode::TRAP => trap_opcode(self, decoded_instruction),
+            self.step();
+        }
+    }
+
+    /// Same as run but lets each tracer observe every instruction
+    pub fn run_traced(&mut self, tracers: &mut [&mut dyn Tracer]) {
+        self.running = true;
+
+        while self.running {
+            let pc = self.reg(Register::PC.into());
+            let instruction = decode_instruction(self.memory[pc as usize]);
+
+            for tracer in tracers.iter_mut() {
+                tracer.before_step(self, pc, &instruction);
             }
+            // a tracer halting before the step keeps the instruction from running
+            if !self.running {
+                break;
+            }
+            self.step();
+            for tracer in tracers.iter_mut() {
+                tracer.after_step(self, pc, &instruction);
+            }
+        }
+    }
+
+    /// Fetch, decode and execute a single instruction
+    pub fn step(&mut self) {
+        // fetch instruction
+        let pc = self.reg(Register::PC.into());
+        let instruction = *self.mem_mut(pc);
+
+        // decode instruction
+        let decoded_instruction = decode_instruction(instruction);
+
+        // update pc
+        *self.reg_mut(Register::PC.into()) = self.reg(Register::PC.into()).wrapping_add(1);
+
+        // execute
+        match decoded_instruction.opcode {
+            Opcode::BR => br_opcode(self, decoded_instruction),
+            Opcode::ADD => add_opcode(self, decoded_instruction),
+            Opcode::LD => ld_opcode(self, decoded_instruction),
+            Opcode::ST => st_opcode(self, decoded_instruction),
+            Opcode::JSR => jsr_opcode(self, decoded_instruction),
+            Opcode::AND => and_opcode(self, decoded_instruction),
+            Opcode::LDR => ldr_opcode(self, decoded_instruction),
+            Opcode::STR => str_opcode(self, decoded_instruction),
+            Opcode::RTI | Opcode::RES => {
+                self.fault(format!(
+                    "illegal instruction x{:04X} ({}) at x{:04X}",
+                    instruction, decoded_instruction.opcode, pc
+                ));
+            }
+            Opcode::NOT => not_opcode(self, decoded_instruction),
+            Opcode::LDI => ldi_opcode(self, decoded_instruction),
+            Opcode::STI => sti_opcode(self, decoded_instruction),
+            Opcode::JMP => jmp_opcode(self, decoded_instruction),
+            Opcode::LEA => lea_opcode(self, decoded_instruction),
+            Opcode::TRAP => trap_opcode(self, decoded_instruction),
         }
+
+        let mut call_stack = std::mem::take(&mut self.call_stack);
+        call_stack.update(self, pc, &decoded_instruction);
+        self.call_stack = call_stack;
     }
 }
 
+/// Observer of the instructions executed by VM::run_traced
+/// pc is the address the instruction was fetched from
+pub trait Tracer {
+    fn before_step(&mut self, _vm: &mut VM, _pc: u16, _instruction: &DecodedInstruction) {}
+
+    fn after_step(&mut self, _vm: &mut VM, _pc: u16, _instruction: &DecodedInstruction) {}
+}
+
+/// Write what a tracer found, a failed write must not stop the program being traced
+pub fn report<W: Write>(output: &mut W, message: fmt::Arguments) {
+    let _ = output.write_fmt(message);
+}
+
 /// Sign Extension
 /// extends a binary value of a certain bit count to a larger bit count (u16 in this case)
 pub fn sext(val: u16, bit_count: usize) -> u16 {
@@ -236,8 +362,7 @@ pub fn update_flags(vm: &mut VM, register_addr: u16) {
 
 #[cfg(test)]
 mod tests {
-    use crate::vm::sext;
-    use crate::{Register, VM};
+    use crate::vm::{sext, Register, VM};
 
     #[test]
     fn test_register_implicit_ordering() {